@@ -0,0 +1,100 @@
+//! Writes `build_info.rs` to OUT_DIR, with what `--version` prints: the git hash of the tree,
+//! when and for which target it was built and the features it was built with.
+
+use std::{
+    env, fs,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Every optional feature, in the order they are listed.
+const FEATURES: &[&str] = &[
+    "systemd",
+    "desktop-notifications",
+    "webhook",
+    "metrics",
+    "portage-python",
+    "portage-native",
+    "journald",
+    "profile",
+];
+
+fn git_hash() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()?;
+    let hash = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !hash.trim().is_empty()).then(|| hash.trim().to_owned())
+}
+
+/// UTC `YYYY-MM-DD HH:MM` of a unix timestamp.
+fn format_timestamp(secs: u64) -> String {
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        rem / 3600,
+        rem / 60 % 60
+    )
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let hash = git_hash().unwrap_or_else(|| "unknown".to_owned());
+    // Honor SOURCE_DATE_EPOCH for reproducible builds
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        });
+    let built = format_timestamp(timestamp);
+    // Worth knowing in bug reports now that it's cross compiled for other arches
+    let target = env::var("TARGET").unwrap();
+    let features: Vec<String> = FEATURES
+        .iter()
+        .map(|feature| {
+            let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+            let sign = if env::var_os(var).is_some() { '+' } else { '-' };
+            format!("{sign}{feature}")
+        })
+        .collect();
+    let features = features.join(" ");
+
+    let out = Path::new(&env::var_os("OUT_DIR").unwrap()).join("build_info.rs");
+    fs::write(
+        out,
+        format!(
+            "/// Short hash of the commit this was built from.\n\
+             pub const GIT_HASH: &str = {hash:?};\n\
+             /// When this was built.\n\
+             pub const BUILD_TIMESTAMP: &str = {built:?};\n\
+             /// The target triple this was built for.\n\
+             pub const TARGET: &str = {target:?};\n\
+             /// The optional features, prefixed with + when enabled and - otherwise.\n\
+             pub const FEATURES: &str = {features:?};\n\
+             /// Everything above, as printed by `--version`.\n\
+             pub const VERSION: &str = concat!(env!(\"CARGO_PKG_VERSION\"), {rest:?});\n",
+            rest = format!(" ({hash}, built {built} for {target}) {features}"),
+        ),
+    )
+    .expect("Couldn't write build_info.rs");
+}
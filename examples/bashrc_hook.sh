@@ -0,0 +1,110 @@
+# shellcheck shell=bash disable=SC2317,SC2329 # the hooks are called by portage
+# Portage hooks for emerge-presence.
+#
+# Put this file and emerge-presence-ipc.sh in /etc/portage, and source this one from
+# /etc/portage/bashrc (or use it as the bashrc), after setting the variables described at the top
+# of emerge-presence-ipc.sh. Nothing is sent when the daemon isn't running.
+
+# shellcheck source=emerge-presence-ipc.sh
+source "${BASH_SOURCE[0]%/*}/emerge-presence-ipc.sh" || return
+
+# The fields of a package, in a json object, from the variables portage sets in the hooks
+_discordrpcpackage() {
+	local fields
+	fields='"category": '"$(_discordrpcjsonstr "$CATEGORY")"
+	fields+=', "package": '"$(_discordrpcjsonstr "$PN")"
+	fields+=', "version": '"$(_discordrpcjsonstr "$PV")"
+	fields+=', "revision": '"$(_discordrpcjsonstr "$PR")"
+	printf '%s' "$fields"
+}
+
+# Set the state ($1) of the package, optionally with how long the phase took ($2, in seconds)
+_discordrpcset() {
+	local payload
+	payload='{"state": '"$(_discordrpcjsonstr "$1")"', '"$(_discordrpcpackage)"
+	payload+=', "homepage": '"$(_discordrpcjsonstr "$HOMEPAGE")"
+	payload+=', "repo": '"$(_discordrpcjsonstr "$PORTAGE_REPO_NAME")"
+	payload+=', "slot": '"$(_discordrpcjsonstr "$SLOT")"
+	payload+=', "inherited": '"$(_discordrpcjsonstr "$INHERITED")"
+	payload+=', "cflags": '"$(_discordrpcjsonstr "$CFLAGS")"
+	payload+=', "cxxflags": '"$(_discordrpcjsonstr "$CXXFLAGS")"
+	# shellcheck disable=SC2086 # one flag per word
+	payload+=', "use_flags": '"$(_discordrpcjsonlist $USE)"
+	[ -n "$2" ] && payload+=', "elapsed_secs": '"$2"
+	[ -n "$DISTFILE_SIZE" ] && payload+=', "distfile_size": '"$DISTFILE_SIZE"
+	[ "$MERGE_TYPE" = binary ] && payload+=', "binary_package": true'
+	payload+='}'
+	_discordrpcsend 0 "$payload"
+}
+
+# Seconds since the start of the phase, as timed by _discordrpcstart
+# ($EPOCHREALTIME has the decimal separator of the locale, json wants a dot)
+_discordrpcstart() {
+	local LC_ALL=C
+	_discordrpcphasestart=$EPOCHREALTIME
+}
+_discordrpcelapsed() {
+	local LC_ALL=C
+	[ -n "$_discordrpcphasestart" ] || return
+	# Bash can't do floating point, awk can
+	LC_ALL=C awk -v start="$_discordrpcphasestart" -v end="$EPOCHREALTIME" 'BEGIN { printf "%.3f", end - start }'
+}
+
+pre_pkg_pretend() {
+	_discordrpcset pretend
+}
+# Shown as "fetching", or "fetching (12 MB)" if portage sets $DISTFILE_SIZE
+pre_src_fetch() {
+	_discordrpcset fetching
+}
+post_src_fetch() {
+	_discordrpcset fetching
+}
+
+# The phases are timed in the hooks, so the build time recorded by the daemon (and the estimates
+# based on it) is the time spent setting up and compiling, whatever the wait between the phases.
+pre_pkg_setup() {
+	_discordrpcstart
+	_discordrpcset preparing
+}
+post_pkg_setup() {
+	_discordrpcset preparing "$(_discordrpcelapsed)"
+}
+pre_src_compile() {
+	_discordrpcstart
+	_discordrpcset compiling
+}
+post_src_compile() {
+	_discordrpcset compiling "$(_discordrpcelapsed)"
+}
+pre_src_test() {
+	_discordrpcset test
+}
+pre_pkg_preinst() {
+	_discordrpcset installing
+}
+pre_pkg_postinst() {
+	_discordrpcset installing
+}
+post_pkg_postinst() {
+	_discordrpcsend 1 ''
+}
+pre_pkg_config() {
+	_discordrpcset configure
+}
+post_pkg_config() {
+	_discordrpcsend 1 ''
+}
+pre_pkg_info() {
+	_discordrpcset info
+}
+post_pkg_info() {
+	_discordrpcsend 1 ''
+}
+
+_discordrpcdie() {
+	local reason="$EBUILD_PHASE phase failed"
+	[ "$EBUILD_PHASE" = test ] && reason="test failure"
+	_discordrpcsend 3 '{"reason": '"$(_discordrpcjsonstr "$reason")"', '"$(_discordrpcpackage)"'}'
+}
+register_die_hook _discordrpcdie
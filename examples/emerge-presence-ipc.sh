@@ -0,0 +1,73 @@
+# shellcheck shell=bash disable=SC2317,SC2329 # the functions are called by the hooks
+# Framing of the commands sent to emerge-presence, sourced by bashrc_hook.sh.
+#
+# _discordsock: the daemon socket, $XDG_RUNTIME_DIR/emerge-presence.sock of the user running it
+# _discordfifo: the fifo of a daemon started with --legacy-fifo, used when _discordproto=1
+# _discordproto: 2 for length prefixed frames on the socket (the default), 1 for null terminated
+#   commands on the fifo
+# _discordsecret: the secret of the config, if any (needs openssl, and the socket)
+
+: "${_discordsock:=/run/user/1000/emerge-presence.sock}"
+: "${_discordfifo:=/tmp/_discordfifo}"
+: "${_discordproto:=2}"
+: "${_discordsecret:=}"
+
+# Names of the commands by opcode, legacy commands are sent by name and authenticated ones are
+# signed with it
+_discordrpcnames=(set unset query die clear list sync sync-done set-operation batch)
+
+# Whether the daemon is there to talk to, the hooks stay silent when it isn't
+_discordrpcrunning() {
+	if [ "$_discordproto" = 1 ]; then
+		[ -p "$_discordfifo" ]
+	else
+		[ -S "$_discordsock" ]
+	fi
+}
+
+# Little endian u32 as printf escapes
+_discordrpcle32() {
+	printf '\\x%02x\\x%02x\\x%02x\\x%02x' $(($1 & 255)) $(($1 >> 8 & 255)) $(($1 >> 16 & 255)) $(($1 >> 24 & 255))
+}
+
+# Its argument as a json string
+_discordrpcjsonstr() {
+	local s="$1"
+	s=${s//\\/\\\\}
+	s=${s//\"/\\\"}
+	s=${s//$'\t'/\\t}
+	s=${s//$'\n'/\\n}
+	printf '"%s"' "$s"
+}
+
+# Its arguments as a json array of strings
+_discordrpcjsonlist() {
+	local list=() arg
+	for arg in "$@"; do
+		list+=("$(_discordrpcjsonstr "$arg")")
+	done
+	local IFS=,
+	printf '[%s]' "${list[*]}"
+}
+
+# Send a command: opcode ($1) and json payload ($2, can be empty)
+_discordrpcsend() {
+	local LC_ALL=C payload="$2" auth
+	_discordrpcrunning || return 0
+	if [ "$_discordproto" = 1 ]; then
+		# Opening the fifo without blocking fails when the daemon isn't reading it
+		printf '%s\0' "${_discordrpcnames[$1]}${payload:+ $payload}" |
+			dd of="$_discordfifo" oflag=nonblock status=none 2>/dev/null
+		return 0
+	fi
+	if [ -n "$_discordsecret" ]; then
+		auth=$(printf '%s:%s' "${_discordrpcnames[$1]}" "$2" | openssl dgst -sha256 -hmac "$_discordsecret" | sed 's/.* //')
+		payload='{"auth":"'"$auth"'"'
+		[ -n "$2" ] && payload+=',"payload":'"$2"
+		payload+='}'
+	fi
+	# shellcheck disable=SC2059 # the format is the header, as escapes
+	printf "$(_discordrpcle32 "$1")$(_discordrpcle32 "${#payload}")%s" "$payload" |
+		socat - "UNIX-CONNECT:$_discordsock" 2>/dev/null
+	return 0
+}
@@ -0,0 +1,22 @@
+//! What the daemon reads from the socket and fifo: frames, legacy commands and batches, back to
+//! back like they arrive in the buffer of a connection, with and without authentication.
+
+#![no_main]
+
+use emerge_presence::{
+    auth::{CommandAuth, Secret},
+    command::read_frame,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let secret: Secret = serde_json::from_str(r#""fuzz""#).unwrap();
+    let auth = CommandAuth::new(&secret);
+    for auth in [None, Some(&auth)] {
+        let mut consumed = 0;
+        while let Some((used, _command)) = read_frame(&data[consumed..], auth) {
+            assert!(used > 0, "read_frame made no progress");
+            consumed += used;
+        }
+    }
+});
@@ -0,0 +1,19 @@
+//! Payloads of the set command. Anything that parses has to survive a round trip, which is what
+//! the state file does with the sessions.
+
+#![no_main]
+
+use emerge_presence::portage::PackagePayload;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(json) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(payload) = serde_json::from_str::<PackagePayload>(json) else {
+        return;
+    };
+    let json = serde_json::to_string(&payload).unwrap();
+    let again: PackagePayload = serde_json::from_str(&json).unwrap();
+    assert_eq!(serde_json::to_string(&again).unwrap(), json);
+});
@@ -0,0 +1,132 @@
+//! Authentication of the commands with a secret shared by the hooks and the daemon, so that
+//! being able to write to the socket (or fifo) isn't enough to control the presence.
+//!
+//! Authenticated commands are frames with `{"auth": "<hmac>", "payload": <payload>}` as their
+//! payload, `payload` being left out for the commands that don't have one. The hmac is the hex
+//! HMAC-SHA256 of `<command name>:<payload>`, with the payload exactly as it is in the frame, or
+//! nothing: `set:{"category":...}`, `unset:`.
+
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use sha2::Sha256;
+
+use crate::error::CommandError;
+
+/// The `secret` of the config, which stays out of the debug output.
+#[derive(Deserialize, Clone)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    auth: String,
+    #[serde(borrow)]
+    payload: Option<&'a RawValue>,
+}
+
+/// Signs and checks commands.
+pub struct CommandAuth {
+    secret: Secret,
+}
+
+impl CommandAuth {
+    pub fn new(secret: &Secret) -> Self {
+        Self {
+            secret: secret.clone(),
+        }
+    }
+
+    fn mac(&self, name: &str, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.0.as_bytes())
+            .expect("HMAC takes keys of any size");
+        mac.update(name.as_bytes());
+        mac.update(b":");
+        mac.update(payload);
+        mac
+    }
+
+    /// The payload of the authenticated frame for command `name`.
+    pub fn seal(&self, name: &str, payload: &[u8]) -> Vec<u8> {
+        let auth: String = self
+            .mac(name, payload)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let mut sealed = format!("{{\"auth\":\"{auth}\"").into_bytes();
+        if !payload.is_empty() {
+            sealed.extend_from_slice(b",\"payload\":");
+            sealed.extend_from_slice(payload);
+        }
+        sealed.push(b'}');
+        sealed
+    }
+
+    /// Check the payload of a frame for command `name`, returning the payload it carries.
+    pub fn open<'a>(&self, name: &str, sealed: &'a [u8]) -> Result<&'a [u8], CommandError> {
+        let envelope: Envelope =
+            serde_json::from_slice(sealed).map_err(CommandError::NotAuthenticated)?;
+        let payload = envelope.payload.map_or("", RawValue::get).as_bytes();
+        let auth = decode_hex(&envelope.auth).ok_or(CommandError::InvalidAuth)?;
+        self.mac(name, payload)
+            .verify_slice(&auth)
+            .map_err(|_| CommandError::WrongAuth)?;
+        Ok(payload)
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(secret: &str) -> CommandAuth {
+        CommandAuth::new(&Secret(secret.to_owned()))
+    }
+
+    #[test]
+    fn round_trip() {
+        let auth = auth("hunter2");
+        let payload = br#"{"category": "dev-libs", "package": "openssl"}"#;
+        let sealed = auth.seal("set", payload);
+        assert_eq!(auth.open("set", &sealed).unwrap(), payload);
+        assert_eq!(auth.open("unset", &auth.seal("unset", b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn known_hmac() {
+        // printf 'unset:' | openssl dgst -sha256 -hmac hunter2
+        let sealed =
+            br#"{"auth":"8af7d07e0a48dc2d1fda24f017ff7f523bd5186986364f56a71359813602010b"}"#;
+        assert!(auth("hunter2").open("unset", sealed).is_ok());
+    }
+
+    #[test]
+    fn rejected() {
+        let sealed = auth("hunter2").seal("set", br#"{"package": "a"}"#);
+        assert!(auth("other").open("set", &sealed).is_err());
+        // Signed for another command
+        assert!(auth("hunter2").open("die", &sealed).is_err());
+        assert!(auth("hunter2").open("set", br#"{"package": "a"}"#).is_err());
+        assert!(auth("hunter2").open("set", br#"{"auth": "zz"}"#).is_err());
+    }
+}
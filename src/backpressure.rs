@@ -0,0 +1,175 @@
+//! Keeps the commands received while discord isn't connected, so that an emerge started before
+//! discord still shows up once it is. They are replayed in order on the next connection.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::command::Command;
+
+/// Most commands kept, the oldest are dropped to make room.
+pub const MAX_COMMANDS: usize = 50;
+/// Commands older than this are stale by the time discord shows up, and dropped.
+pub const MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Commands waiting for a connection, oldest first.
+#[derive(Default)]
+pub struct BackpressureBuffer {
+    commands: VecDeque<(Instant, Command)>,
+}
+
+impl BackpressureBuffer {
+    /// Whether `command` can wait for a connection. The ones that are replied to can't, their
+    /// sender waits for the reply.
+    pub fn accepts(command: &Command) -> bool {
+        !matches!(command, Command::Query | Command::List | Command::Batch(_))
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Keep `command` for later. A set replaces the set of its session that came right before it
+    /// (with nothing else about the session in between), only its latest phase matters.
+    pub fn push(&mut self, command: Command) {
+        self.push_at(Instant::now(), command);
+    }
+
+    fn push_at(&mut self, now: Instant, command: Command) {
+        self.expire(now);
+        if let Command::Set(payload) = &command {
+            let session = payload.pid.unwrap_or(0);
+            let last = self
+                .commands
+                .iter()
+                .rposition(|(_, queued)| concerns(queued, session));
+            if let Some(i) = last.filter(|&i| matches!(self.commands[i].1, Command::Set(_))) {
+                tracing::trace!("Replacing the buffered set of session {session}");
+                self.commands.remove(i);
+            }
+        }
+        if self.commands.len() >= MAX_COMMANDS {
+            tracing::warn!("More than {MAX_COMMANDS} commands buffered, dropping the oldest");
+            self.commands.pop_front();
+        }
+        self.commands.push_back((now, command));
+    }
+
+    /// The commands to replay, in the order they were received.
+    pub fn take(&mut self) -> Vec<Command> {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> Vec<Command> {
+        self.expire(now);
+        self.commands
+            .drain(..)
+            .map(|(_, command)| command)
+            .collect()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let fresh = self
+            .commands
+            .iter()
+            .position(|(at, _)| now.saturating_duration_since(*at) <= MAX_AGE)
+            .unwrap_or(self.commands.len());
+        if fresh > 0 {
+            tracing::debug!("Dropping {fresh} buffered commands older than {MAX_AGE:?}");
+            self.commands.drain(..fresh);
+        }
+    }
+}
+
+/// Whether `command` changes the session of the emerge `pid` (0 for the hooks without one).
+fn concerns(command: &Command, pid: u32) -> bool {
+    match command {
+        Command::Set(payload) => payload.pid.unwrap_or(0) == pid,
+        Command::Die(payload) => payload.package.pid.unwrap_or(0) == pid,
+        Command::SetOperation(payload) => payload.pid.unwrap_or(0) == pid,
+        // Without a pid it ends every session
+        Command::Unset(payload) => payload.pid.is_none_or(|unset| unset == pid),
+        Command::Clear => true,
+        Command::Query
+        | Command::List
+        | Command::Sync(_)
+        | Command::SyncDone
+        | Command::Batch(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::command::UnsetPayload;
+
+    fn set(package: &str, pid: u32) -> Command {
+        Command::Set(
+            serde_json::from_value(json!({
+                "category": "app-misc",
+                "package": package,
+                "pid": pid,
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn unset(pid: u32) -> Command {
+        Command::Unset(UnsetPayload { pid: Some(pid) })
+    }
+
+    /// The packages of the sets, and `unset` for unsets.
+    fn names(commands: &[Command]) -> Vec<String> {
+        commands
+            .iter()
+            .map(|command| match command {
+                Command::Set(payload) => payload.package.clone(),
+                Command::Unset(_) => "unset".to_owned(),
+                _ => "other".to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dedup_sets() {
+        let mut buffer = BackpressureBuffer::default();
+        buffer.push(set("a", 1));
+        buffer.push(set("b", 2));
+        buffer.push(set("c", 1));
+        buffer.push(unset(1));
+        buffer.push(set("d", 1));
+        buffer.push(set("e", 1));
+        // The unset keeps "c" from being replaced, order is that of the last sets
+        assert_eq!(names(&buffer.take()), ["b", "c", "unset", "e"]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn bounded() {
+        let mut buffer = BackpressureBuffer::default();
+        for pid in 0..MAX_COMMANDS as u32 + 5 {
+            buffer.push(set(&pid.to_string(), pid));
+        }
+        assert_eq!(buffer.len(), MAX_COMMANDS);
+        assert_eq!(names(&buffer.take())[0], "5");
+    }
+
+    #[test]
+    fn expire() {
+        let mut buffer = BackpressureBuffer::default();
+        let start = Instant::now();
+        buffer.push_at(start, set("old", 1));
+        buffer.push_at(start + MAX_AGE / 2, set("new", 2));
+        assert_eq!(
+            names(&buffer.take_at(start + MAX_AGE + Duration::from_secs(1))),
+            ["new"]
+        );
+    }
+}
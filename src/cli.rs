@@ -0,0 +1,261 @@
+use std::{
+    fs::OpenOptions,
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::Subcommand;
+use serde_json::json;
+
+use emerge_presence::{
+    auth::CommandAuth,
+    command::{
+        command_name, encode_frame, OP_BATCH, OP_CLEAR, OP_LIST, OP_QUERY, OP_SET,
+        OP_SET_OPERATION, OP_SYNC, OP_SYNC_DONE, OP_UNSET,
+    },
+};
+
+/// Commands talking to a running daemon instead of starting one.
+// Parsed once from the arguments, the size of set doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+pub enum CliCommand {
+    /// Show a package, like the bashrc hooks do
+    Set {
+        #[arg(long)]
+        category: String,
+        #[arg(long)]
+        package: String,
+        /// Version without the revision
+        #[arg(long)]
+        version: Option<String>,
+        /// Revision (r1, r2, ...)
+        #[arg(long)]
+        revision: Option<String>,
+        /// preparing, compiling, installing, ...
+        #[arg(long)]
+        state: Option<String>,
+        /// Pid of the emerge process the package belongs to
+        #[arg(long)]
+        pid: Option<u32>,
+        /// Upstream homepage
+        #[arg(long)]
+        homepage: Option<String>,
+        /// Repository of the ebuild
+        #[arg(long)]
+        repo: Option<String>,
+        /// Enabled use flags
+        #[arg(long = "use", value_delimiter = ' ')]
+        use_flags: Option<Vec<String>>,
+        /// Size in bytes of the sources being fetched
+        #[arg(long)]
+        distfile_size: Option<u64>,
+        /// Number of packages in the merge, instead of reading it from the mtimedb
+        #[arg(long)]
+        total: Option<u32>,
+        /// Position of the package in the merge, from 1
+        #[arg(long)]
+        position: Option<u32>,
+        /// Inherited eclasses, to tell the build system
+        #[arg(long)]
+        inherited: Option<String>,
+        /// Slot of the package, shown unless it's 0
+        #[arg(long)]
+        slot: Option<String>,
+        /// CFLAGS of the build, for the logs and the status
+        #[arg(long, allow_hyphen_values = true)]
+        cflags: Option<String>,
+        /// CXXFLAGS of the build, for the logs and the status
+        #[arg(long, allow_hyphen_values = true)]
+        cxxflags: Option<String>,
+        /// How long the phase in --state took, when it's over
+        #[arg(long)]
+        elapsed_secs: Option<f64>,
+        /// Tests run so far, with --state test
+        #[arg(long)]
+        test_count: Option<u32>,
+        /// The package is merged from a binary package
+        #[arg(long)]
+        binary_package: bool,
+    },
+    /// End the session of an emerge (or of all of them without --pid)
+    Unset {
+        #[arg(long)]
+        pid: Option<u32>,
+    },
+    /// End every session and clear the presence right away
+    Clear,
+    /// Print what the daemon is currently showing
+    Status,
+    /// Print the sessions the daemon is tracking
+    List,
+    /// Show that repositories are being synced
+    Sync {
+        /// Names of the repositories
+        repos: Vec<String>,
+    },
+    /// End the sync
+    SyncDone,
+    /// Send several commands at once, as a json array, and print the result of each
+    Batch {
+        /// `[{"cmd": "set", "category": ..., "package": ...}, {"cmd": "unset"}]`
+        commands: String,
+    },
+    /// Tell what an emerge is doing as a whole
+    SetOperation {
+        /// normal-merge, preserved-rebuild, world-update, depclean or sync
+        operation: String,
+        /// Pid of the emerge process
+        #[arg(long)]
+        pid: Option<u32>,
+    },
+}
+
+/// Where the daemon reads its commands from.
+pub enum Target {
+    Socket(PathBuf),
+    /// With --legacy-fifo, which can't carry replies.
+    Fifo(PathBuf),
+}
+
+impl CliCommand {
+    pub fn run(&self, target: &Target, auth: Option<&CommandAuth>) -> Result<()> {
+        match self {
+            Self::Set {
+                category,
+                package,
+                version,
+                revision,
+                state,
+                pid,
+                homepage,
+                repo,
+                use_flags,
+                distfile_size,
+                total,
+                position,
+                inherited,
+                slot,
+                cflags,
+                cxxflags,
+                elapsed_secs,
+                test_count,
+                binary_package,
+            } => {
+                let payload = json!({
+                    "category": category,
+                    "package": package,
+                    "version": version,
+                    "revision": revision,
+                    "state": state,
+                    "pid": pid,
+                    "homepage": homepage,
+                    "repo": repo,
+                    "use_flags": use_flags,
+                    "distfile_size": distfile_size,
+                    "total": total,
+                    "position": position,
+                    "inherited": inherited,
+                    "slot": slot,
+                    "cflags": cflags,
+                    "cxxflags": cxxflags,
+                    "elapsed_secs": elapsed_secs,
+                    "test_count": test_count,
+                    "binary_package": binary_package,
+                });
+                send(target, auth, OP_SET, &serde_json::to_vec(&payload)?)?;
+            }
+            Self::Unset { pid } => {
+                let payload = match pid {
+                    Some(pid) => serde_json::to_vec(&json!({ "pid": pid }))?,
+                    None => Vec::new(),
+                };
+                send(target, auth, OP_UNSET, &payload)?;
+            }
+            Self::Clear => send(target, auth, OP_CLEAR, b"")?,
+            Self::Status => print_reply(&request(target, auth, OP_QUERY, b"")?)?,
+            Self::List => print_reply(&request(target, auth, OP_LIST, b"")?)?,
+            Self::Batch { commands } => {
+                print_reply(&request(target, auth, OP_BATCH, commands.as_bytes())?)?;
+            }
+            Self::Sync { repos } => {
+                send(
+                    target,
+                    auth,
+                    OP_SYNC,
+                    &serde_json::to_vec(&json!({ "repos": repos }))?,
+                )?;
+            }
+            Self::SyncDone => send(target, auth, OP_SYNC_DONE, b"")?,
+            Self::SetOperation { operation, pid } => {
+                send(
+                    target,
+                    auth,
+                    OP_SET_OPERATION,
+                    &serde_json::to_vec(&json!({ "operation": operation, "pid": pid }))?,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn connect(socket: &PathBuf) -> Result<UnixStream> {
+    UnixStream::connect(socket).with_context(|| format!("Couldn't connect to {}", socket.display()))
+}
+
+/// The frame of a command, authenticated with `auth` if set.
+fn frame(auth: Option<&CommandAuth>, opcode: u32, payload: &[u8]) -> Vec<u8> {
+    match (auth, command_name(opcode)) {
+        (Some(auth), Some(name)) => encode_frame(opcode, &auth.seal(name, payload)),
+        _ => encode_frame(opcode, payload),
+    }
+}
+
+/// Send a command that doesn't get a reply.
+fn send(target: &Target, auth: Option<&CommandAuth>, opcode: u32, payload: &[u8]) -> Result<()> {
+    let frame = frame(auth, opcode, payload);
+    match target {
+        Target::Socket(socket) => connect(socket)?.write_all(&frame)?,
+        Target::Fifo(fifo) => OpenOptions::new()
+            .write(true)
+            .open(fifo)
+            .with_context(|| format!("Couldn't open {}", fifo.display()))?
+            .write_all(&frame)?,
+    }
+    Ok(())
+}
+
+/// Send a command to the daemon and wait for its reply.
+fn request(
+    target: &Target,
+    auth: Option<&CommandAuth>,
+    opcode: u32,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let Target::Socket(socket) = target else {
+        bail!("The daemon can't reply through the fifo, this needs the socket");
+    };
+    let mut stream = connect(socket)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(&frame(auth, opcode, payload))?;
+
+    let mut header = [0u8; 8];
+    stream
+        .read_exact(&mut header)
+        .context("Daemon didn't reply")?;
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    let mut reply = vec![0u8; len];
+    stream.read_exact(&mut reply)?;
+    Ok(reply)
+}
+
+fn print_reply(reply: &[u8]) -> Result<()> {
+    let reply: serde_json::Value =
+        serde_json::from_slice(reply).context("Daemon sent invalid json")?;
+    println!("{}", serde_json::to_string_pretty(&reply)?);
+    Ok(())
+}
@@ -0,0 +1,325 @@
+//! The commands the hooks send to the daemon, and how they're framed.
+
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[cfg(not(feature = "tokio"))]
+use crate::discord::retry_with_backoff;
+use crate::{
+    auth::CommandAuth,
+    discord::Client,
+    error::CommandError,
+    metrics,
+    portage::{parse_emerge_cmdline, OperationType, PackagePayload},
+};
+
+/// Opcodes of the framed command protocol, the body of each frame is the json payload of the
+/// command (can be empty for unset and query). Replies use the opcode of the command they answer.
+pub const OP_SET: u32 = 0;
+pub const OP_UNSET: u32 = 1;
+pub const OP_QUERY: u32 = 2;
+pub const OP_DIE: u32 = 3;
+pub const OP_CLEAR: u32 = 4;
+pub const OP_LIST: u32 = 5;
+pub const OP_SYNC: u32 = 6;
+pub const OP_SYNC_DONE: u32 = 7;
+pub const OP_SET_OPERATION: u32 = 8;
+pub const OP_BATCH: u32 = 9;
+
+/// Anything bigger is assumed to be garbage (or a desync), no command comes close to this.
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+/// Tries at setting the presence before giving up, see [`retry_with_backoff`].
+#[cfg(not(feature = "tokio"))]
+const SET_ATTEMPTS: u32 = 3;
+
+/// A parsed command.
+pub enum Command {
+    /// Show a package, or update the phase of the one shown.
+    Set(PackagePayload),
+    /// End a session (after the unset delay).
+    Unset(UnsetPayload),
+    /// Reply with what is currently shown.
+    Query,
+    /// Show that a package failed to merge.
+    Die(DiePayload),
+    /// End every session and clear the presence now, without waiting for the unset delay.
+    Clear,
+    /// Reply with every tracked session.
+    List,
+    /// Show that the repositories are being synced, until the sync is done.
+    Sync(SyncPayload),
+    /// The sync is over, go back to showing the sessions (or nothing).
+    SyncDone,
+    /// Tell what an emerge is doing as a whole (`@preserved-rebuild`, ...).
+    SetOperation(OperationPayload),
+    /// Several commands at once.
+    Batch(CommandBatch),
+}
+
+impl Command {
+    fn from_parts(opcode: u32, payload: &[u8]) -> Result<Self, CommandError> {
+        let is_empty = payload.iter().all(u8::is_ascii_whitespace);
+        match opcode {
+            OP_SET => Ok(Self::Set(serde_json::from_slice(payload)?)),
+            OP_UNSET if is_empty => Ok(Self::Unset(UnsetPayload::default())),
+            OP_UNSET => Ok(Self::Unset(serde_json::from_slice(payload)?)),
+            OP_QUERY => Ok(Self::Query),
+            OP_DIE => Ok(Self::Die(serde_json::from_slice(payload)?)),
+            OP_CLEAR => Ok(Self::Clear),
+            OP_LIST => Ok(Self::List),
+            OP_SYNC if is_empty => Ok(Self::Sync(SyncPayload::default())),
+            OP_SYNC => Ok(Self::Sync(serde_json::from_slice(payload)?)),
+            OP_SYNC_DONE => Ok(Self::SyncDone),
+            OP_SET_OPERATION => Ok(Self::SetOperation(serde_json::from_slice(payload)?)),
+            OP_BATCH => Ok(Self::Batch(CommandBatch::parse(payload)?)),
+            _ => Err(CommandError::UnknownOpcode(opcode)),
+        }
+    }
+
+    /// Parse a legacy command: its name, optionally followed by a space and a json payload.
+    pub fn from_legacy(command: &[u8]) -> Result<Self, CommandError> {
+        let command = std::str::from_utf8(command)?;
+        let (name, payload) = command.split_once(' ').unwrap_or((command, ""));
+        let opcode =
+            opcode_of(name).ok_or_else(|| CommandError::UnknownCommand(name.to_owned()))?;
+        Self::from_parts(opcode, payload.as_bytes())
+    }
+}
+
+/// The opcode of a command from its name, as used by legacy commands and batches.
+fn opcode_of(name: &str) -> Option<u32> {
+    Some(match name {
+        "set" => OP_SET,
+        "unset" => OP_UNSET,
+        "query" => OP_QUERY,
+        "die" => OP_DIE,
+        "clear" => OP_CLEAR,
+        "list" => OP_LIST,
+        "sync" => OP_SYNC,
+        "sync-done" => OP_SYNC_DONE,
+        "set-operation" => OP_SET_OPERATION,
+        _ => return None,
+    })
+}
+
+/// The name of the command of `opcode`, which authenticated commands are signed with.
+pub fn command_name(opcode: u32) -> Option<&'static str> {
+    Some(match opcode {
+        OP_SET => "set",
+        OP_UNSET => "unset",
+        OP_QUERY => "query",
+        OP_DIE => "die",
+        OP_CLEAR => "clear",
+        OP_LIST => "list",
+        OP_SYNC => "sync",
+        OP_SYNC_DONE => "sync-done",
+        OP_SET_OPERATION => "set-operation",
+        OP_BATCH => "batch",
+        _ => return None,
+    })
+}
+
+/// Commands sent together, as a json array of objects with the name of the command in `cmd` and
+/// the fields of its payload next to it: `[{"cmd": "set", "category": ...}, {"cmd": "unset"}]`.
+/// They're handled in order, and one failing doesn't stop the others.
+pub struct CommandBatch(pub Vec<Result<Command, CommandError>>);
+
+impl CommandBatch {
+    /// Parse a batch, only failing if it isn't an array: invalid commands are errors in it.
+    pub fn parse(json: &[u8]) -> Result<Self, CommandError> {
+        let entries: Vec<serde_json::Map<String, Value>> =
+            serde_json::from_slice(json).map_err(CommandError::InvalidBatch)?;
+        let commands = entries
+            .into_iter()
+            .map(|mut entry| {
+                let name = match entry.remove("cmd") {
+                    Some(Value::String(name)) => name,
+                    _ => return Err(CommandError::MissingCmd),
+                };
+                let opcode = opcode_of(&name).ok_or(CommandError::UnknownCommand(name))?;
+                let payload = if entry.is_empty() {
+                    Vec::new()
+                } else {
+                    serde_json::to_vec(&entry)?
+                };
+                Command::from_parts(opcode, &payload)
+            })
+            .collect();
+        Ok(Self(commands))
+    }
+}
+
+/// Frame `payload`, the other way around from [`read_frame`].
+pub fn encode_frame(opcode: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + payload.len());
+    buf.extend_from_slice(&opcode.to_le_bytes());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Try to read a frame from the start of `buf`, framed like discord ipc: a 4 bytes little endian
+/// opcode, a 4 bytes little endian length and the payload. Returns `None` if the frame isn't
+/// complete yet, or the number of bytes consumed and the parsed command.
+///
+/// Legacy null terminated commands (`set {...}\0`) are still accepted, they are told apart from
+/// frames by their first byte being a letter (which would be an absurdly big opcode). Batches
+/// can be sent the same way, null terminated and starting with `[`.
+///
+/// With `auth`, only frames authenticated by it are accepted, see [`crate::auth`].
+pub fn read_frame(
+    buf: &[u8],
+    auth: Option<&CommandAuth>,
+) -> Option<(usize, Result<Command, CommandError>)> {
+    let first = *buf.first()?;
+    if first.is_ascii_alphabetic() || first == b'[' {
+        let Some(end) = buf.iter().position(|&b| b == 0) else {
+            // Otherwise a writer that never terminates its command grows the buffer forever
+            if buf.len() > MAX_FRAME_LEN {
+                return Some((buf.len(), Err(CommandError::Unterminated(MAX_FRAME_LEN))));
+            }
+            return None;
+        };
+        let command = match first {
+            _ if auth.is_some() => Err(CommandError::LegacyWithSecret),
+            b'[' => CommandBatch::parse(&buf[..end]).map(Command::Batch),
+            _ => Command::from_legacy(&buf[..end]),
+        };
+        return Some((end + 1, command));
+    }
+
+    let header = buf.get(..8)?;
+    let opcode = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Some((buf.len(), Err(CommandError::TooBig(len))));
+    }
+    let payload = buf.get(8..8 + len)?;
+    let command = match auth {
+        Some(auth) => command_name(opcode)
+            .ok_or(CommandError::UnknownOpcode(opcode))
+            .and_then(|name| auth.open(name, payload))
+            .and_then(|payload| Command::from_parts(opcode, payload)),
+        None => Command::from_parts(opcode, payload),
+    };
+    Some((8 + len, command))
+}
+
+/// Handle a command, returning the reply to send back to its sender if any.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn handle_command(
+    client: &mut Client,
+    command: Command,
+) -> Result<Option<Vec<u8>>, CommandError> {
+    client.last_command = Some(SystemTime::now());
+    match command {
+        Command::Set(payload) => {
+            tracing::info!("Got set");
+            metrics::record_set_command();
+            let flags = payload.pid.map(parse_emerge_cmdline).unwrap_or_default();
+            tracing::debug!("Emerge flags: {flags:?}");
+            tracing::debug!(cflags = ?payload.cflags, cxxflags = ?payload.cxxflags, "Build flags");
+            // A set lost to discord restarting would leave the previous package shown until the
+            // next one
+            #[cfg(not(feature = "tokio"))]
+            retry_with_backoff(client, SET_ATTEMPTS, |client| {
+                client.set_package(payload.clone(), flags.clone())
+            })?;
+            // Only queued with tokio, the main loop sends it
+            #[cfg(feature = "tokio")]
+            client.set_package(payload, flags)?;
+        }
+        Command::Unset(payload) => {
+            tracing::info!("Got unset, queueing");
+            client.unset_package(payload.pid);
+        }
+        Command::Query => {
+            tracing::info!("Got query");
+            let status = serde_json::to_vec(&client.query())?;
+            return Ok(Some(encode_frame(OP_QUERY, &status)));
+        }
+        Command::Die(DiePayload { package, reason }) => {
+            tracing::info!("Got die");
+            let flags = package.pid.map(parse_emerge_cmdline).unwrap_or_default();
+            client.fail_package(package, flags, reason)?;
+        }
+        Command::Clear => {
+            tracing::info!("Got clear");
+            client.clear_sessions()?;
+        }
+        Command::List => {
+            tracing::info!("Got list");
+            let sessions = serde_json::to_vec(&client.list_sessions())?;
+            return Ok(Some(encode_frame(OP_LIST, &sessions)));
+        }
+        Command::Sync(payload) => {
+            tracing::info!(repos = ?payload.repos, "Got sync");
+            client.set_sync(payload.repos)?;
+        }
+        Command::SyncDone => {
+            tracing::info!("Got sync-done");
+            client.sync_done()?;
+        }
+        Command::SetOperation(payload) => {
+            tracing::info!(operation = ?payload.operation, "Got set-operation");
+            client.set_operation(payload.pid, payload.operation)?;
+        }
+        Command::Batch(CommandBatch(commands)) => {
+            tracing::info!("Got batch of {} commands", commands.len());
+            let results: Vec<Value> = commands
+                .into_iter()
+                .map(|command| {
+                    // Batches can't be nested, there's no name for them in one
+                    match command.and_then(|command| handle_command(client, command)) {
+                        // Replies are framed, the batch has them as json
+                        Ok(Some(reply)) => json!({
+                            "ok": true,
+                            "reply": serde_json::from_slice::<Value>(&reply[8..]).ok(),
+                        }),
+                        Ok(None) => json!({ "ok": true }),
+                        Err(err) => {
+                            tracing::warn!("Batched command failed ({err})");
+                            json!({ "ok": false, "error": err.to_string() })
+                        }
+                    }
+                })
+                .collect();
+            return Ok(Some(encode_frame(OP_BATCH, &serde_json::to_vec(&results)?)));
+        }
+    }
+    Ok(None)
+}
+
+/// Payload of the die command, sent by the pkg_die hook.
+#[derive(Deserialize)]
+pub struct DiePayload {
+    #[serde(flatten)]
+    pub package: PackagePayload,
+    /// Why the merge failed, shown in the state.
+    pub reason: Option<String>,
+}
+
+/// Payload of the unset command, optional for backwards compatibility.
+#[derive(Deserialize, Default)]
+pub struct UnsetPayload {
+    /// The emerge whose session ends, every session ends without it.
+    pub pid: Option<u32>,
+}
+
+/// Payload of the set-operation command.
+#[derive(Deserialize)]
+pub struct OperationPayload {
+    pub operation: OperationType,
+    /// The emerge doing it, like for set.
+    pub pid: Option<u32>,
+}
+
+/// Payload of the sync command.
+#[derive(Deserialize, Default)]
+pub struct SyncPayload {
+    /// Names of the repositories being synced.
+    #[serde(default)]
+    pub repos: Vec<String>,
+}
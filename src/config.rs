@@ -0,0 +1,368 @@
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use nix::unistd::{access, AccessFlags};
+use serde::{de, Deserialize, Deserializer};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    auth::Secret, error::ConfigError, notify::NotifierKind, template::Templates, transport,
+};
+
+/// The upstream discord application, which has the gentoo assets.
+pub const DEFAULT_CLIENT_ID: &str = "1007427345801556039";
+
+/// Discord refuses asset keys longer than this.
+const MAX_ASSET_KEY_LEN: usize = 32;
+
+/// What [`Config::validate`] checks, in the order `--check` prints them.
+pub const CONFIG_SECTIONS: &[&str] = &[
+    "paths",
+    "log_level",
+    "assets",
+    "templates",
+    "notifiers",
+    "rate_limit",
+    "transport",
+];
+
+/// Daemon configuration, every field is optional in the file and defaults to the values that used
+/// to be hard-coded.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Discord application id, DISCORD_CLIENT_ID takes precedence if set.
+    pub client_id: String,
+    pub fifo_path: PathBuf,
+    /// Most bytes buffered from the fifo or a connection to the socket at once. The rest of a
+    /// bigger write to the fifo is dropped, a connection sending more is closed.
+    pub max_payload_bytes: usize,
+    /// Command socket, `$XDG_RUNTIME_DIR/emerge-presence.sock` if unset.
+    pub socket_path: Option<PathBuf>,
+    /// Shared with the hooks, which then have to authenticate their commands with it.
+    pub secret: Option<Secret>,
+    pub pid_file: PathBuf,
+    /// How long to wait after an unset before clearing the presence, 0 clears it right away.
+    #[serde(alias = "clear_delay_secs")]
+    pub unset_delay_secs: u64,
+    /// Default log filter, RUST_LOG takes precedence if set.
+    pub log_level: String,
+    /// Show the number of jobs and binary package options of emerge in the state.
+    pub show_emerge_flags: bool,
+    /// Show the build system of the package as the small image, instead of the phase.
+    pub show_build_system: bool,
+    /// Show how long the package has been merging in the state.
+    pub show_elapsed: bool,
+    /// Show the number of installed packages next to the position in the merge.
+    pub show_installed_count: bool,
+    /// Where the state is dumped on SIGUSR1.
+    pub dump_path: PathBuf,
+    /// Use flags considered default, only the differences with these are shown.
+    pub use_baseline: Option<Vec<String>>,
+    /// How long a failure (die command or emerge.log) stays shown.
+    pub failure_display_secs: u64,
+    /// How long the summary of an emerge ("Merged 17 packages in 2h 34m") is shown before clearing
+    /// the presence, 0 clears it right away.
+    pub summary_display_secs: u64,
+    /// How often the emerge processes of the sessions are checked, to end the sessions of the
+    /// ones that died without unsetting.
+    pub pid_check_secs: u64,
+    /// How long the main loop can go without running before the daemon is considered stuck and
+    /// killed, 0 (the default) disables the watchdog.
+    pub watchdog_secs: u64,
+    /// Start the daemon again when the watchdog kills it, instead of leaving that to the service
+    /// manager.
+    pub watchdog_restart: bool,
+    /// emerge.log to watch for failures the hooks didn't report, not watched if unset.
+    pub emerge_log: Option<PathBuf>,
+    /// Add a button linking to the package on packages.gentoo.org.
+    pub show_package_button: bool,
+    /// Add a button linking to the upstream homepage, when the hooks send it.
+    pub show_homepage_button: bool,
+    /// Mark the package activities as a specific instance of a session (`instance` of discord).
+    pub activity_instance: bool,
+    /// Discord ipc socket to connect to, searched for (`discord-ipc-0` to 19) if unset.
+    pub ipc_socket_path: Option<PathBuf>,
+    /// Where the sessions are saved to survive restarts,
+    /// `$XDG_RUNTIME_DIR/emerge-presence-state.json` if unset.
+    pub state_path: Option<PathBuf>,
+    /// Where build durations are kept to estimate when packages will be done,
+    /// `$XDG_DATA_HOME/emerge-presence/history.json` if unset.
+    pub history_path: Option<PathBuf>,
+    /// Asset keys to use instead of the default ones ("gentoodrpgt", "phase_compile", ...).
+    pub assets_map: HashMap<String, String>,
+    /// Large images of the packages of some categories, instead of "gentoodrpgt".
+    pub category_images: CategoryImageMap,
+    /// How many activity updates can be sent in a row before being rate limited.
+    pub rate_limit_updates: u32,
+    /// How long (in seconds) it takes for one more update to be allowed.
+    pub rate_limit_refill_secs: u64,
+    /// minijinja template of the first line, `category/package version` if unset.
+    pub details_template: Option<String>,
+    /// minijinja template of the second line, the phase and use flags if unset.
+    pub state_template: Option<String>,
+    /// Port to serve prometheus metrics on (on localhost), needs the metrics feature.
+    pub metrics_port: Option<u16>,
+    /// What discord shows before the name of the application ("Playing", "Watching", ...).
+    pub activity_type: ActivityType,
+    /// Who to tell when an emerge finishes: "desktop" and "webhook" ("log" does nothing, the
+    /// summary is always logged).
+    pub notifiers: Vec<NotifierKind>,
+    /// Where the webhook notifier posts.
+    pub webhook_url: Option<String>,
+    /// Discord webhook the activity is posted to when discord can't be reached over ipc, needs
+    /// the webhook feature.
+    pub presence_webhook_url: Option<String>,
+    /// Image url of the thumbnail of the webhook messages.
+    pub presence_webhook_thumbnail: Option<String>,
+}
+
+/// Asset keys of the large image by category (`dev-lang`) or category prefix (`dev`).
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(transparent)]
+pub struct CategoryImageMap(HashMap<String, String>);
+
+impl From<HashMap<String, String>> for CategoryImageMap {
+    fn from(images: HashMap<String, String>) -> Self {
+        Self(images)
+    }
+}
+
+impl CategoryImageMap {
+    /// The image of `category`: the one of the whole category, or else the one of the part before
+    /// the dash (`dev` for `dev-lang`).
+    pub fn image(&self, category: &str) -> Option<&str> {
+        self.0
+            .get(category)
+            .or_else(|| self.0.get(category.split_once('-')?.0))
+            .map(String::as_str)
+    }
+
+    /// These images, and those of `other` for the categories (and prefixes) that have none here.
+    pub fn or(&self, other: &CategoryImageMap) -> CategoryImageMap {
+        let mut images = other.0.clone();
+        images.extend(self.0.clone());
+        Self(images)
+    }
+}
+
+/// The activity types discord lets applications use, given either by name or by id in the config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ActivityType {
+    #[default]
+    Playing = 0,
+    Listening = 2,
+    Watching = 3,
+    Competing = 5,
+}
+
+impl TryFrom<u8> for ActivityType {
+    type Error = String;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            0 => Ok(Self::Playing),
+            2 => Ok(Self::Listening),
+            3 => Ok(Self::Watching),
+            5 => Ok(Self::Competing),
+            _ => Err(format!("Invalid activity type {id}, expected 0, 2, 3 or 5")),
+        }
+    }
+}
+
+impl std::str::FromStr for ActivityType {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "playing" => Ok(Self::Playing),
+            "listening" => Ok(Self::Listening),
+            "watching" => Ok(Self::Watching),
+            "competing" => Ok(Self::Competing),
+            _ => Err(format!(
+                "Invalid activity type {name:?}, expected playing, listening, watching or competing"
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ActivityType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Id(u8),
+            Name(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Id(id) => id.try_into(),
+            Repr::Name(name) => name.parse(),
+        }
+        .map_err(de::Error::custom)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            client_id: DEFAULT_CLIENT_ID.to_owned(),
+            fifo_path: PathBuf::from("/tmp/_discordfifo"),
+            max_payload_bytes: transport::MAX_PAYLOAD_BYTES,
+            socket_path: None,
+            secret: None,
+            pid_file: PathBuf::from("/tmp/rpcdiscordpid"),
+            unset_delay_secs: 30,
+            log_level: "error".to_owned(),
+            show_emerge_flags: false,
+            show_build_system: false,
+            show_elapsed: false,
+            show_installed_count: false,
+            dump_path: PathBuf::from("/tmp/emerge-presence-dump.json"),
+            use_baseline: None,
+            failure_display_secs: 60,
+            summary_display_secs: 5,
+            pid_check_secs: 5,
+            watchdog_secs: 0,
+            watchdog_restart: false,
+            emerge_log: None,
+            show_package_button: true,
+            show_homepage_button: true,
+            activity_instance: false,
+            ipc_socket_path: None,
+            state_path: None,
+            history_path: None,
+            assets_map: HashMap::new(),
+            category_images: CategoryImageMap::default(),
+            rate_limit_updates: 5,
+            rate_limit_refill_secs: 4,
+            details_template: None,
+            state_template: None,
+            metrics_port: None,
+            activity_type: ActivityType::default(),
+            notifiers: Vec::new(),
+            webhook_url: None,
+            presence_webhook_url: None,
+            presence_webhook_thumbnail: None,
+        }
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/emerge-presence/config.toml`, or `~/.config/emerge-presence/config.toml`
+    /// if XDG_CONFIG_HOME isn't set.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".config")))?;
+        Some(base.join("emerge-presence").join("config.toml"))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read config file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Couldn't parse config file {}", path.display()))
+    }
+
+    /// Everything wrong with the config that parsing it didn't catch, empty when it's good to go.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let mut error = |section, message: String| errors.push(ConfigError { section, message });
+
+        // The pid file may not exist yet, then its directory has to be writable
+        for (name, path) in [("pid_file", &self.pid_file), ("dump_path", &self.dump_path)] {
+            let target = match path.exists() {
+                true => path.as_path(),
+                false => path
+                    .parent()
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                    .unwrap_or(Path::new(".")),
+            };
+            if let Err(err) = access(target, AccessFlags::W_OK) {
+                error(
+                    "paths",
+                    format!("{name} {} isn't writable ({err})", path.display()),
+                );
+            }
+        }
+        if let Some(path) = &self.emerge_log {
+            if let Err(err) = access(path.as_path(), AccessFlags::R_OK) {
+                error(
+                    "paths",
+                    format!("emerge_log {} isn't readable ({err})", path.display()),
+                );
+            }
+        }
+
+        if let Err(err) = EnvFilter::try_new(&self.log_level) {
+            error(
+                "log_level",
+                format!("Invalid filter {:?} ({err})", self.log_level),
+            );
+        }
+
+        let assets = self
+            .assets_map
+            .values()
+            .chain(self.category_images.0.values());
+        for asset in assets {
+            // Urls are fine, only the keys of uploaded assets are limited
+            if !asset.starts_with("http") && asset.chars().count() > MAX_ASSET_KEY_LEN {
+                error(
+                    "assets",
+                    format!("{asset:?} is longer than {MAX_ASSET_KEY_LEN} characters"),
+                );
+            }
+        }
+
+        if let Err(err) = Templates::new(
+            self.details_template.as_deref(),
+            self.state_template.as_deref(),
+        ) {
+            error("templates", format!("{err:#}"));
+        }
+
+        if self.notifiers.contains(&NotifierKind::Webhook) && self.webhook_url.is_none() {
+            error(
+                "notifiers",
+                "The webhook notifier needs webhook_url".to_owned(),
+            );
+        }
+
+        if self.rate_limit_updates == 0 {
+            error(
+                "rate_limit",
+                "rate_limit_updates is 0, nothing would ever be sent".to_owned(),
+            );
+        }
+
+        if self.max_payload_bytes < transport::MIN_PAYLOAD_BYTES {
+            error(
+                "transport",
+                format!(
+                    "max_payload_bytes is {}, commands need at least {}",
+                    self.max_payload_bytes,
+                    transport::MIN_PAYLOAD_BYTES
+                ),
+            );
+        }
+        errors
+    }
+
+    /// Load the config from `path` if given, otherwise from the default location. Only an
+    /// explicitly given file is required to exist.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => Self::from_file(path),
+            None => match Self::default_path() {
+                Some(path) if path.exists() => Self::from_file(&path),
+                _ => Ok(Self::default()),
+            },
+        }
+    }
+}
@@ -0,0 +1,2500 @@
+//! The discord ipc client, which turns the sessions into activities.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env,
+    fmt::Display,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+#[cfg(not(feature = "tokio"))]
+use std::{
+    io::{Read, Write},
+    os::unix::{io::AsRawFd, net::UnixStream},
+};
+
+#[cfg(not(feature = "tokio"))]
+use mio::{unix::SourceFd, Interest, Registry, Token};
+#[cfg(not(feature = "tokio"))]
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+#[cfg(feature = "tokio")]
+use tokio::{io::AsyncWriteExt, net::UnixStream};
+
+use crate::{
+    command::{encode_frame, MAX_FRAME_LEN},
+    config::{ActivityType, CategoryImageMap, Config},
+    error::PresenceError,
+    heartbeat,
+    history::BuildHistoryDb,
+    metrics,
+    mtimedb::MergeState,
+    portage::{self, pid_alive, EmergeFlags, OperationType, PackagePayload, PackageState, Portage},
+    session::{
+        CompletionSummary, Failure, MergeSession, SessionDump, SessionListEntry, SessionStatus,
+    },
+    state::StateFile,
+    template::{TemplateContext, Templates},
+    unix_secs,
+    webhook::WebhookClient,
+};
+
+/// Every discord ipc socket found, there can be more than one with several instances (stable,
+/// canary, ptb) running.
+fn find_ipc_paths() -> Vec<PathBuf> {
+    let base = PathBuf::from(
+        ["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"]
+            .into_iter()
+            .find_map(|v| env::var(v).ok())
+            .unwrap_or_else(|| "/tmp".to_owned()),
+    );
+    (0..20)
+        .filter_map(|n| base.join(format!("discord-ipc-{n}")).canonicalize().ok())
+        .collect()
+}
+
+/// A count with thousands separators: `1,847`.
+fn format_count(count: u32) -> String {
+    let digits = count.to_string();
+    let mut out = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(digit);
+    }
+    out
+}
+
+/// Discord refuses activity strings longer than this.
+const MAX_FIELD_LEN: usize = 128;
+
+/// The flags to show: every enabled flag (`+flag`) without a baseline, otherwise only the
+/// differences with it, including the flags of the baseline that were disabled (`-flag`).
+fn use_flag_changes(flags: &[String], baseline: Option<&HashSet<String>>) -> Vec<String> {
+    let Some(baseline) = baseline else {
+        return flags.iter().map(|flag| format!("+{flag}")).collect();
+    };
+    let enabled: HashSet<&str> = flags.iter().map(String::as_str).collect();
+    let mut disabled: Vec<&String> = baseline
+        .iter()
+        .filter(|flag| !enabled.contains(flag.as_str()))
+        .collect();
+    disabled.sort();
+    flags
+        .iter()
+        .filter(|flag| !baseline.contains(*flag))
+        .map(|flag| format!("+{flag}"))
+        .chain(disabled.into_iter().map(|flag| format!("-{flag}")))
+        .collect()
+}
+
+/// Cut `text` to `MAX_FIELD_LEN` characters, ending with an ellipsis if it was too long.
+fn truncate_field(text: String) -> String {
+    if text.chars().count() <= MAX_FIELD_LEN {
+        return text;
+    }
+    let mut text: String = text.chars().take(MAX_FIELD_LEN - 1).collect();
+    text.push('…');
+    text
+}
+
+/// Join as many flags as fit in `room` characters (each prefixed by a space), ending with an
+/// ellipsis if some had to be left out.
+fn fit_use_flags(flags: &[String], room: usize) -> String {
+    let mut text = String::new();
+    for (i, flag) in flags.iter().enumerate() {
+        let is_last = i + 1 == flags.len();
+        // Keep space for " …" unless this is the last flag
+        let reserve = if is_last { 0 } else { 2 };
+        if text.chars().count() + 1 + flag.chars().count() + reserve > room {
+            if room >= text.chars().count() + 2 {
+                text += " …";
+            }
+            break;
+        }
+        text.push(' ');
+        text += flag;
+    }
+    text
+}
+
+/// Snapshot of the client, written on SIGUSR1.
+#[derive(Serialize)]
+pub struct StateDump<'a> {
+    /// The [`Display`] of the client.
+    summary: String,
+    connected: bool,
+    discord_path: Option<&'a Path>,
+    /// What discord told us when connecting.
+    ready: Option<&'a ReadyPayload>,
+    /// Unix timestamp of the last command received.
+    last_command: Option<u64>,
+    backoff: BackoffDump,
+    active_sessions: Vec<SessionDump<'a>>,
+    /// Repositories being synced, if a sync is shown.
+    syncing: Option<&'a [String]>,
+}
+
+/// The backoff in the state dump.
+#[derive(Serialize)]
+pub struct BackoffDump {
+    consecutive_failures: u32,
+    next_retry_in_secs: f64,
+}
+
+/// Where the connection to discord is at.
+#[derive(Debug)]
+pub enum ConnectionState {
+    Disconnected,
+    /// The socket is open, but discord didn't answer the handshake yet.
+    Connecting {
+        stream: UnixStream,
+        path: PathBuf,
+    },
+    Connected {
+        stream: UnixStream,
+        path: PathBuf,
+    },
+    /// The last `attempt` connection attempts failed, the next one waits for `next_retry` so we
+    /// don't hammer (and spam the logs about) a discord socket that isn't there.
+    Reconnecting {
+        attempt: u32,
+        next_retry: Instant,
+    },
+}
+
+impl ConnectionState {
+    const BACKOFF_BASE: Duration = Duration::from_secs(1);
+    /// Attempts are counted up to this, where the delay has long reached its max, so that
+    /// counting on from there can't overflow.
+    const MAX_ATTEMPT: u32 = 32;
+
+    /// The socket, while connecting or connected.
+    fn stream(&mut self) -> Option<&mut UnixStream> {
+        match self {
+            Self::Connecting { stream, .. } | Self::Connected { stream, .. } => Some(stream),
+            Self::Disconnected | Self::Reconnecting { .. } => None,
+        }
+    }
+
+    /// The discord socket, while connecting or connected.
+    fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Connecting { path, .. } | Self::Connected { path, .. } => Some(path),
+            Self::Disconnected | Self::Reconnecting { .. } => None,
+        }
+    }
+
+    fn failed_attempts(&self) -> u32 {
+        match self {
+            Self::Reconnecting { attempt, .. } => *attempt,
+            _ => 0,
+        }
+    }
+
+    /// Time left before the next connection attempt is allowed.
+    fn retry_in(&self) -> Duration {
+        match self {
+            Self::Reconnecting { next_retry, .. } => {
+                next_retry.saturating_duration_since(Instant::now())
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// The state after `attempt` failed attempts, along with the delay until the next one:
+    /// `min(base * 2^(attempt - 1), max)` with ±25% jitter.
+    fn retry(attempt: u32, max: Duration) -> (Self, Duration) {
+        let attempt = attempt.min(Self::MAX_ATTEMPT);
+        let delay = Self::BACKOFF_BASE
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(max)
+            .mul_f64(rand::thread_rng().gen_range(0.75..=1.25));
+        let next_retry = Instant::now() + delay;
+        (
+            Self::Reconnecting {
+                attempt,
+                next_retry,
+            },
+            delay,
+        )
+    }
+
+    /// The state after discord closed the connection with `code`, along with the delay until the
+    /// next attempt: the usual first one, or the longest for codes that aren't
+    /// [worth reconnecting](worth_reconnecting) after (where we keep trying, slowly, in case
+    /// discord was wrong).
+    fn closed(code: u32, max: Duration) -> (Self, Duration) {
+        match worth_reconnecting(code) {
+            true => Self::retry(1, max),
+            false => Self::retry(Self::MAX_ATTEMPT, max),
+        }
+    }
+}
+
+/// Token bucket for activity updates: discord ignores updates (or drops the connection) past 5
+/// per 20 seconds.
+pub struct RateLimiter {
+    capacity: u32,
+    tokens: u32,
+    /// How long it takes to get a token back.
+    refill: Duration,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, refill: Duration) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            refill,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        if self.tokens >= self.capacity || self.refill.is_zero() {
+            self.tokens = self.capacity;
+            self.last_refill = Instant::now();
+            return;
+        }
+        let refilled = (self.last_refill.elapsed().as_nanos() / self.refill.as_nanos()) as u32;
+        self.tokens = self.tokens.saturating_add(refilled).min(self.capacity);
+        self.last_refill += self.refill * refilled;
+    }
+
+    /// Take a token if there is one.
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    /// How long until a token is available.
+    pub fn wait_time(&mut self) -> Duration {
+        self.refill();
+        if self.tokens > 0 {
+            Duration::ZERO
+        } else {
+            (self.last_refill + self.refill).saturating_duration_since(Instant::now())
+        }
+    }
+}
+
+/// Activity updates held back by the rate limiter, older ones are dropped past this.
+const MAX_QUEUED_ACTIVITIES: usize = 2;
+
+/// Opcodes of the discord ipc.
+pub const IPC_HANDSHAKE: u32 = 0;
+pub const IPC_FRAME: u32 = 1;
+pub const IPC_CLOSE: u32 = 2;
+pub const IPC_PING: u32 = 3;
+pub const IPC_PONG: u32 = 4;
+
+/// Codes of the close frames discord sends.
+pub const CLOSE_NORMAL: u32 = 1000;
+pub const CLOSE_UNSUPPORTED: u32 = 1003;
+pub const CLOSE_INVALID_CLIENT_ID: u32 = 4000;
+pub const CLOSE_INVALID_ORIGIN: u32 = 4001;
+pub const CLOSE_RATE_LIMITED: u32 = 4002;
+pub const CLOSE_INVALID_VERSION: u32 = 4004;
+pub const CLOSE_INVALID_ENCODING: u32 = 4005;
+
+/// Whether connecting again can go better after discord closed the connection with `code`.
+/// Discord shutting down (normal close) or rate limiting us will pass, it refusing our client id
+/// or what we send won't until we (or the config) change.
+pub fn worth_reconnecting(code: u32) -> bool {
+    !matches!(
+        code,
+        CLOSE_UNSUPPORTED
+            | CLOSE_INVALID_CLIENT_ID
+            | CLOSE_INVALID_ORIGIN
+            | CLOSE_INVALID_VERSION
+            | CLOSE_INVALID_ENCODING
+    )
+}
+
+#[derive(Deserialize)]
+struct CloseFrame {
+    #[serde(default)]
+    code: u32,
+    #[serde(default)]
+    message: String,
+}
+
+/// How long discord has to answer a command.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct PendingRequest {
+    cmd: &'static str,
+    sent_at: Instant,
+}
+
+/// Data of the `READY` event discord sends after the handshake.
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
+pub struct ReadyPayload {
+    user: Option<DiscordUser>,
+    /// Only there once authorized with OAuth2, which we don't do.
+    scopes: Option<Vec<String>>,
+}
+
+/// The discord user we're connected as.
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
+pub struct DiscordUser {
+    id: String,
+    username: String,
+    /// "0" for users that migrated to unique usernames.
+    discriminator: String,
+}
+
+impl Display for DiscordUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.discriminator.as_str() {
+            "" | "0" => write!(f, "{}", self.username),
+            discriminator => write!(f, "{}#{discriminator}", self.username),
+        }
+    }
+}
+
+/// The scope needed to set activities, when discord tells us about scopes.
+const ACTIVITIES_SCOPE: &str = "rpc.activities.write";
+
+/// Reply to the query command: the status of the shown session, who it's shown to and the
+/// version of portage.
+#[derive(Serialize)]
+pub struct QueryReply<'a> {
+    #[serde(flatten)]
+    status: SessionStatus<'a>,
+    /// The [`Display`] of the client.
+    summary: String,
+    discord_user: Option<String>,
+    portage_version: Option<&'static semver::Version>,
+}
+
+/// The error in the data of an `ERROR` event.
+fn discord_error(data: &serde_json::Value) -> PresenceError {
+    PresenceError::Discord {
+        code: data["code"].as_i64().unwrap_or_default(),
+        message: data["message"]
+            .as_str()
+            .unwrap_or("unknown error")
+            .to_owned(),
+    }
+}
+
+/// A sync of the ebuild repositories, which isn't tied to any emerge session.
+struct RepoSync {
+    repos: Vec<String>,
+    started_at: SystemTime,
+}
+
+/// Called with the name and data of the events discord dispatches.
+pub type DispatchHandler = Box<dyn FnMut(&str, &serde_json::Value) + Send>;
+
+/// Wait for `events` on `stream` until `deadline`, returning whether they happened.
+#[cfg(not(feature = "tokio"))]
+fn wait_for(stream: &UnixStream, events: PollFlags, deadline: Instant) -> std::io::Result<bool> {
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        // Rounded up, a poll of 0ms for the last few µs would spin
+        let timeout = i32::try_from(left.as_micros().div_ceil(1000)).unwrap_or(i32::MAX);
+        let mut fds = [PollFd::new(stream.as_raw_fd(), events)];
+        match poll(&mut fds, timeout) {
+            Ok(n) => return Ok(n > 0),
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// The first frame of `buf` if it's all there, taken out of it.
+fn take_frame(buf: &mut Vec<u8>) -> Result<Option<(u32, String)>, PresenceError> {
+    let Some(header) = buf.get(..8) else {
+        return Ok(None);
+    };
+    let opcode = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    // Waiting for the rest would buffer whatever discord (or a desync) claims
+    if len > MAX_FRAME_LEN {
+        return Err(PresenceError::IpcFrame(format!(
+            "Frame of {len} bytes is too big"
+        )));
+    }
+    if buf.len() < 8 + len {
+        return Ok(None);
+    }
+    let frame: Vec<u8> = buf.drain(..8 + len).skip(8).collect();
+    let payload = String::from_utf8(frame)
+        .map_err(|_| PresenceError::IpcFrame("Payload isn't valid utf-8".to_owned()))?;
+    tracing::trace!(opcode, %payload, "Received frame");
+    Ok(Some((opcode, payload)))
+}
+
+/// Connection to discord, and the sessions it shows.
+pub struct Client {
+    client_id: String,
+    connection: ConnectionState,
+    /// What was read from the socket of the connection without making a whole frame yet.
+    read_buf: Vec<u8>,
+    /// Socket of the last discord instance we connected to, tried first on reconnections.
+    last_path: Option<PathBuf>,
+    /// Discord socket to use instead of searching for one.
+    ipc_socket_path: Option<PathBuf>,
+    /// The `READY` event of the current connection.
+    ready: Option<ReadyPayload>,
+    /// When discord last answered a ping.
+    last_pong: Option<Instant>,
+    /// How often the [`Heartbeat`](crate::heartbeat::Heartbeat) pings discord.
+    ping_interval: Duration,
+    /// Longest wait between two connection attempts.
+    max_reconnect_delay: Duration,
+    activity_type: ActivityType,
+    /// Active sessions by emerge pid, 0 is the session of hooks that don't send a pid.
+    active_sessions: HashMap<u32, MergeSession>,
+    /// The sync in progress, from the sync command until sync-done.
+    sync: Option<RepoSync>,
+    /// When the summary of the last emerge was shown, until something else is.
+    summary_shown_at: Option<Instant>,
+    /// Add a summary of the emerge options to the state.
+    show_emerge_flags: bool,
+    /// Show the build system as the small image rather than the phase.
+    show_build_system: bool,
+    show_elapsed: bool,
+    /// Add the number of installed packages to the state.
+    show_installed_count: bool,
+    /// Only show the use flags that differ from these.
+    use_baseline: Option<HashSet<String>>,
+    /// Add a button linking to packages.gentoo.org.
+    show_package_button: bool,
+    /// Add a button linking to the homepage of the package, if the hook sent it.
+    show_homepage_button: bool,
+    /// The `instance` of the package activities.
+    activity_instance: bool,
+    pub(crate) last_command: Option<SystemTime>,
+    /// Commands sent to discord that haven't been answered yet, by nonce.
+    pending_nonces: HashMap<String, PendingRequest>,
+    /// Events subscribed to, subscribed to again after reconnecting.
+    subscriptions: Vec<String>,
+    dispatch_handler: Option<DispatchHandler>,
+    /// The merge list and installed packages.
+    portage: Portage,
+    /// Where the sessions are saved after each change.
+    state_file: Option<StateFile>,
+    history: Option<BuildHistoryDb>,
+    /// Overrides of the asset keys, for applications with differently named assets.
+    assets_map: HashMap<String, String>,
+    /// Large images of the packages by category.
+    category_images: CategoryImageMap,
+    /// The images given to the builder, which the ones of the config don't replace.
+    builder_category_images: CategoryImageMap,
+    /// Formats of the details and state set in the config.
+    templates: Option<Templates>,
+    rate_limiter: RateLimiter,
+    /// Whether the updates go through the rate limiter, see [`ClientBuilder::rate_limit`].
+    rate_limited: bool,
+    /// Counts the package activities, for their party id.
+    activity_sequence: u32,
+    /// Activities (or `null` to clear) waiting for the rate limiter, oldest first.
+    queued_activities: VecDeque<serde_json::Value>,
+    /// Print the activities to stdout instead of talking to discord, which then always looks
+    /// connected.
+    dry_run: bool,
+    /// Where the activities go while discord can't be reached over ipc.
+    webhook: Option<WebhookClient>,
+    /// Poll the socket is registered with after each connection, see [`Self::register`].
+    #[cfg(not(feature = "tokio"))]
+    registry: Option<(Registry, Token)>,
+}
+
+/// One line summary of the connection and of the session shown, for the logs, the query reply and
+/// the state dump: `Client { connected: yes, discord_path: /run/user/1000/discord-ipc-0, session:
+/// 3/7 dev-libs/openssl-3.0.1 [compiling] }`.
+impl Display for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Client {{ connected: ")?;
+        match &self.connection {
+            _ if self.dry_run => write!(f, "yes (dry run)")?,
+            ConnectionState::Connected { path, .. } => {
+                write!(f, "yes, discord_path: {}", path.display())?;
+            }
+            ConnectionState::Connecting { path, .. } => {
+                write!(f, "no (handshake), discord_path: {}", path.display())?;
+            }
+            ConnectionState::Reconnecting { .. } => {
+                let retry_in = self.connection.retry_in().as_secs_f64().ceil();
+                write!(f, "no (backoff: {retry_in}s)")?;
+            }
+            ConnectionState::Disconnected => write!(f, "no")?,
+        }
+        write!(f, ", session: ")?;
+        let Some((_, session)) = self.latest_session() else {
+            return write!(f, "none }}");
+        };
+        match &session.current_package {
+            Some(payload) => {
+                if let Some((position, total)) = session.queue_position() {
+                    write!(f, "{position}/{total} ")?;
+                }
+                write!(f, "{}/{}", payload.category, payload.package)?;
+                if let Some(version) = payload.full_version() {
+                    write!(f, "-{version}")?;
+                }
+                if let Some(state) = &payload.state {
+                    write!(f, " [{state}]")?;
+                }
+            }
+            None => match session.operation {
+                Some(operation) => write!(f, "{}", operation.description())?,
+                None => write!(f, "starting")?,
+            },
+        }
+        if self.active_sessions.len() > 1 {
+            write!(f, " (+{} more)", self.active_sessions.len() - 1)?;
+        }
+        write!(f, " }}")
+    }
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut pids: Vec<_> = self.active_sessions.keys().collect();
+        pids.sort();
+        f.debug_struct("Client")
+            .field("client_id", &self.client_id)
+            .field("connection", &self.connection)
+            .field("last_path", &self.last_path)
+            .field("ipc_socket_path", &self.ipc_socket_path)
+            .field("ready", &self.ready)
+            .field("last_pong", &self.last_pong)
+            .field("session_pids", &pids)
+            .field("syncing", &self.sync.as_ref().map(|sync| &sync.repos))
+            .field("pending_nonces", &self.pending_nonces.keys())
+            .field("queued_activities", &self.queued_activities.len())
+            .field("subscriptions", &self.subscriptions)
+            .field("summary_shown_at", &self.summary_shown_at)
+            .field("last_command", &self.last_command)
+            .field("dry_run", &self.dry_run)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Longest wait between two connection attempts, unless [`ClientBuilder::max_reconnect_delay`]
+/// says otherwise.
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Options of a [`Client`], for those that aren't in the [`Config`] (or to create one without).
+///
+/// ```
+/// # use emerge_presence::discord::ClientBuilder;
+/// let client = ClientBuilder::default()
+///     .client_id("1007427345801556039")
+///     .rate_limit(false)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct ClientBuilder {
+    client_id: Option<String>,
+    heartbeat_interval: Duration,
+    max_reconnect_delay: Duration,
+    category_images: HashMap<String, String>,
+    rate_limit: bool,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            client_id: None,
+            heartbeat_interval: heartbeat::PING_INTERVAL,
+            max_reconnect_delay: MAX_RECONNECT_DELAY,
+            category_images: HashMap::new(),
+            rate_limit: true,
+        }
+    }
+}
+
+impl ClientBuilder {
+    /// The id of the discord application, the only thing that has to be set.
+    pub fn client_id(mut self, id: &str) -> Self {
+        self.client_id = Some(id.to_owned());
+        self
+    }
+
+    /// How often discord is pinged, [`heartbeat::PING_INTERVAL`] by default.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// The longest the backoff waits between two connection attempts, [`MAX_RECONNECT_DELAY`]
+    /// by default.
+    pub fn max_reconnect_delay(mut self, delay: Duration) -> Self {
+        self.max_reconnect_delay = delay;
+        self
+    }
+
+    /// Large images by category or category prefix, like the `category_images` of the config
+    /// (which only adds images for the categories that have none here).
+    pub fn category_images(mut self, images: HashMap<String, String>) -> Self {
+        self.category_images = images;
+        self
+    }
+
+    /// Whether to hold back activity updates past what discord allows (the default), with the
+    /// limits of the config once it's [applied](Client::apply_config).
+    pub fn rate_limit(mut self, enabled: bool) -> Self {
+        self.rate_limit = enabled;
+        self
+    }
+
+    pub fn build(self) -> Result<Client, PresenceError> {
+        let client_id = self
+            .client_id
+            .filter(|id| !id.is_empty())
+            .ok_or(PresenceError::NoClientId)?;
+        // A zero refill delay gives the token back right away
+        let rate_limiter = match self.rate_limit {
+            true => RateLimiter::new(5, Duration::from_secs(4)),
+            false => RateLimiter::new(1, Duration::ZERO),
+        };
+        Ok(Client {
+            ping_interval: self.heartbeat_interval,
+            max_reconnect_delay: self.max_reconnect_delay,
+            category_images: CategoryImageMap::from(self.category_images.clone()),
+            builder_category_images: CategoryImageMap::from(self.category_images),
+            rate_limiter,
+            rate_limited: self.rate_limit,
+            ..Client::with_id(client_id)
+        })
+    }
+}
+
+impl Client {
+    #[deprecated(note = "use `ClientBuilder::default().client_id(id).build()`")]
+    pub fn new(id: &(impl ToString + ?Sized)) -> Self {
+        Self::with_id(id.to_string())
+    }
+
+    fn with_id(client_id: String) -> Self {
+        Self {
+            client_id,
+            connection: ConnectionState::Disconnected,
+            read_buf: Vec::new(),
+            last_path: None,
+            ipc_socket_path: None,
+            ready: None,
+            last_pong: None,
+            ping_interval: heartbeat::PING_INTERVAL,
+            max_reconnect_delay: MAX_RECONNECT_DELAY,
+            activity_type: ActivityType::default(),
+            active_sessions: HashMap::new(),
+            sync: None,
+            summary_shown_at: None,
+            show_emerge_flags: false,
+            show_build_system: false,
+            show_elapsed: false,
+            show_installed_count: false,
+            use_baseline: None,
+            show_package_button: true,
+            show_homepage_button: true,
+            activity_instance: false,
+            last_command: None,
+            pending_nonces: HashMap::new(),
+            subscriptions: Vec::new(),
+            dispatch_handler: None,
+            portage: Portage::new(),
+            state_file: None,
+            history: None,
+            assets_map: HashMap::new(),
+            category_images: CategoryImageMap::default(),
+            builder_category_images: CategoryImageMap::default(),
+            templates: None,
+            rate_limiter: RateLimiter::new(5, Duration::from_secs(4)),
+            rate_limited: true,
+            activity_sequence: 0,
+            queued_activities: VecDeque::new(),
+            dry_run: false,
+            webhook: None,
+            #[cfg(not(feature = "tokio"))]
+            registry: None,
+        }
+    }
+
+    /// Take the options set in the config.
+    pub fn apply_config(&mut self, config: &Config) {
+        self.show_emerge_flags = config.show_emerge_flags;
+        self.show_build_system = config.show_build_system;
+        self.show_elapsed = config.show_elapsed;
+        self.show_installed_count = config.show_installed_count;
+        self.use_baseline = config
+            .use_baseline
+            .as_ref()
+            .map(|flags| flags.iter().cloned().collect());
+        self.ipc_socket_path = config.ipc_socket_path.clone();
+        self.activity_type = config.activity_type;
+        self.assets_map = config.assets_map.clone();
+        self.category_images = self.builder_category_images.or(&config.category_images);
+        if self.rate_limited {
+            self.rate_limiter = RateLimiter::new(
+                config.rate_limit_updates,
+                Duration::from_secs(config.rate_limit_refill_secs),
+            );
+        }
+        self.show_package_button = config.show_package_button;
+        self.show_homepage_button = config.show_homepage_button;
+        self.activity_instance = config.activity_instance;
+        self.webhook = WebhookClient::from_config(config);
+    }
+
+    /// Restore the sessions saved in `state`, and save them there after each change.
+    pub fn use_state_file(&mut self, state: StateFile) {
+        match state.load() {
+            Ok(sessions) => {
+                if !sessions.is_empty() {
+                    tracing::info!("Restored {} sessions", sessions.len());
+                }
+                self.active_sessions = sessions;
+            }
+            Err(err) => tracing::warn!("Couldn't restore sessions ({err:?})"),
+        }
+        self.state_file = Some(state);
+    }
+
+    /// Record how long packages take to build in `history`, and show the estimates of the ones
+    /// built before.
+    pub fn set_history(&mut self, history: BuildHistoryDb) {
+        self.history = Some(history);
+    }
+
+    /// Format the details and state with `templates` instead of the defaults.
+    pub fn set_templates(&mut self, templates: Templates) {
+        self.templates = Some(templates);
+    }
+
+    /// Print the activities to stdout instead of talking to discord.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Read the merge state again, for when the mtimedb was written.
+    pub fn refresh_merge_state(&mut self) {
+        self.portage.refresh_merge_state();
+    }
+
+    /// How many emerges are tracked.
+    pub fn session_count(&self) -> usize {
+        self.active_sessions.len()
+    }
+
+    maybe_async! {
+        /// Clear the presence and say goodbye to discord. The sessions are saved (for the next
+        /// instance on a restart) but forgotten, nothing will update them anymore.
+        pub fn shutdown(&mut self) {
+            self.save_state();
+            self.active_sessions.clear();
+            self.sync = None;
+            // Without a connection this removes the webhook message, if any
+            if let Err(err) = self.clear_presence() {
+                tracing::warn!("Couldn't clear presence ({err:?})");
+            }
+            // Queued with tokio, or by the rate limiter
+            if self.is_connected() {
+                if let Err(err) = maybe_await!(self.flush_activities()) {
+                    tracing::warn!("Couldn't clear presence ({err:?})");
+                }
+            }
+            // Waits for the removal to go through
+            drop(self.webhook.take());
+            if self.is_connected() {
+                maybe_await!(self.disconnect()).ok();
+            }
+        }
+    }
+    pub fn is_connected(&self) -> bool {
+        self.dry_run || matches!(self.connection, ConnectionState::Connected { .. })
+    }
+    /// Whether the backoff allows another connection attempt yet.
+    pub fn should_retry(&self) -> bool {
+        self.connection.retry_in().is_zero()
+    }
+    maybe_async! {
+        fn open_stream(&mut self) -> Result<(), PresenceError> {
+            let retry_in = self.connection.retry_in();
+            if !retry_in.is_zero() {
+                return Err(PresenceError::RetryLater(retry_in));
+            }
+            let failed_attempts = self.connection.failed_attempts();
+            self.connection = ConnectionState::Disconnected;
+            let candidates = match &self.ipc_socket_path {
+                Some(path) => vec![path.clone()],
+                None => {
+                    let mut paths = find_ipc_paths();
+                    // Stick to the instance we were connected to if it's still there
+                    if let Some(last) = &self.last_path {
+                        if let Some(i) = paths.iter().position(|path| path == last) {
+                            paths[..=i].rotate_right(1);
+                        }
+                    }
+                    paths
+                }
+            };
+            let mut res = Err(PresenceError::NoIpcSocket);
+            for path in candidates {
+                match maybe_await!(UnixStream::connect(&path)) {
+                    Ok(stream) => {
+                        res = Ok((stream, path));
+                        break;
+                    }
+                    Err(err) => {
+                        tracing::debug!("Couldn't connect to {} ({err})", path.display());
+                        res = Err(err.into());
+                    }
+                }
+            }
+            match res {
+                Ok((stream, path)) => {
+                    // Reads never wait, the frames are put together in read_buf
+                    #[cfg(not(feature = "tokio"))]
+                    stream.set_nonblocking(true)?;
+                    self.read_buf.clear();
+                    if self.last_path.is_some() {
+                        metrics::record_reconnect();
+                    }
+                    self.last_path = Some(path.clone());
+                    self.connection = ConnectionState::Connecting { stream, path };
+                    Ok(())
+                }
+                Err(err) => {
+                    let (state, delay) = ConnectionState::retry(
+                        failed_attempts.saturating_add(1),
+                        self.max_reconnect_delay,
+                    );
+                    self.connection = state;
+                    tracing::debug!("Connection failed, next attempt in {delay:?}");
+                    Err(err)
+                }
+            }
+        }
+    }
+    fn handle_io(&mut self, io: std::io::Result<()>) -> Result<(), PresenceError> {
+        match io {
+            Err(io) => match io.kind() {
+                std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset => {
+                    if self.connection.stream().is_some() {
+                        // Dropping the socket closes it
+                        self.connection = ConnectionState::Disconnected;
+                        Err(PresenceError::BrokenPipe)
+                    } else {
+                        Ok(())
+                    }
+                }
+                _ => Err(io.into()),
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+    maybe_async! {
+        #[tracing::instrument(skip(self))]
+        pub fn connect(&mut self) -> Result<(), PresenceError> {
+            tracing::trace!("Connect");
+            if !self.is_connected() {
+                maybe_await!(self.establish())?;
+            }
+            Ok(())
+        }
+    }
+    maybe_async! {
+        /// Open the socket and go through the handshake.
+        fn establish(&mut self) -> Result<(), PresenceError> {
+            maybe_await!(self.open_stream())?;
+            tracing::trace!("Socket open");
+            if let Err(err) = maybe_await!(self.handshake()) {
+                // The next handshake would most likely fail the same way, don't retry right away.
+                // Close frames already scheduled the next attempt according to their code.
+                if !matches!(err, PresenceError::Closed { .. }) {
+                    let (state, delay) = ConnectionState::retry(1, self.max_reconnect_delay);
+                    self.connection = state;
+                    tracing::debug!("Handshake failed, next attempt in {delay:?}");
+                }
+                return Err(err);
+            }
+            self.connection =
+                match std::mem::replace(&mut self.connection, ConnectionState::Disconnected) {
+                    ConnectionState::Connecting { stream, path } => {
+                        ConnectionState::Connected { stream, path }
+                    }
+                    state => state,
+                };
+            tracing::trace!("Connected");
+            #[cfg(not(feature = "tokio"))]
+            self.register_stream();
+            // The ipc presence takes over
+            if let Some(webhook) = &mut self.webhook {
+                webhook.clear();
+            }
+            for event in self.subscriptions.clone() {
+                if let Err(err) = maybe_await!(self.request("SUBSCRIBE", Some(&event), json!({}))) {
+                    tracing::warn!("Couldn't subscribe to {event} again ({err})");
+                }
+            }
+            Ok(())
+        }
+    }
+    /// Register the socket with `registry` (now if connected, and after each connection), so that
+    /// what discord sends on its own wakes the poll with `token` instead of waiting for its
+    /// timeout. The events are still read by [`Self::poll_events`], which reads until there is
+    /// nothing left as the registration is edge triggered.
+    #[cfg(not(feature = "tokio"))]
+    pub fn register(&mut self, registry: &Registry, token: Token) -> std::io::Result<()> {
+        self.registry = Some((registry.try_clone()?, token));
+        self.register_stream();
+        Ok(())
+    }
+    #[cfg(not(feature = "tokio"))]
+    fn register_stream(&mut self) {
+        let (Some((registry, token)), Some(stream)) = (&self.registry, self.connection.stream())
+        else {
+            return;
+        };
+        // The registration goes away with the socket, a new one gets a new registration
+        let fd = stream.as_raw_fd();
+        if let Err(err) = registry.register(&mut SourceFd(&fd), *token, Interest::READABLE) {
+            tracing::warn!(
+                "Couldn't register the discord socket, events wait for the next wakeup ({err})"
+            );
+        }
+    }
+    fn nonce(&self) -> String {
+        format!("{:016x}", rand::random::<u128>())
+    }
+    maybe_async! {
+        /// Send a command and wait for the response with the same nonce, frames without (or with
+        /// another) nonce are skipped. Errors reported by discord are returned as
+        /// [`PresenceError::Discord`].
+        pub fn command(
+            &mut self,
+            cmd: &'static str,
+            args: serde_json::Value,
+        ) -> Result<serde_json::Value, PresenceError> {
+            maybe_await!(self.request(cmd, None, args))
+        }
+    }
+    maybe_async! {
+        /// [`Self::command`], for the commands that are about an event.
+        fn request(
+            &mut self,
+            cmd: &'static str,
+            evt: Option<&str>,
+            args: serde_json::Value,
+        ) -> Result<serde_json::Value, PresenceError> {
+            let nonce = self.nonce();
+            let mut frame = json!({ "cmd": cmd, "nonce": nonce, "args": args });
+            if let Some(evt) = evt {
+                frame["evt"] = json!(evt);
+            }
+            maybe_await!(self.send(IPC_FRAME, &frame))?;
+            if self.dry_run {
+                return Ok(serde_json::Value::Null);
+            }
+            self.pending_nonces.insert(
+                nonce.clone(),
+                PendingRequest {
+                    cmd,
+                    sent_at: Instant::now(),
+                },
+            );
+            let deadline = Instant::now() + RESPONSE_TIMEOUT;
+            loop {
+                self.pending_nonces
+                    .retain(|nonce, pending| match pending.sent_at.elapsed() {
+                        elapsed if elapsed > RESPONSE_TIMEOUT => {
+                            tracing::warn!(
+                                "No response to {} ({nonce}) in {elapsed:?}",
+                                pending.cmd
+                            );
+                            false
+                        }
+                        _ => true,
+                    });
+                if !self.pending_nonces.contains_key(&nonce) {
+                    return Err(PresenceError::Timeout(RESPONSE_TIMEOUT));
+                }
+                let payloads = match maybe_await!(self.wait_data(deadline)) {
+                    Err(err @ PresenceError::Timeout(_)) => {
+                        self.pending_nonces.remove(&nonce);
+                        tracing::warn!("No response to {cmd} ({nonce}) in {RESPONSE_TIMEOUT:?}");
+                        return Err(err);
+                    }
+                    res => res?,
+                };
+                // The frames after the response are handled too, nothing keeps them for later
+                let mut response = None;
+                for payload in payloads {
+                    let frame: serde_json::Value = serde_json::from_str(&payload)?;
+                    let Some(id) = frame["nonce"].as_str() else {
+                        if frame["cmd"] == "DISPATCH" {
+                            self.dispatch(&frame);
+                        } else {
+                            tracing::debug!("Skipping frame without nonce: {payload}");
+                        }
+                        continue;
+                    };
+                    let Some(pending) = self.pending_nonces.remove(id) else {
+                        tracing::debug!("Skipping response to unknown nonce {id}");
+                        continue;
+                    };
+                    if id != nonce {
+                        tracing::debug!("Late response to {} ({id})", pending.cmd);
+                        continue;
+                    }
+                    response = Some(frame);
+                }
+                match response {
+                    Some(response) if response["evt"] == "ERROR" => {
+                        return Err(discord_error(&response["data"]));
+                    }
+                    Some(response) => return Ok(response),
+                    None => {}
+                }
+            }
+        }
+    }
+    maybe_async! {
+        pub fn send(&mut self, opcode: u32, payload: &impl Serialize) -> Result<(), PresenceError> {
+            if self.dry_run {
+                // Only commands are interesting, not the close frames
+                if opcode == IPC_FRAME {
+                    println!("{}", serde_json::to_string_pretty(payload)?);
+                }
+                return Ok(());
+            }
+            let payload = serde_json::to_string(payload)?;
+            let frame = encode_frame(opcode, payload.as_bytes());
+            maybe_await!(self.write_frame(&frame))?;
+            tracing::trace!(opcode, %payload, "Sent frame");
+            Ok(())
+        }
+    }
+    /// Write all of `frame`, giving up (and on the connection) after [`RESPONSE_TIMEOUT`].
+    #[cfg(not(feature = "tokio"))]
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), PresenceError> {
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        let mut written = 0;
+        while written < frame.len() {
+            let stream = self
+                .connection
+                .stream()
+                .ok_or(PresenceError::Disconnected)?;
+            let res = match stream.write(&frame[written..]) {
+                Ok(0) => Err(ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    written += len;
+                    Ok(())
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => Ok(()),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    match wait_for(stream, PollFlags::POLLOUT, deadline) {
+                        Ok(true) => Ok(()),
+                        Ok(false) => {
+                            // Part of the frame may be sent, there's no getting back in sync
+                            self.connection = ConnectionState::Disconnected;
+                            self.pending_nonces.clear();
+                            return Err(PresenceError::Timeout(RESPONSE_TIMEOUT));
+                        }
+                        Err(err) => Err(err),
+                    }
+                }
+                Err(err) => Err(err),
+            };
+            self.handle_io(res)?;
+        }
+        Ok(())
+    }
+    /// Write all of `frame`, giving up (and on the connection) after [`RESPONSE_TIMEOUT`].
+    #[cfg(feature = "tokio")]
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), PresenceError> {
+        let stream = self
+            .connection
+            .stream()
+            .ok_or(PresenceError::Disconnected)?;
+        match tokio::time::timeout(RESPONSE_TIMEOUT, stream.write_all(frame)).await {
+            Ok(res) => self.handle_io(res),
+            Err(_) => {
+                // Part of the frame may be sent, there's no getting back in sync
+                self.connection = ConnectionState::Disconnected;
+                self.pending_nonces.clear();
+                Err(PresenceError::Timeout(RESPONSE_TIMEOUT))
+            }
+        }
+    }
+    /// Read what discord sent so far and return the frames that are whole, the rest of the last
+    /// one staying buffered for the next call. Never waits: the list is empty until a frame is
+    /// all there.
+    pub fn recv(&mut self) -> Result<Vec<(u32, String)>, PresenceError> {
+        if self.dry_run {
+            // Nobody to answer, pretend discord is fine with everything we send
+            return Ok(vec![(IPC_FRAME, "{}".to_owned())]);
+        }
+        let stream = self
+            .connection
+            .stream()
+            .ok_or(PresenceError::Disconnected)?;
+        let mut chunk = [0u8; 4096];
+        // Until there's nothing left, the registration with the poll is edge triggered
+        let res = loop {
+            #[cfg(not(feature = "tokio"))]
+            let read = stream.read(&mut chunk);
+            #[cfg(feature = "tokio")]
+            let read = stream.try_read(&mut chunk);
+            match read {
+                Ok(0) => break Err(ErrorKind::BrokenPipe.into()),
+                Ok(len) => self.read_buf.extend_from_slice(&chunk[..len]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        let mut frames = Vec::new();
+        // What follows a bad frame can't be made sense of
+        while let Some(frame) = take_frame(&mut self.read_buf).inspect_err(|_| {
+            self.connection = ConnectionState::Disconnected;
+            self.read_buf.clear();
+        })? {
+            frames.push(frame);
+        }
+        // Discord hanging up, the frames it sent before (a close frame) come first. The next
+        // call finds it gone again.
+        if frames.is_empty() {
+            self.handle_io(res)?;
+        }
+        Ok(frames)
+    }
+    maybe_async! {
+        /// Wait until `deadline` for discord to send data frames, answering pings and handling
+        /// close frames on the way.
+        fn wait_data(&mut self, deadline: Instant) -> Result<Vec<String>, PresenceError> {
+            loop {
+                let mut payloads = Vec::new();
+                for (opcode, payload) in self.recv()? {
+                    payloads.extend(maybe_await!(self.handle_frame(opcode, payload))?);
+                }
+                if !payloads.is_empty() {
+                    return Ok(payloads);
+                }
+                if !maybe_await!(self.readable_until(deadline))? {
+                    return Err(PresenceError::Timeout(RESPONSE_TIMEOUT));
+                }
+            }
+        }
+    }
+    /// Wait until `deadline` for something to read, returning whether there is.
+    #[cfg(not(feature = "tokio"))]
+    fn readable_until(&mut self, deadline: Instant) -> Result<bool, PresenceError> {
+        let stream = self
+            .connection
+            .stream()
+            .ok_or(PresenceError::Disconnected)?;
+        Ok(wait_for(stream, PollFlags::POLLIN, deadline)?)
+    }
+    /// Wait until `deadline` for something to read, returning whether there is.
+    #[cfg(feature = "tokio")]
+    async fn readable_until(&mut self, deadline: Instant) -> Result<bool, PresenceError> {
+        let stream = self
+            .connection
+            .stream()
+            .ok_or(PresenceError::Disconnected)?;
+        let deadline = tokio::time::Instant::from_std(deadline);
+        match tokio::time::timeout_at(deadline, stream.readable()).await {
+            Ok(res) => res.map(|()| true).map_err(Into::into),
+            Err(_) => Ok(false),
+        }
+    }
+    /// Wait for discord to send something, for [`Self::poll_events`] to read. Never returns while
+    /// disconnected (or in dry run), so that it can be raced against the other wakeups.
+    #[cfg(feature = "tokio")]
+    pub async fn readable(&mut self) -> Result<(), PresenceError> {
+        match self.connection.stream() {
+            Some(stream) if !self.dry_run => Ok(stream.readable().await?),
+            _ => std::future::pending().await,
+        }
+    }
+    maybe_async! {
+        #[tracing::instrument(skip(self))]
+        pub fn handshake(&mut self) -> Result<(), PresenceError> {
+            self.ready = None;
+            let handshake = json!({
+                "v": 1u32,
+                "client_id": self.client_id,
+                "nonce": self.nonce(),
+            });
+            maybe_await!(self.send(IPC_HANDSHAKE, &handshake))?;
+            // Discord closes the connection right away when it doesn't like the handshake (bad
+            // client id for example), which wait_data turns into an error.
+            let mut payloads =
+                maybe_await!(self.wait_data(Instant::now() + RESPONSE_TIMEOUT))?.into_iter();
+            let payload = payloads.next().unwrap_or_default();
+            tracing::debug!("Handshake response: {payload}");
+            let mut response: serde_json::Value = serde_json::from_str(&payload)?;
+            if response["evt"] == "ERROR" {
+                return Err(discord_error(&response["data"]));
+            }
+            let ready: ReadyPayload = serde_json::from_value(response["data"].take())?;
+            if let Some(user) = &ready.user {
+                tracing::info!("Connected as {user}");
+            }
+            if let Some(scopes) = &ready.scopes {
+                if !scopes.iter().any(|scope| scope == ACTIVITIES_SCOPE) {
+                    tracing::warn!(
+                        "Missing the {ACTIVITIES_SCOPE} scope, discord may refuse activities"
+                    );
+                }
+            }
+            self.ready = Some(ready);
+            for payload in payloads {
+                self.handle_event(&payload)?;
+            }
+            Ok(())
+        }
+    }
+    maybe_async! {
+        /// The payload of data frames, `None` for the others which are handled here.
+        fn handle_frame(
+            &mut self,
+            opcode: u32,
+            payload: String,
+        ) -> Result<Option<String>, PresenceError> {
+            match opcode {
+                IPC_FRAME => return Ok(Some(payload)),
+                IPC_PING => {
+                    tracing::trace!("Got ping, sending pong");
+                    let payload: serde_json::Value = serde_json::from_str(&payload)?;
+                    maybe_await!(self.send(IPC_PONG, &payload))?;
+                }
+                IPC_PONG => {
+                    tracing::trace!("Got pong");
+                    self.last_pong = Some(Instant::now());
+                }
+                IPC_CLOSE => {
+                    let CloseFrame { code, message } = serde_json::from_str(&payload)?;
+                    let (state, delay) = ConnectionState::closed(code, self.max_reconnect_delay);
+                    self.connection = state;
+                    self.pending_nonces.clear();
+                    match code {
+                        CLOSE_NORMAL => tracing::info!(
+                            "Discord closed the connection ({message}), reconnecting in {delay:?}"
+                        ),
+                        CLOSE_RATE_LIMITED => tracing::warn!(
+                            "Discord closed the connection because of rate limiting, reconnecting in {delay:?}"
+                        ),
+                        code if worth_reconnecting(code) => tracing::warn!(
+                            "Discord closed the connection ({code}: {message}), reconnecting in {delay:?}"
+                        ),
+                        code => tracing::error!(
+                            "Discord refused the connection ({code}: {message}), reconnecting in {delay:?} in case it changes its mind"
+                        ),
+                    }
+                    return Err(PresenceError::Closed { code, message });
+                }
+                _ => tracing::warn!("Ignoring frame with unknown opcode {opcode}: {payload}"),
+            }
+            Ok(None)
+        }
+    }
+    maybe_async! {
+        /// Ping discord, [`Self::last_pong`] tells when it answered.
+        pub fn ping(&mut self) -> Result<(), PresenceError> {
+            if self.dry_run {
+                self.last_pong = Some(Instant::now());
+                return Ok(());
+            }
+            let nonce = self.nonce();
+            maybe_await!(self.send(IPC_PING, &json!({ "nonce": nonce })))
+        }
+    }
+    pub fn last_pong(&self) -> Option<Instant> {
+        self.last_pong
+    }
+    pub fn ping_interval(&self) -> Duration {
+        self.ping_interval
+    }
+    maybe_async! {
+        /// Ask discord to dispatch `event` (`ACTIVITY_JOIN`, `ACTIVITY_JOIN_REQUEST`, ...) to the
+        /// handler set with [`Self::on_dispatch`], now if connected and after every reconnection.
+        pub fn subscribe(&mut self, event: &str) -> Result<(), PresenceError> {
+            if !self.subscriptions.iter().any(|sub| sub == event) {
+                self.subscriptions.push(event.to_owned());
+            }
+            if self.is_connected() {
+                maybe_await!(self.request("SUBSCRIBE", Some(event), json!({})))?;
+            }
+            Ok(())
+        }
+    }
+    /// Call `handler` with the events discord dispatches.
+    pub fn on_dispatch(&mut self, handler: impl FnMut(&str, &serde_json::Value) + Send + 'static) {
+        self.dispatch_handler = Some(Box::new(handler));
+    }
+    fn dispatch(&mut self, frame: &serde_json::Value) {
+        let event = frame["evt"].as_str().unwrap_or_default();
+        tracing::debug!("Got {event} event");
+        if let Some(handler) = &mut self.dispatch_handler {
+            handler(event, &frame["data"]);
+        }
+    }
+    /// A data frame discord sent on its own.
+    fn handle_event(&mut self, payload: &str) -> Result<(), PresenceError> {
+        let frame: serde_json::Value = serde_json::from_str(payload)?;
+        if frame["cmd"] == "DISPATCH" {
+            self.dispatch(&frame);
+        } else {
+            tracing::debug!("Skipping unexpected frame: {payload}");
+        }
+        Ok(())
+    }
+    maybe_async! {
+        /// Read what discord sent on its own since the last command: events, pings and close
+        /// frames. Everything readable is read, which is what the edge triggered registration of
+        /// the socket needs.
+        pub fn poll_events(&mut self) -> Result<(), PresenceError> {
+            if self.dry_run {
+                return Ok(());
+            }
+            let frames = match self.recv() {
+                Ok(frames) => frames,
+                Err(err) => {
+                    self.connection = ConnectionState::Disconnected;
+                    self.pending_nonces.clear();
+                    return Err(err);
+                }
+            };
+            for (opcode, payload) in frames {
+                // The close frame scheduled the reconnection
+                if let Some(payload) = maybe_await!(self.handle_frame(opcode, payload))? {
+                    self.handle_event(&payload)?;
+                }
+            }
+            Ok(())
+        }
+    }
+    maybe_async! {
+        /// Tell discord we're leaving and close the socket.
+        pub fn disconnect(&mut self) -> Result<(), PresenceError> {
+            maybe_await!(self.send(IPC_CLOSE, &json!({}))).ok();
+            self.pending_nonces.clear();
+            // Whatever we show next is sent from scratch after connecting
+            self.queued_activities.clear();
+            self.ready = None;
+            let connection = std::mem::replace(&mut self.connection, ConnectionState::Disconnected);
+            if let ConnectionState::Connecting { mut stream, .. }
+            | ConnectionState::Connected { mut stream, .. } = connection
+            {
+                tracing::trace!("Sent disconnection");
+                #[cfg(not(feature = "tokio"))]
+                {
+                    stream.flush()?;
+                    stream.shutdown(std::net::Shutdown::Both).ok();
+                }
+                #[cfg(feature = "tokio")]
+                stream.shutdown().await.ok();
+                tracing::trace!("Socket shutdown (flush)");
+            }
+            Ok(())
+        }
+    }
+    maybe_async! {
+        #[tracing::instrument(skip(self))]
+        pub fn reconnect(&mut self) -> Result<(), PresenceError> {
+            tracing::trace!("Reconnection");
+            if self.dry_run {
+                return Ok(());
+            }
+
+            maybe_await!(self.disconnect())?;
+            maybe_await!(self.establish())
+        }
+    }
+
+    /// Remove the activity without disconnecting, only once no session is left to show. A sync
+    /// that is still going is shown again instead.
+    #[tracing::instrument(skip(self))]
+    pub fn clear_presence(&mut self) -> Result<(), PresenceError> {
+        if self.has_sessions() {
+            tracing::debug!("Sessions are still active, not clearing");
+            return Ok(());
+        }
+        if self.sync.is_some() {
+            tracing::debug!("Still syncing, showing the sync");
+            return self.show_sync();
+        }
+        self.set_activity(serde_json::Value::Null)
+    }
+
+    /// Send the activity, or queue it if the rate limiter says we've been updating too often. With
+    /// tokio it's always queued, for [`Self::flush_activities`] to send.
+    fn set_activity(&mut self, activity: serde_json::Value) -> Result<(), PresenceError> {
+        self.summary_shown_at = None;
+        if !self.is_connected() {
+            if let Some(webhook) = &mut self.webhook {
+                tracing::debug!("Not connected, posting the activity to the webhook");
+                webhook.update(&activity);
+                return Ok(());
+            }
+            tracing::debug!("Not connected, the presence will be set once connected");
+            return Ok(());
+        }
+        #[cfg(not(feature = "tokio"))]
+        if self.queued_activities.is_empty() && self.rate_limiter.try_acquire() {
+            return self.send_activity(activity);
+        }
+        if self.queued_activities.len() >= MAX_QUEUED_ACTIVITIES {
+            tracing::debug!("Too many updates queued, dropping the oldest");
+            self.queued_activities.pop_front();
+        }
+        tracing::debug!("Queueing activity");
+        self.queued_activities.push_back(activity);
+        Ok(())
+    }
+
+    maybe_async! {
+        fn send_activity(&mut self, activity: serde_json::Value) -> Result<(), PresenceError> {
+            let args = json!({
+                "activity": activity,
+                "pid": 0u32
+            });
+            let response = maybe_await!(self.command("SET_ACTIVITY", args))?;
+            tracing::debug!("Response: {response}");
+            Ok(())
+        }
+    }
+
+    maybe_async! {
+        /// Send the queued activities the rate limiter now allows.
+        pub fn flush_activities(&mut self) -> Result<(), PresenceError> {
+            while !self.queued_activities.is_empty() && self.rate_limiter.try_acquire() {
+                let activity = self.queued_activities.pop_front().unwrap();
+                tracing::debug!("Sending queued activity");
+                maybe_await!(self.send_activity(activity))?;
+            }
+            Ok(())
+        }
+    }
+
+    /// How long until the next queued activity can be sent, if there is one.
+    pub fn next_flush(&mut self) -> Option<Duration> {
+        if self.queued_activities.is_empty() {
+            None
+        } else {
+            Some(self.rate_limiter.wait_time())
+        }
+    }
+
+    // The span fields end up as journal fields with the journald feature
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            category = %payload.category,
+            package = %payload.package,
+            pid = ?payload.pid,
+            state = ?payload.state,
+            merge_position = tracing::field::Empty,
+        )
+    )]
+    pub fn set_package(
+        &mut self,
+        payload: PackagePayload,
+        flags: EmergeFlags,
+    ) -> Result<(), PresenceError> {
+        let version = payload.full_version();
+        let pid = self.update_session(payload, flags, None);
+        // Recorded first, so that the event carries the position of this set
+        if let Some((position, total)) = self.active_sessions[&pid].queue_position() {
+            tracing::Span::current().record("merge_position", format!("{position}/{total}"));
+        }
+        tracing::info!(version = ?version, "Set activity");
+        self.save_state();
+        self.show_session(pid)
+    }
+
+    /// Show that the package failed to merge, until the failure delay runs out or another set
+    /// comes in.
+    #[tracing::instrument(skip_all, fields(category = %payload.category, package = %payload.package, pid = ?payload.pid))]
+    pub fn fail_package(
+        &mut self,
+        payload: PackagePayload,
+        flags: EmergeFlags,
+        reason: Option<String>,
+    ) -> Result<(), PresenceError> {
+        tracing::info!(?reason, "Package failed");
+        let failure = Failure {
+            reason,
+            at: Instant::now(),
+        };
+        let pid = self.update_session(payload, flags, Some(failure));
+        self.save_state();
+        self.show_session(pid)
+    }
+
+    /// Mark the most recently updated session as failed, for failures we learn about without
+    /// knowing the package (from emerge.log). Returns false if there was no session to fail, or
+    /// if it already failed (the die hook knows better than the log).
+    pub fn fail_latest_session(&mut self, reason: String) -> Result<bool, PresenceError> {
+        let Some((pid, session)) = self.latest_session() else {
+            return Ok(false);
+        };
+        if session.failure.is_some() {
+            return Ok(false);
+        }
+        let session = self
+            .active_sessions
+            .get_mut(&pid)
+            .ok_or(PresenceError::NoSession)?;
+        session.failure = Some(Failure {
+            reason: Some(reason),
+            at: Instant::now(),
+        });
+        session.failed += 1;
+        session.last_update = Instant::now();
+        self.show_session(pid)?;
+        Ok(true)
+    }
+
+    /// Record `payload` in the session of its pid, creating it if needed. Returns the pid.
+    fn update_session(
+        &mut self,
+        mut payload: PackagePayload,
+        flags: EmergeFlags,
+        failure: Option<Failure>,
+    ) -> u32 {
+        payload.sanitize();
+        // A total from the hook is authoritative, no need to read the mtimedb then
+        let merge = match payload.total {
+            Some(_) => MergeState::default(),
+            None => self.portage.merge_state(),
+        };
+        let count = merge.list_length;
+        if let Some(backup) = merge.backup_list_length {
+            tracing::debug!("An earlier merge of {backup} packages was interrupted");
+        }
+
+        let pid = payload.pid.unwrap_or(0);
+        let next_package = self
+            .active_sessions
+            .get(&pid)
+            .and_then(|session| session.current_package.as_ref())
+            .is_some_and(|current| current.package_key() != payload.package_key());
+        if next_package {
+            self.record_build(pid);
+        }
+        let session = self
+            .active_sessions
+            .entry(pid)
+            .or_insert_with(|| MergeSession::new(payload.pid));
+        if next_package {
+            session.phase_durations.clear();
+        }
+        if let (Some(secs), Some(state)) = (payload.elapsed_secs, &payload.state) {
+            match Duration::try_from_secs_f64(secs) {
+                Ok(duration) => {
+                    session.phase_durations.insert(state.clone(), duration);
+                }
+                Err(err) => tracing::debug!("Ignoring elapsed_secs of {secs} ({err})"),
+            }
+        }
+        if payload.total.is_none() {
+            session.total_packages = session.total_packages.max(count);
+            session.merge_len = count;
+        }
+        session.is_resume = merge.is_resume || flags.resume;
+        if session.is_resume && payload.state.is_none() {
+            payload.state = Some(PackageState::Resuming);
+        }
+        let new_package = !session
+            .package_start_times
+            .contains_key(&payload.package_key());
+        session
+            .package_start_times
+            .entry(payload.package_key())
+            .or_insert_with(SystemTime::now);
+        if let Some(total) = payload.total {
+            // Count the packages down from the total ourselves, like the merge list would
+            if session.total_packages != total {
+                session.total_packages = total;
+                session.merge_len = total;
+            } else if new_package {
+                session.merge_len = session.merge_len.saturating_sub(1).max(1);
+            }
+        }
+        session.current_package = Some(payload);
+        session.flags = flags;
+        session.last_update = Instant::now();
+        session.unset_at = None;
+        if failure.is_some() && session.failure.is_none() {
+            session.failed += 1;
+        }
+        session.failure = failure;
+        pid
+    }
+
+    /// Queue the end of the session of `pid`, or of every session if the hook didn't send a pid.
+    pub fn unset_package(&mut self, pid: Option<u32>) {
+        let now = Instant::now();
+        let pids = match pid {
+            Some(pid) => vec![pid],
+            None => self.active_sessions.keys().copied().collect(),
+        };
+        for pid in pids {
+            self.record_build(pid);
+            if let Some(session) = self.active_sessions.get_mut(&pid) {
+                session.unset_at = Some(now);
+            }
+        }
+        // The emerge installed (or removed) some
+        self.portage.forget_installed();
+        self.save_state();
+    }
+
+    /// Forget when the current package of `pid` started, adding its build to the history unless
+    /// it failed. Does nothing when an unset already did it.
+    fn record_build(&mut self, pid: u32) {
+        let Some(session) = self.active_sessions.get_mut(&pid) else {
+            return;
+        };
+        let Some(payload) = &session.current_package else {
+            return;
+        };
+        if session.unset_at.is_some() {
+            return;
+        }
+        let duration = session.build_duration();
+        if session
+            .package_start_times
+            .remove(&payload.package_key())
+            .is_none()
+            || session.failure.is_some()
+        {
+            return;
+        }
+        metrics::record_package_merged();
+        let (category, package) = (payload.category.clone(), payload.package.clone());
+        let binary = payload.binary_package;
+        session.record_merged(format!("{category}/{package}"), duration);
+        // Installing a binary package says nothing about how long building it takes
+        let history = self.history.as_mut().filter(|_| !binary);
+        if let Some(history) = history {
+            if let Err(err) = history.record(&category, &package, duration) {
+                tracing::warn!("Couldn't save build history ({err:?})");
+            }
+        }
+    }
+
+    /// Drop every session (and the sync) and clear the presence right away.
+    pub fn clear_sessions(&mut self) -> Result<(), PresenceError> {
+        self.active_sessions.clear();
+        self.sync = None;
+        self.save_state();
+        self.clear_presence()
+    }
+
+    /// Show that `repos` are being synced, until [`Client::sync_done`].
+    #[tracing::instrument(skip(self))]
+    pub fn set_sync(&mut self, repos: Vec<String>) -> Result<(), PresenceError> {
+        let started_at = match &self.sync {
+            // Repositories synced one by one shouldn't reset the elapsed time
+            Some(sync) => sync.started_at,
+            None => SystemTime::now(),
+        };
+        self.sync = Some(RepoSync { repos, started_at });
+        self.show_sync()
+    }
+
+    /// Record what the emerge of `pid` is doing, until its session ends.
+    #[tracing::instrument(skip(self))]
+    pub fn set_operation(
+        &mut self,
+        pid: Option<u32>,
+        operation: OperationType,
+    ) -> Result<(), PresenceError> {
+        let session = self
+            .active_sessions
+            .entry(pid.unwrap_or(0))
+            .or_insert_with(|| MergeSession::new(pid));
+        session.operation = Some(operation);
+        session.last_update = Instant::now();
+        session.unset_at = None;
+        self.save_state();
+        self.show_session(pid.unwrap_or(0))
+    }
+
+    /// Show the operation of a session that didn't get to its first package yet.
+    fn show_operation(
+        &mut self,
+        operation: OperationType,
+        started_at: Instant,
+    ) -> Result<(), PresenceError> {
+        let started_at = SystemTime::now() - started_at.elapsed();
+        let value = json!({
+            "type": self.activity_type as u8,
+            "details": operation.description(),
+            "timestamps": {
+                "start": started_at.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64,
+            },
+            "assets": {
+                "large_image": self.asset(operation.large_image().unwrap_or("gentoodrpgt")),
+            },
+        });
+        self.set_activity(value)
+    }
+
+    /// Show what the last emerge did, for the daemon to clear once [`Self::summary_left`] runs
+    /// out.
+    pub fn show_summary(&mut self, summary: &CompletionSummary) -> Result<(), PresenceError> {
+        let mut value = json!({
+            "type": self.activity_type as u8,
+            "details": summary.to_string(),
+            "assets": {
+                "large_image": self.asset("gentoodrpgt"),
+            },
+        });
+        if let Some(details) = summary.details() {
+            value
+                .as_object_mut()
+                .unwrap()
+                .insert("state".to_owned(), json!(truncate_field(details)));
+        }
+        self.set_activity(value)?;
+        self.summary_shown_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// How long the summary has left to be shown for `delay`, if it's shown.
+    pub fn summary_left(&self, delay: Duration) -> Option<Duration> {
+        self.summary_shown_at
+            .map(|at| delay.saturating_sub(at.elapsed()))
+    }
+
+    /// End the sync, showing the latest session again if there is one.
+    pub fn sync_done(&mut self) -> Result<(), PresenceError> {
+        let Some(sync) = self.sync.take() else {
+            tracing::debug!("Not syncing, ignoring sync-done");
+            return Ok(());
+        };
+        tracing::info!(
+            "Sync done after {:?}",
+            sync.started_at.elapsed().unwrap_or_default()
+        );
+        if self.has_sessions() {
+            self.show_latest_session()
+        } else {
+            self.clear_presence()
+        }
+    }
+
+    fn show_sync(&mut self) -> Result<(), PresenceError> {
+        let sync = self.sync.as_ref().ok_or(PresenceError::NoSession)?;
+        let details = match sync.repos.len() {
+            0 => "Syncing repositories".to_owned(),
+            _ => truncate_field(sync.repos.join(", ")),
+        };
+        let mut value = json!({
+            "type": self.activity_type as u8,
+            "details": details,
+            "timestamps": {
+                "start": sync.started_at.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64,
+            },
+            "assets": {
+                "large_image": self.asset("gentoodrpgt_sync"),
+            },
+        });
+        let state = match sync.repos.len() {
+            0 => None,
+            1 => Some("Syncing 1 repo".to_owned()),
+            count => Some(format!("Syncing {count} repos")),
+        };
+        if let Some(state) = state {
+            value
+                .as_object_mut()
+                .unwrap()
+                .insert("state".to_owned(), json!(state));
+        }
+        self.set_activity(value)
+    }
+
+    /// Write the sessions to the state file, if there is one.
+    pub fn save_state(&self) {
+        if let Some(state) = &self.state_file {
+            if let Err(err) = state.save(&self.active_sessions) {
+                tracing::warn!("Couldn't save sessions ({err:?})");
+            }
+        }
+    }
+
+    /// Drop sessions whose emerge process died or that haven't been set again for `delay` after
+    /// an unset. Failed sessions are kept for `failure_delay` instead, whether emerge exited or
+    /// not. Returns the removed sessions.
+    pub fn expire_sessions(
+        &mut self,
+        delay: Duration,
+        failure_delay: Duration,
+    ) -> Vec<MergeSession> {
+        self.expire_sessions_at(Instant::now(), delay, failure_delay)
+    }
+
+    /// [`Self::expire_sessions`] as of `now`.
+    fn expire_sessions_at(
+        &mut self,
+        now: Instant,
+        delay: Duration,
+        failure_delay: Duration,
+    ) -> Vec<MergeSession> {
+        let expired: Vec<u32> = self
+            .active_sessions
+            .iter()
+            .filter(|&(&pid, session)| match &session.failure {
+                Some(failure) => now.saturating_duration_since(failure.at) > failure_delay,
+                None => {
+                    let expired = session
+                        .unset_at
+                        .is_some_and(|ts| now.saturating_duration_since(ts) > delay);
+                    expired || !pid_alive(pid)
+                }
+            })
+            .map(|(&pid, _)| pid)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|pid| {
+                let session = self.active_sessions.remove(&pid)?;
+                tracing::info!(
+                    "Session {pid} ended after {:?}",
+                    session.started_at.elapsed()
+                );
+                Some(session)
+            })
+            .collect()
+    }
+
+    pub fn has_sessions(&self) -> bool {
+        !self.active_sessions.is_empty()
+    }
+
+    fn latest_session(&self) -> Option<(u32, &MergeSession)> {
+        self.active_sessions
+            .iter()
+            .max_by_key(|(_, session)| session.last_update)
+            .map(|(&pid, session)| (pid, session))
+    }
+
+    /// Show the most recently updated session.
+    pub fn show_latest_session(&mut self) -> Result<(), PresenceError> {
+        let (pid, _) = self.latest_session().ok_or(PresenceError::NoSession)?;
+        self.show_session(pid)
+    }
+
+    /// Show the latest session (or the sync) again, if there is one. For after a connection: the sessions can
+    /// come from the state file, or have gone on while discord was away.
+    pub fn refresh_presence(&mut self) -> Result<(), PresenceError> {
+        if self.has_sessions() {
+            self.show_latest_session()?;
+        } else if self.sync.is_some() {
+            self.show_sync()?;
+        }
+        Ok(())
+    }
+
+    /// Status of the session currently shown, if any.
+    pub fn query(&self) -> Option<QueryReply<'_>> {
+        Some(QueryReply {
+            status: self.latest_session()?.1.status()?,
+            summary: self.to_string(),
+            discord_user: self
+                .ready
+                .as_ref()
+                .and_then(|ready| ready.user.as_ref())
+                .map(ToString::to_string),
+            portage_version: portage::portage_version(),
+        })
+    }
+
+    /// Every session that has a package to show, by pid.
+    pub fn list_sessions(&self) -> Vec<SessionListEntry<'_>> {
+        let mut sessions: Vec<_> = self
+            .active_sessions
+            .values()
+            .filter_map(|session| {
+                let status = session.status()?;
+                Some(SessionListEntry {
+                    pid: session.emerge_pid,
+                    category: status.category,
+                    package: status.package,
+                    version: status.version,
+                    state: status.state,
+                    queue_position: status.queue_position,
+                    queue_total: status.queue_total,
+                    operation: status.operation,
+                    started_at_secs: status.started_at,
+                })
+            })
+            .collect();
+        sessions.sort_by_key(|entry| entry.pid);
+        sessions
+    }
+
+    /// Snapshot of the whole client state, for debugging.
+    pub fn dump(&self) -> StateDump<'_> {
+        StateDump {
+            summary: self.to_string(),
+            connected: self.is_connected(),
+            discord_path: self.connection.path(),
+            ready: self.ready.as_ref(),
+            last_command: self.last_command.map(unix_secs),
+            backoff: BackoffDump {
+                consecutive_failures: self.connection.failed_attempts(),
+                next_retry_in_secs: self.connection.retry_in().as_secs_f64(),
+            },
+            active_sessions: self
+                .active_sessions
+                .values()
+                .map(|session| SessionDump {
+                    emerge_pid: session.emerge_pid,
+                    merge_len: session.merge_len,
+                    total_packages: session.total_packages,
+                    unset_pending: session.unset_at.is_some(),
+                    failed: session.failure.is_some(),
+                    resumed: session.is_resume,
+                    status: session.status(),
+                })
+                .collect(),
+            syncing: self.sync.as_ref().map(|sync| sync.repos.as_slice()),
+        }
+    }
+
+    /// The state line: the phase, followed by the use flags and emerge options if enabled, with
+    /// `installed_count` if it's to be shown.
+    fn state_text(&self, session: &MergeSession, installed_count: Option<u32>) -> Option<String> {
+        let payload = session.current_package.as_ref()?;
+        let summary = self
+            .show_emerge_flags
+            .then(|| session.flags.summary())
+            .flatten();
+        let Some(state) = &payload.state else {
+            return summary;
+        };
+
+        let mut text = match (state, session.queue_position()) {
+            _ if payload.installing_binary() => portage::BINARY_STATE.to_owned(),
+            (PackageState::Resuming, Some((position, total))) => {
+                format!("resuming: {position}/{total}")
+            }
+            (PackageState::Fetching, _) => match payload.distfile_size {
+                Some(size) => format!("fetching ({})", portage::format_size(size)),
+                None => state.to_string(),
+            },
+            (PackageState::Testing, _) => match payload.test_count {
+                Some(count) => format!("{state} ({} run)", format_count(count)),
+                None => state.to_string(),
+            },
+            _ => state.to_string(),
+        };
+        if self.show_elapsed {
+            text += &format!(" ({})", portage::format_duration(session.elapsed()));
+        }
+        // Discord has no room for it in the party, which is the position and length of the merge
+        let installed_count = installed_count.filter(|_| session.queue_position().is_some());
+        if let Some(count) = installed_count {
+            text += &format!(" (of {} installed)", format_count(count));
+        }
+        let suffix = summary.map(|s| format!(" — {s}")).unwrap_or_default();
+        let flags = payload.use_flags.as_deref().unwrap_or_default();
+        if !flags.is_empty() {
+            let room = MAX_FIELD_LEN.saturating_sub(text.chars().count() + suffix.chars().count());
+            text += &fit_use_flags(&use_flag_changes(flags, self.use_baseline.as_ref()), room);
+        }
+        text += &suffix;
+        Some(text)
+    }
+
+    /// The asset key to send for `key`, as remapped by `assets_map`.
+    fn asset<'a>(&'a self, key: &'a str) -> &'a str {
+        self.assets_map.get(key).map_or(key, String::as_str)
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn show_session(&mut self, pid: u32) -> Result<(), PresenceError> {
+        self.activity_sequence = self.activity_sequence.wrapping_add(1);
+        // Asked before the session is borrowed, the answer is cached anyway
+        let installed_count = self
+            .show_installed_count
+            .then(|| self.portage.installed_count())
+            .flatten();
+        let session = self
+            .active_sessions
+            .get(&pid)
+            .ok_or(PresenceError::NoSession)?;
+        let Some(payload) = session.current_package.as_ref() else {
+            let operation = session.operation.ok_or(PresenceError::NoSession)?;
+            return self.show_operation(operation, session.started_at);
+        };
+        // Discord ignores an activity identical to the one it shows, the party id changing makes
+        // every update go through (a party without size isn't shown)
+        let mut party = json!({ "id": format!("emerge-{}", self.activity_sequence) });
+        if let Some((pos, total)) = session.queue_position() {
+            party["size"] = json!([pos, total]);
+        }
+
+        let PackagePayload {
+            category,
+            package,
+            homepage,
+            ..
+        } = payload;
+        let overlay = payload.overlay();
+        let rebuild = session.operation == Some(OperationType::PreservedRebuild);
+        let context = self.templates.as_ref().map(|templates| {
+            let queue = session.queue_position();
+            let flags = payload.use_flags.as_deref().unwrap_or_default();
+            let context = TemplateContext {
+                category,
+                package,
+                version: payload.full_version(),
+                slot: payload.slot(),
+                state: payload.state_text(),
+                use_flags: use_flag_changes(flags, self.use_baseline.as_ref()).join(" "),
+                repo: overlay,
+                queue_pos: queue.map(|(pos, _)| pos),
+                queue_total: queue.map(|(_, total)| total),
+            };
+            (templates, context)
+        });
+        let details = context
+            .as_ref()
+            .and_then(|(templates, context)| templates.details(context))
+            .map(truncate_field)
+            .unwrap_or_else(|| {
+                // The packages of a preserved rebuild aren't what's interesting, they go in the
+                // state instead
+                if rebuild {
+                    return OperationType::PreservedRebuild.description().to_owned();
+                }
+                let mut name = format!("{category}/{package}");
+                let mut rest = String::new();
+                if let Some(version) = payload.full_version() {
+                    rest += &format!(" {version}");
+                }
+                if let Some(overlay) = overlay {
+                    rest += &format!(" [{overlay}]");
+                }
+                // The slot is the first thing to go when it doesn't fit
+                if let Some(slot) = payload.slot() {
+                    let len = name.chars().count() + rest.chars().count() + slot.chars().count();
+                    if len < MAX_FIELD_LEN {
+                        name += &format!(":{slot}");
+                    }
+                }
+                name + &rest
+            });
+        let (large_image, state) = match &session.failure {
+            Some(Failure {
+                reason: Some(reason),
+                ..
+            }) => ("gentoodrpgt_fail", Some(format!("failed: {reason}"))),
+            Some(Failure { reason: None, .. }) => ("gentoodrpgt_fail", Some("failed".to_owned())),
+            None => {
+                let state = context
+                    .as_ref()
+                    .and_then(|(templates, context)| templates.state(context))
+                    .map(truncate_field);
+                // Downloading looks different from building, whatever the category
+                let image = match payload.state {
+                    Some(PackageState::Fetching) => "gentoodrpgt_fetch",
+                    _ => session
+                        .operation
+                        .and_then(OperationType::large_image)
+                        .or_else(|| self.category_images.image(category))
+                        .unwrap_or("gentoodrpgt"),
+                };
+                let state = state.or_else(|| {
+                    let text = self.state_text(session, installed_count);
+                    if !rebuild {
+                        return text;
+                    }
+                    Some(truncate_field(match text {
+                        Some(text) => format!("{category}/{package}: {text}"),
+                        None => format!("{category}/{package}"),
+                    }))
+                });
+                (image, state)
+            }
+        };
+
+        let started_at = session.package_started_at();
+        let mut timestamps = json!({
+            "start": started_at.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64,
+        });
+        let estimate = self
+            .history
+            .as_ref()
+            .and_then(|history| history.estimate(category, package));
+        let estimate = estimate.filter(|_| session.failure.is_none() && !payload.binary_package);
+        if let Some(estimate) = estimate {
+            let end = (started_at + estimate)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap();
+            timestamps
+                .as_object_mut()
+                .unwrap()
+                .insert("end".to_owned(), json!(end.as_millis() as u64));
+        }
+
+        // `instance` says whether the activity is an "instanced game session": one specific
+        // session (a match, a server) others could be in, rather than just what the user is
+        // doing. Discord uses it alongside the party and secrets of invites, so with neither it
+        // mostly just tags the activity as this machine's build session, hence off by default.
+        let mut value = json!({
+            "type": self.activity_type as u8,
+            "instance": self.activity_instance,
+            "details": details,
+            "timestamps": timestamps,
+            "assets": {
+                "large_image": self.asset(large_image)
+            },
+        });
+
+        // The image can be the one of the category, so the tooltip tells which package it is
+        let large_text = match overlay {
+            Some(overlay) => format!("{category}/{package} from the {overlay} overlay"),
+            None => format!("{category}/{package}"),
+        };
+        value["assets"]
+            .as_object_mut()
+            .unwrap()
+            .insert("large_text".to_owned(), json!(truncate_field(large_text)));
+
+        let build_system = payload
+            .build_system()
+            .filter(|_| self.show_build_system)
+            .and_then(|build_system| Some((build_system.asset_key()?, build_system.name())));
+        let assets = value["assets"].as_object_mut().unwrap();
+        match (&payload.state, build_system) {
+            // Nothing is built, the build system doesn't matter
+            (Some(_), _) if payload.installing_binary() => {
+                assets.insert("small_image".to_owned(), json!(self.asset("phase_binary")));
+                assets.insert("small_text".to_owned(), json!(portage::BINARY_STATE));
+            }
+            (phase, Some((key, name))) => {
+                assets.insert("small_image".to_owned(), json!(self.asset(key)));
+                let text = match phase {
+                    Some(phase) => format!("{phase} ({name})"),
+                    None => name.to_owned(),
+                };
+                assets.insert("small_text".to_owned(), json!(text));
+            }
+            (Some(phase), None) => {
+                if let Some(key) = phase.asset_key() {
+                    assets.insert("small_image".to_owned(), json!(self.asset(key)));
+                }
+                assets.insert("small_text".to_owned(), json!(phase.to_string()));
+            }
+            (None, None) => {}
+        }
+
+        if let Some(state) = state {
+            value
+                .as_object_mut()
+                .unwrap()
+                .insert("state".to_owned(), json!(state));
+        }
+        value
+            .as_object_mut()
+            .unwrap()
+            .insert("party".to_owned(), party);
+        // Discord allows at most two buttons, which is exactly what we have.
+        let mut buttons = Vec::new();
+        // packages.gentoo.org only knows about the main tree
+        if self.show_package_button && overlay.is_none() {
+            buttons.push(json!({
+                "label": "Gentoo Package",
+                "url": format!("https://packages.gentoo.org/packages/{category}/{package}"),
+            }));
+        }
+        // $HOMEPAGE can hold several urls, the first one is the main one.
+        let homepage = homepage
+            .as_deref()
+            .and_then(|h| h.split_whitespace().next());
+        if let Some(homepage) = homepage.filter(|_| self.show_homepage_button) {
+            buttons.push(json!({
+                "label": "Homepage",
+                "url": homepage,
+            }));
+        }
+        if !buttons.is_empty() {
+            value
+                .as_object_mut()
+                .unwrap()
+                .insert("buttons".to_owned(), json!(buttons));
+        }
+
+        self.set_activity(value)
+    }
+}
+
+/// Run `f`, and run it again after reconnecting when it fails because the connection to discord
+/// broke (discord restarting for example), up to `max_attempts` times in total. Nothing waits
+/// here: the first reconnection is immediate, after a failed one the backoff of the connection
+/// says when the next is due, which the main loop makes (showing the sessions again) once it is.
+#[cfg(not(feature = "tokio"))]
+pub fn retry_with_backoff<T>(
+    client: &mut Client,
+    max_attempts: u32,
+    mut f: impl FnMut(&mut Client) -> Result<T, PresenceError>,
+) -> Result<T, PresenceError> {
+    let mut attempt = 1;
+    loop {
+        let err = match f(client) {
+            Err(err @ (PresenceError::BrokenPipe | PresenceError::Disconnected)) => err,
+            res => return res,
+        };
+        loop {
+            if attempt >= max_attempts {
+                tracing::error!("Giving up after {attempt} attempts ({err})");
+                return Err(err);
+            }
+            if !client.should_retry() {
+                tracing::info!("{err}, reconnecting once the backoff allows it");
+                return Err(err);
+            }
+            attempt += 1;
+            tracing::info!("{err}, reconnecting (attempt {attempt}/{max_attempts})");
+            match client.reconnect() {
+                Ok(()) => break,
+                Err(err) => tracing::debug!("Reconnection failed ({err})"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        assert_eq!(format_count(0), "0");
+        assert_eq!(format_count(847), "847");
+        assert_eq!(format_count(1847), "1,847");
+        assert_eq!(format_count(1_234_567), "1,234,567");
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    #[test]
+    fn retry_until_max_attempts() {
+        let mut client = client();
+        client.set_dry_run(true);
+        let mut calls = 0;
+        let res: Result<(), _> = retry_with_backoff(&mut client, 2, |_| {
+            calls += 1;
+            Err(PresenceError::BrokenPipe)
+        });
+        assert!(matches!(res, Err(PresenceError::BrokenPipe)));
+        assert_eq!(calls, 2);
+
+        // Not a connection problem, retrying wouldn't help
+        calls = 0;
+        let res: Result<(), _> = retry_with_backoff(&mut client, 3, |_| {
+            calls += 1;
+            Err(PresenceError::NoSession)
+        });
+        assert!(matches!(res, Err(PresenceError::NoSession)));
+        assert_eq!(calls, 1);
+
+        // A failed reconnection leaves the next attempt to the main loop instead of waiting
+        let dir = tempfile::tempdir().unwrap();
+        let mut absent = self::client();
+        absent.apply_config(&Config {
+            ipc_socket_path: Some(dir.path().join("discord-ipc-0")),
+            ..Config::default()
+        });
+        calls = 0;
+        let res: Result<(), _> = retry_with_backoff(&mut absent, 5, |_| {
+            calls += 1;
+            Err(PresenceError::Disconnected)
+        });
+        assert!(matches!(res, Err(PresenceError::Disconnected)));
+        assert_eq!(calls, 1);
+        assert!(!absent.should_retry());
+    }
+
+    /// A client connected to the other end of the returned socket.
+    #[cfg(not(feature = "tokio"))]
+    fn connected() -> (Client, UnixStream) {
+        let (stream, discord) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut client = client();
+        client.connection = ConnectionState::Connected {
+            stream,
+            path: PathBuf::new(),
+        };
+        (client, discord)
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    #[test]
+    fn recv_whole_frames() {
+        let (mut client, mut discord) = connected();
+        assert!(client.recv().unwrap().is_empty());
+        let mut bytes = encode_frame(IPC_FRAME, b"{}");
+        bytes.extend(encode_frame(IPC_PONG, b"[]"));
+        discord.write_all(&bytes).unwrap();
+        assert_eq!(
+            client.recv().unwrap(),
+            [(IPC_FRAME, "{}".to_owned()), (IPC_PONG, "[]".to_owned())]
+        );
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    #[test]
+    fn recv_keeps_split_frames() {
+        let (mut client, mut discord) = connected();
+        let bytes = encode_frame(IPC_FRAME, b"{\"a\":1}");
+        for part in [&bytes[..1], &bytes[1..6], &bytes[6..10]] {
+            discord.write_all(part).unwrap();
+            assert!(client.recv().unwrap().is_empty());
+        }
+        discord.write_all(&bytes[10..]).unwrap();
+        assert_eq!(
+            client.recv().unwrap(),
+            [(IPC_FRAME, "{\"a\":1}".to_owned())]
+        );
+    }
+
+    fn client() -> Client {
+        ClientBuilder::default().client_id("0").build().unwrap()
+    }
+
+    fn package(name: &str) -> PackagePayload {
+        serde_json::from_value(json!({ "category": "app-misc", "package": name })).unwrap()
+    }
+
+    #[test]
+    fn display() {
+        let mut client = client();
+        assert_eq!(
+            client.to_string(),
+            "Client { connected: no, session: none }"
+        );
+        client.set_dry_run(true);
+        let mut payload = package("openssl");
+        payload.version = Some("3.0.1".to_owned());
+        payload.state = Some(PackageState::Compiling);
+        client.set_package(payload, EmergeFlags::default()).unwrap();
+        assert_eq!(
+            client.to_string(),
+            "Client { connected: yes (dry run), session: app-misc/openssl-3.0.1 [compiling] }"
+        );
+    }
+
+    #[test]
+    fn hook_timed_phases() {
+        let mut client = client();
+        client.set_dry_run(true);
+        for (state, secs) in [
+            (PackageState::Compiling, 90.5),
+            (PackageState::Installing, 9.5),
+        ] {
+            let mut payload = package("gcc");
+            payload.state = Some(state);
+            payload.elapsed_secs = Some(secs);
+            client.set_package(payload, EmergeFlags::default()).unwrap();
+        }
+        client.unset_package(None);
+        let summary = client.active_sessions[&0].summary();
+        assert_eq!(
+            summary.longest_build,
+            Some(("app-misc/gcc".to_owned(), Duration::from_secs(100)))
+        );
+    }
+
+    #[test]
+    fn binary_package() {
+        let mut client = client();
+        client.set_dry_run(true);
+        let mut payload = package("firefox-bin");
+        payload.binary_package = true;
+        for (state, text) in [
+            (PackageState::Fetching, "fetching"),
+            (PackageState::Preparing, "installing from binary"),
+            (PackageState::Installing, "installing from binary"),
+        ] {
+            payload.state = Some(state);
+            client
+                .set_package(payload.clone(), EmergeFlags::default())
+                .unwrap();
+            let session = &client.active_sessions[&0];
+            assert_eq!(client.state_text(session, None).as_deref(), Some(text));
+        }
+    }
+
+    #[test]
+    fn set_after_unset_restarts_the_delay() {
+        let delay = Duration::from_secs(30);
+        let mut client = client();
+        client.set_dry_run(true);
+
+        client
+            .set_package(package("first"), EmergeFlags::default())
+            .unwrap();
+        client.unset_package(None);
+        let first_unset = client.active_sessions[&0].unset_at.unwrap();
+        client
+            .set_package(package("second"), EmergeFlags::default())
+            .unwrap();
+        // The delay of the first unset ran out, but the set came in since
+        let later = first_unset + delay * 2;
+        assert!(client.expire_sessions_at(later, delay, delay).is_empty());
+        assert_eq!(client.session_count(), 1);
+
+        // Only an unset starts it again
+        client.unset_package(None);
+        let unset = client.active_sessions[&0].unset_at.unwrap();
+        let within = unset + delay / 2;
+        assert!(client.expire_sessions_at(within, delay, delay).is_empty());
+        let after = unset + delay * 2;
+        assert_eq!(client.expire_sessions_at(after, delay, delay).len(), 1);
+        assert!(!client.has_sessions());
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    #[test]
+    fn recv_fails_on_hang_up() {
+        let (mut client, mut discord) = connected();
+        let mut bytes = encode_frame(IPC_CLOSE, b"{}");
+        bytes.extend([1, 2]);
+        discord.write_all(&bytes).unwrap();
+        drop(discord);
+        // What came before is still handed out
+        assert_eq!(client.recv().unwrap(), [(IPC_CLOSE, "{}".to_owned())]);
+        assert!(matches!(client.recv(), Err(PresenceError::BrokenPipe)));
+        assert!(!client.is_connected());
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    #[test]
+    fn recv_rejects_huge_frames() {
+        let (mut client, mut discord) = connected();
+        let mut bytes = IPC_FRAME.to_le_bytes().to_vec();
+        bytes.extend((MAX_FRAME_LEN as u32 + 1).to_le_bytes());
+        discord.write_all(&bytes).unwrap();
+        assert!(matches!(client.recv(), Err(PresenceError::IpcFrame(_))));
+        assert!(!client.is_connected());
+    }
+}
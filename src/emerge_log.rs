@@ -0,0 +1,70 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    path::PathBuf,
+    sync::{mpsc::Sender, Arc},
+    thread,
+    time::Duration,
+};
+
+use crate::{wake, InternalEvent, Waker};
+
+/// How often we look for new lines once we reach the end of the log.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Extract the reason from an emerge.log line reporting a failure, lines look like
+/// `1665000000:  *** exiting unsuccessfully with status '1'.`.
+fn failure_reason(line: &str) -> Option<String> {
+    let (_, message) = line.split_once(':')?;
+    let message = message.trim();
+    if message.starts_with("!!! Failed") || message.starts_with("*** exiting unsuccessfully") {
+        Some(
+            message
+                .trim_start_matches(['!', '*', ' '])
+                .trim_end_matches('.')
+                .to_owned(),
+        )
+    } else {
+        None
+    }
+}
+
+/// Follow `path` (usually /var/log/emerge.log) in a background thread, sending a failure event
+/// whenever emerge logs one. This catches failures even when the hooks didn't send a die.
+pub fn watch(path: PathBuf, events: Sender<InternalEvent>, waker: Arc<Waker>) {
+    thread::Builder::new()
+        .name("emerge-log".to_owned())
+        .spawn(move || {
+            if let Err(err) = follow(&path, &events, &waker) {
+                tracing::warn!("Stopped watching {} ({err:?})", path.display());
+            }
+        })
+        .expect("Couldn't spawn emerge.log watcher");
+}
+
+fn follow(path: &PathBuf, events: &Sender<InternalEvent>, waker: &Waker) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    // Only new lines are interesting
+    let mut pos = reader.seek(SeekFrom::End(0))?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let len = reader.read_line(&mut line)?;
+        if len == 0 || !line.ends_with('\n') {
+            // Wait for the rest of the line, and start over if the log got rotated/truncated
+            reader.seek(SeekFrom::Start(pos))?;
+            thread::sleep(POLL_INTERVAL);
+            if std::fs::metadata(path)?.len() < pos {
+                reader = BufReader::new(File::open(path)?);
+                pos = 0;
+            }
+            continue;
+        }
+        pos += len as u64;
+        if let Some(reason) = failure_reason(&line) {
+            tracing::info!("emerge.log reported a failure: {reason}");
+            events.send(InternalEvent::Failure { reason })?;
+            wake(waker)?;
+        }
+    }
+}
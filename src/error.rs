@@ -0,0 +1,161 @@
+use std::{
+    collections::TryReserveError,
+    io::{self, ErrorKind},
+    os::unix::io::RawFd,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use crate::discord;
+
+/// A problem found by [`Config::validate`](crate::config::Config::validate), in one of
+/// [`CONFIG_SECTIONS`](crate::config::CONFIG_SECTIONS).
+#[derive(Error, Debug)]
+#[error("{section}: {message}")]
+pub struct ConfigError {
+    pub section: &'static str,
+    pub message: String,
+}
+
+/// Errors of the discord client and portage queries.
+#[derive(Error, Debug)]
+pub enum PresenceError {
+    #[error("Not connected to discord")]
+    Disconnected,
+    #[error("No client id given")]
+    NoClientId,
+    #[error("Couldn't find the discord ipc socket")]
+    NoIpcSocket,
+    #[error("Not retrying to connect for another {0:?}")]
+    RetryLater(Duration),
+    #[error("Broken pipe")]
+    BrokenPipe,
+    #[error("No active session")]
+    NoSession,
+    #[error("Portage query failed: {0}")]
+    PortageQuery(String),
+    #[error("Invalid ipc frame: {0}")]
+    IpcFrame(String),
+    #[error("No response from discord after {0:?}")]
+    Timeout(Duration),
+    #[error("Discord error {code}: {message}")]
+    Discord { code: i64, message: String },
+    /// Discord sent a close frame, see [`discord::worth_reconnecting`] for the codes.
+    #[error("Discord closed the connection ({code}: {message})")]
+    Closed { code: u32, message: String },
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl PresenceError {
+    /// Errors that go away once we reconnect, as opposed to ones that mean something is wrong
+    /// with us.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Disconnected
+            | Self::NoIpcSocket
+            | Self::RetryLater(_)
+            | Self::BrokenPipe
+            | Self::Timeout(_) => true,
+            // Discord going away, or not being there yet
+            Self::Io(err) => matches!(
+                err.kind(),
+                ErrorKind::BrokenPipe
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::NotConnected
+                    | ErrorKind::NotFound
+                    | ErrorKind::UnexpectedEof
+                    | ErrorKind::TimedOut
+                    | ErrorKind::WouldBlock
+                    | ErrorKind::Interrupted
+            ),
+            Self::Closed { code, .. } => discord::worth_reconnecting(*code),
+            _ => false,
+        }
+    }
+}
+
+/// Errors of the commands: the ones that don't parse or authenticate, and the ones that fail.
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error("Unknown opcode {0}")]
+    UnknownOpcode(u32),
+    #[error("Unknown command {0:?}")]
+    UnknownCommand(String),
+    #[error("Batch isn't an array of objects ({0})")]
+    InvalidBatch(serde_json::Error),
+    #[error("Batched command without a cmd")]
+    MissingCmd,
+    #[error("Command not terminated after {0} bytes, dropping buffered data")]
+    Unterminated(usize),
+    #[error("Frame of {0} bytes is too big, dropping buffered data")]
+    TooBig(usize),
+    #[error("Legacy commands can't be authenticated, send frames")]
+    LegacyWithSecret,
+    #[error("Command isn't authenticated ({0})")]
+    NotAuthenticated(serde_json::Error),
+    #[error("Invalid auth")]
+    InvalidAuth,
+    #[error("Wrong auth, the secrets of the hooks and daemon probably differ")]
+    WrongAuth,
+    #[error(transparent)]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Presence(#[from] PresenceError),
+}
+
+/// Errors of the fifo and socket the commands come through.
+#[derive(Error, Debug)]
+pub enum TransportError {
+    #[error("Couldn't {action} {} ({err})", path.display())]
+    Path {
+        action: &'static str,
+        path: PathBuf,
+        err: io::Error,
+    },
+    #[error("Invalid fd {fd} ({err})")]
+    InvalidFd { fd: RawFd, err: nix::Error },
+    #[error("fd {0} is neither a socket nor a fifo")]
+    NotSocketOrFifo(RawFd),
+    #[error(transparent)]
+    Reserve(#[from] TryReserveError),
+    #[error(transparent)]
+    Sys(#[from] nix::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl TransportError {
+    pub(crate) fn at(action: &'static str, path: &Path, err: io::Error) -> Self {
+        Self::Path {
+            action,
+            path: path.to_owned(),
+            err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transient() {
+        assert!(PresenceError::Disconnected.is_transient());
+        assert!(PresenceError::Io(ErrorKind::ConnectionReset.into()).is_transient());
+        assert!(PresenceError::Io(ErrorKind::BrokenPipe.into()).is_transient());
+        // Not something reconnecting fixes
+        assert!(!PresenceError::Io(ErrorKind::PermissionDenied.into()).is_transient());
+        assert!(!PresenceError::Io(ErrorKind::InvalidData.into()).is_transient());
+        assert!(!PresenceError::NoSession.is_transient());
+        assert!(!PresenceError::NoClientId.is_transient());
+    }
+}
@@ -0,0 +1,77 @@
+//! Pings discord every [`PING_INTERVAL`] (or the
+//! [`heartbeat_interval`](crate::discord::ClientBuilder::heartbeat_interval) of the client). A
+//! dead connection (discord restarted, its socket removed) is otherwise only noticed on the next
+//! write, which can be hours into a long build.
+
+use std::time::{Duration, Instant};
+
+use crate::discord::Client;
+
+/// How long the connection can go without a ping.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How long discord has to answer a ping before the connection is considered dead.
+pub const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Heartbeat {
+    last_ping: Instant,
+    /// When the ping that wasn't answered yet was sent.
+    waiting_since: Option<Instant>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self {
+            last_ping: Instant::now(),
+            waiting_since: None,
+        }
+    }
+
+    maybe_async! {
+        /// Ping discord if it's time to, and reconnect if the last ping went unanswered for too
+        /// long. Once per iteration of the main loop, after reading what discord sent.
+        pub fn tick(&mut self, client: &mut Client) {
+            if !client.is_connected() {
+                self.waiting_since = None;
+                return;
+            }
+            if let Some(sent) = self.waiting_since {
+                if client.last_pong().is_some_and(|pong| pong >= sent) {
+                    tracing::trace!("Discord answered the ping");
+                    self.waiting_since = None;
+                } else if sent.elapsed() > PONG_TIMEOUT {
+                    tracing::warn!("No pong from discord in {PONG_TIMEOUT:?}, reconnecting");
+                    self.waiting_since = None;
+                    let res = maybe_await!(client.reconnect());
+                    match res.and_then(|()| client.refresh_presence()) {
+                        Ok(()) => tracing::info!("Client reconnected"),
+                        Err(err) => tracing::info!("Reconnection failed ({err})"),
+                    }
+                }
+                return;
+            }
+            if self.last_ping.elapsed() >= client.ping_interval() {
+                self.last_ping = Instant::now();
+                match maybe_await!(client.ping()) {
+                    Ok(()) => self.waiting_since = Some(self.last_ping),
+                    Err(err) => tracing::debug!("Couldn't ping discord ({err})"),
+                }
+            }
+        }
+    }
+
+    /// Time until [`Self::tick`] has something to do.
+    pub fn next_tick(&self, client: &Client) -> Duration {
+        match self.waiting_since {
+            Some(sent) => PONG_TIMEOUT.saturating_sub(sent.elapsed()),
+            None => client
+                .ping_interval()
+                .saturating_sub(self.last_ping.elapsed()),
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,63 @@
+use std::{collections::HashMap, env, path::PathBuf, time::Duration};
+
+use anyhow::Result;
+
+use crate::write_atomic;
+
+/// Builds shorter than this are most likely fetches or binary packages and would skew the
+/// estimates.
+const MIN_DURATION: Duration = Duration::from_secs(5);
+/// Weight of the newest build in the moving average.
+const SMOOTHING: f64 = 0.5;
+
+/// `$XDG_DATA_HOME/emerge-presence/history.json`, or `~/.local/share/emerge-presence/history.json`
+/// if XDG_DATA_HOME isn't set.
+pub fn default_history_path() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .map(|dir| dir.join("emerge-presence").join("history.json"))
+}
+
+/// How long packages took to build, keyed by `category/package`, in seconds.
+pub struct BuildHistoryDb {
+    path: PathBuf,
+    durations: HashMap<String, f64>,
+}
+
+impl BuildHistoryDb {
+    /// Read the history at `path`, a missing file is an empty history.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let durations = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self { path, durations })
+    }
+
+    /// How long the package is expected to take, if it was built before.
+    pub fn estimate(&self, category: &str, package: &str) -> Option<Duration> {
+        self.durations
+            .get(&format!("{category}/{package}"))
+            .map(|&secs| Duration::from_secs_f64(secs))
+    }
+
+    /// Record a build of the package and write the history.
+    pub fn record(&mut self, category: &str, package: &str, duration: Duration) -> Result<()> {
+        if duration < MIN_DURATION {
+            return Ok(());
+        }
+        let secs = duration.as_secs_f64();
+        self.durations
+            .entry(format!("{category}/{package}"))
+            .and_modify(|avg| *avg = SMOOTHING * secs + (1.0 - SMOOTHING) * *avg)
+            .or_insert(secs);
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        write_atomic(&self.path, &serde_json::to_vec(&self.durations)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+//! Show what portage is merging as a discord rich presence.
+//!
+//! The `emerge-presence` binary is a daemon around this crate: the bashrc hooks send
+//! [`command::Command`]s over a socket (or the legacy fifo), which update the sessions of a
+//! [`discord::Client`] that sends the activity to discord.
+
+/// A `fn` that talks to discord, an `async fn` with the tokio feature so that it waits on the
+/// runtime instead of blocking its thread. Such functions call each other through
+/// [`maybe_await!`].
+#[cfg(feature = "tokio")]
+#[macro_export]
+macro_rules! maybe_async {
+    ($(#[$attr:meta])* $vis:vis fn $($rest:tt)*) => {
+        $(#[$attr])* $vis async fn $($rest)*
+    };
+}
+/// A `fn` that talks to discord, an `async fn` with the tokio feature so that it waits on the
+/// runtime instead of blocking its thread. Such functions call each other through
+/// [`maybe_await!`].
+#[cfg(not(feature = "tokio"))]
+#[macro_export]
+macro_rules! maybe_async {
+    ($($item:tt)*) => {
+        $($item)*
+    };
+}
+
+/// The call of a [`maybe_async!`] function, awaited with the tokio feature.
+#[cfg(feature = "tokio")]
+#[macro_export]
+macro_rules! maybe_await {
+    ($call:expr) => {
+        $call.await
+    };
+}
+/// The call of a [`maybe_async!`] function, awaited with the tokio feature.
+#[cfg(not(feature = "tokio"))]
+#[macro_export]
+macro_rules! maybe_await {
+    ($call:expr) => {
+        $call
+    };
+}
+
+pub mod auth;
+pub mod backpressure;
+/// Generated by build.rs.
+pub mod build_info {
+    include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+}
+pub mod command;
+pub mod config;
+pub mod discord;
+pub mod emerge_log;
+pub mod error;
+pub mod heartbeat;
+pub mod history;
+pub mod metrics;
+pub mod mtimedb;
+pub mod notify;
+pub mod portage;
+#[cfg(feature = "portage-native")]
+mod portage_native;
+pub mod profile;
+pub mod session;
+pub mod simulate;
+pub mod state;
+pub mod systemd;
+pub mod template;
+pub mod transport;
+pub mod watchdog;
+pub mod webhook;
+
+use std::{path::Path, time::SystemTime};
+
+use command::Command;
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Events coming from background threads, which wake the main loop up through the [`Waker`].
+pub enum InternalEvent {
+    /// emerge.log reported a failure.
+    Failure { reason: String },
+    /// A command of `--simulate`.
+    Command(Box<Command>),
+}
+
+/// Wakes the main loop up for the [`InternalEvent`]s: the waker of its poll, or with tokio a
+/// notify it waits on.
+#[cfg(not(feature = "tokio"))]
+pub use mio::Waker;
+#[cfg(feature = "tokio")]
+pub use tokio::sync::Notify as Waker;
+
+#[cfg(not(feature = "tokio"))]
+fn wake(waker: &Waker) -> std::io::Result<()> {
+    waker.wake()
+}
+#[cfg(feature = "tokio")]
+fn wake(waker: &Waker) -> std::io::Result<()> {
+    // Kept for the next wait if the loop isn't waiting yet
+    waker.notify_one();
+    Ok(())
+}
+
+/// Write `content` to `path` through a temporary file, so readers never see half of it.
+pub fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(&tmp, path)
+}
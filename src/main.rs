@@ -1,356 +1,970 @@
+mod cli;
+
+#[cfg(not(feature = "tokio"))]
+use std::io::ErrorKind;
 use std::{
     env,
-    fmt::Display,
-    fs::File,
-    io::{Read, Write},
-    os::unix::{net::UnixStream, prelude::AsRawFd},
+    fs::{File, OpenOptions},
+    io::{IsTerminal, Write},
+    os::unix::{io::RawFd, prelude::AsRawFd},
     path::{Path, PathBuf},
-    process::Command,
-    time::{Duration, Instant, SystemTime},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    time::Duration,
 };
 
 use anyhow::{Context, Result};
-use indoc::indoc;
-use mio::{unix::SourceFd, Events, Interest, Poll, Token};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use cli::CliCommand;
+use emerge_presence::{
+    auth::CommandAuth,
+    backpressure::BackpressureBuffer,
+    build_info,
+    command::handle_command,
+    config::{self, Config},
+    discord::{Client, ClientBuilder},
+    emerge_log,
+    error::PresenceError,
+    heartbeat::Heartbeat,
+    history::{self, BuildHistoryDb},
+    metrics,
+    mtimedb::{self, MtimeDbWatch},
+    notify::{self, CompletionNotifier},
+    portage,
+    profile::{self, Profiler},
+    session::MergeSession,
+    simulate,
+    state::{self, StateFile},
+    systemd,
+    template::Templates,
+    transport::{self, Transport},
+    watchdog::Watchdog,
+    write_atomic, InternalEvent, Waker,
+};
+use emerge_presence::{maybe_async, maybe_await};
+#[cfg(not(feature = "tokio"))]
+use mio::{Events, Interest, Poll, Registry, Token};
 use nix::{
     fcntl::{flock, FlockArg},
-    sys::stat::{umask, Mode},
-    unistd::mkfifo,
+    unistd::{chdir, dup2, fork, setsid, ForkResult},
 };
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-
-fn find_ipc_path() -> Option<PathBuf> {
-    let base = PathBuf::from(
-        ["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"]
-            .into_iter()
-            .find_map(|v| env::var(v).ok())
-            .unwrap_or_else(|| "/tmp".to_owned()),
-    );
-    (0..10).find_map(|n| base.join(format!("discord-ipc-{n}")).canonicalize().ok())
-}
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+#[cfg(not(feature = "tokio"))]
+use signal_hook_mio::v0_8::Signals;
+use tracing_subscriber::EnvFilter;
 
-fn get_merge_list_length() -> u32 {
-    Command::new("python")
-        .arg("-c")
-        .arg(indoc! {"
-            import portage
-            l = portage.mtimedb.get('resume', {}).get('mergelist')
-            print(0 if l is None else len(l), end='')
-        "})
-        .output()
-        .ok()
-        .and_then(|out| std::str::from_utf8(&out.stdout).ok()?.parse().ok())
-        .unwrap_or(0)
+fn write_dump(client: &Client, path: &Path) -> Result<()> {
+    write_atomic(path, &serde_json::to_vec_pretty(&client.dump())?)?;
+    Ok(())
 }
 
-pub struct Client {
-    client_id: String,
-    stream: Option<UnixStream>,
-    path: Option<PathBuf>,
-    merge_len: Option<u32>,
+/// The signals of [`Self::new`] as they arrive, like the `Signals` of signal-hook without tokio.
+#[cfg(feature = "tokio")]
+struct Signals {
+    streams: Vec<(i32, tokio::signal::unix::Signal)>,
 }
 
-impl Client {
-    pub fn new(id: &(impl ToString + ?Sized)) -> Self {
-        Self {
-            client_id: id.to_string(),
-            stream: None,
-            path: None,
-            merge_len: None,
-        }
-    }
-    pub fn is_connected(&self) -> bool {
-        self.stream.is_some()
+#[cfg(feature = "tokio")]
+impl Signals {
+    fn new(signals: &[i32]) -> std::io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let streams = signals
+            .iter()
+            .map(|&sig| Ok((sig, signal(SignalKind::from_raw(sig))?)))
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self { streams })
     }
-    fn handle_io(&mut self, io: std::io::Result<()>) -> Result<()> {
-        match io {
-            Err(io) => match io.kind() {
-                std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset => {
-                    if let Some(stream) = self.stream.as_mut() {
-                        stream.shutdown(std::net::Shutdown::Both).ok();
-                        self.stream = None;
-                        Err(anyhow::anyhow!("Broken Pipe"))
-                    } else {
-                        Ok(())
-                    }
+
+    /// Wait for the next signal, cancel safe.
+    async fn recv(&mut self) -> i32 {
+        std::future::poll_fn(|cx| {
+            for (signal, stream) in &mut self.streams {
+                if stream.poll_recv(cx).is_ready() {
+                    return std::task::Poll::Ready(*signal);
                 }
-                _ => Err(io)?,
-            },
-            Ok(()) => Ok(()),
-        }
+            }
+            std::task::Poll::Pending
+        })
+        .await
     }
-    pub fn connect(&mut self) -> Result<()> {
-        log::trace!("Connect");
-        if !self.is_connected() {
-            self.path = Some(find_ipc_path().context("Couldn't find discord-ipc")?);
-            self.stream = UnixStream::connect(self.path.as_ref().unwrap()).ok();
-            self.stream.as_ref().context("Failed to connect")?;
-            log::trace!("Connected");
-            self.handshake()?;
+}
+
+/// What woke [`Daemon::run`] up.
+#[cfg(feature = "tokio")]
+enum Wakeup {
+    Signal(i32),
+    /// The transport received this many bytes.
+    Received(usize),
+    MtimeDb,
+    /// Discord sent something, a thread sent an event or the timeout ran out, which the update
+    /// looks at anyway.
+    Other,
+}
+
+/// Dump the state or the flamegraph on the signals asking for it.
+fn handle_signal(signal: i32, client: &Client, config: &Config, profiler: &Option<Profiler>) {
+    if signal == SIGUSR1 {
+        match write_dump(client, &config.dump_path) {
+            Ok(()) => tracing::info!("Dumped state to {}", config.dump_path.display()),
+            Err(err) => tracing::warn!("Couldn't dump state ({err:?})"),
         }
-        Ok(())
     }
-    fn nonce(&self) -> String {
-        format!("{:016x}", rand::random::<u128>())
-    }
-    pub fn send(&mut self, opcode: u32, payload: &impl Serialize) -> Result<()> {
-        let stream = self.stream.as_mut().context("Socket isn't open")?;
-        let mut buf = Vec::new();
-        let payload = serde_json::to_string(payload)?;
-        let len = payload.len() as u32;
-        buf.extend_from_slice(&opcode.to_le_bytes());
-        buf.extend_from_slice(&len.to_le_bytes());
-        buf.extend_from_slice(payload.as_bytes());
-        let res = stream.write_all(&buf);
-        self.handle_io(res)?;
-        log::trace!("Sent opcode {opcode} with payload: {payload}");
-        Ok(())
-    }
-    pub fn recv(&mut self) -> Result<(u32, String)> {
-        let stream = self.stream.as_mut().context("Socket isn't open")?;
-        let opcode = get_number(stream)?;
-        let len = get_number(stream)?;
-        let mut buf = vec![0u8; len as usize];
-        let res = stream.read_exact(&mut buf);
-        self.handle_io(res)?;
-        let payload = std::str::from_utf8(&buf)?.to_string();
-        log::trace!("Received opcode {opcode} with payload {payload}");
-        Ok((opcode, payload))
-    }
-    pub fn handshake(&mut self) -> Result<()> {
-        let res = self.send(
-            0,
-            &json!({
-                "v": 1u32,
-                "client_id": self.client_id,
-                "nonce": self.nonce(),
-            }),
-        );
-        log::debug!("Handshake response: {:?}", self.recv());
-        res
-    }
-    pub fn reconnect(&mut self) -> Result<()> {
-        log::trace!("Reconnection");
-
-        self.send(2, &json!({})).ok();
-        if let Some(stream) = self.stream.as_mut() {
-            log::trace!("Sent disconnection");
-            stream.flush()?;
-            stream.shutdown(std::net::Shutdown::Both).ok();
-            log::trace!("Socket shutdown (flush)");
+    if let (SIGUSR2, Some(profiler)) = (signal, profiler) {
+        match profiler.dump(Path::new(profile::FLAMEGRAPH_PATH)) {
+            Ok(()) => {
+                tracing::info!("Wrote flamegraph to {}", profile::FLAMEGRAPH_PATH)
+            }
+            Err(err) => tracing::warn!("Couldn't write flamegraph ({err:?})"),
         }
+    }
+}
 
-        self.path = Some(find_ipc_path().context("Couldn't find discord-ipc")?);
-        self.stream = UnixStream::connect(self.path.as_ref().unwrap()).ok();
-        self.stream.as_ref().context("Reconnection failed")?;
+/// Everything the main loop works with.
+struct Daemon {
+    client: Client,
+    transport: Transport,
+    #[cfg(not(feature = "tokio"))]
+    poll: Poll,
+    /// What the threads notify, along with sending their events.
+    #[cfg(feature = "tokio")]
+    waker: Arc<Waker>,
+    signals: Signals,
+    /// Events of the background threads.
+    internal: Receiver<InternalEvent>,
+    mtimedb_watch: Option<MtimeDbWatch>,
+    notifiers: Vec<Box<dyn CompletionNotifier>>,
+    watchdog: Option<Watchdog>,
+    heartbeat: Heartbeat,
+    /// With a `secret`, commands have to be authenticated.
+    auth: Option<CommandAuth>,
+    /// Commands received while disconnected, handled once connected.
+    buffered: BackpressureBuffer,
+    profiler: Option<Profiler>,
+    config: Config,
+}
 
-        log::trace!("New connection open");
-        self.handshake()?;
+impl Daemon {
+    /// Longest the main loop can wait before it has something to do.
+    fn timeout(&mut self) -> Duration {
+        let Self {
+            client,
+            watchdog,
+            heartbeat,
+            config,
+            ..
+        } = self;
+        // Longest we wait before checking on the sessions, which is how long it takes to notice
+        // an emerge that was killed (or crashed) without unsetting. Waiting doesn't beat, so it
+        // must stay well under the watchdog timeout.
+        let mut check_interval = Duration::from_secs(config.pid_check_secs.max(1));
+        if watchdog.is_some() {
+            check_interval =
+                check_interval.min(Duration::from_secs((config.watchdog_secs / 2).max(1)));
+        }
+        let summary_delay = Duration::from_secs(config.summary_display_secs);
+        [
+            client.next_flush(),
+            client.summary_left(summary_delay),
+            client.is_connected().then(|| heartbeat.next_tick(client)),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(check_interval, Duration::min)
+    }
 
-        Ok(())
+    #[cfg(not(feature = "tokio"))]
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn run(&mut self) -> Result<()> {
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.beat();
+        }
+        let mut events = Events::with_capacity(64);
+        let timeout = self.timeout();
+        match self.poll.poll(&mut events, Some(timeout)) {
+            // A signal arrived, let the main loop look at it.
+            Err(err) if err.kind() == ErrorKind::Interrupted => return Ok(()),
+            res => res?,
+        }
+
+        if events.iter().any(|event| event.token() == SIGNALS) {
+            for signal in self.signals.pending() {
+                handle_signal(signal, &self.client, &self.config, &self.profiler);
+            }
+        }
+        if let Some(watch) = &self.mtimedb_watch {
+            let written = events.iter().any(|event| event.token() == MTIMEDB) && watch.changed();
+            if written {
+                tracing::trace!("mtimedb changed");
+                self.client.refresh_merge_state();
+            }
+        }
+        let len = self.transport.receive(&events, self.poll.registry())?;
+        self.update(len)
     }
 
-    pub fn set_package(&mut self, payload: PackagePayload) -> Result<()> {
-        let count = get_merge_list_length();
-        log::trace!("Got merge list len: {count}");
-        let new_count = self.merge_len.unwrap_or(0).max(count);
-        let party = match new_count {
-            0 => None,
-            1.. => Some(json!({
-                "id": "id",
-                "size": [new_count - count + 1, new_count]
-            })),
+    #[cfg(feature = "tokio")]
+    #[tracing::instrument(level = "trace", skip_all)]
+    async fn run(&mut self) -> Result<()> {
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.beat();
+        }
+        let timeout = self.timeout();
+        let Self {
+            client,
+            transport,
+            waker,
+            signals,
+            mtimedb_watch,
+            ..
+        } = self;
+        let mtimedb = async {
+            match mtimedb_watch {
+                Some(watch) => watch.readable().await,
+                None => std::future::pending().await,
+            }
+        };
+        // Everything raced is cancel safe, what the others were waiting for is still there for
+        // the next run
+        let wakeup = tokio::select! {
+            signal = signals.recv() => Wakeup::Signal(signal),
+            len = transport.receive() => Wakeup::Received(len?),
+            res = mtimedb => {
+                res?;
+                Wakeup::MtimeDb
+            }
+            res = client.readable() => {
+                // Read (and the error found again) by the update
+                if let Err(err) = res {
+                    tracing::debug!("Couldn't wait on discord ({err})");
+                }
+                Wakeup::Other
+            }
+            () = waker.notified() => Wakeup::Other,
+            () = tokio::time::sleep(timeout) => Wakeup::Other,
         };
+        let len = match wakeup {
+            Wakeup::Signal(signal) => {
+                handle_signal(signal, &self.client, &self.config, &self.profiler);
+                0
+            }
+            Wakeup::Received(len) => len,
+            Wakeup::MtimeDb => {
+                if self
+                    .mtimedb_watch
+                    .as_ref()
+                    .is_some_and(MtimeDbWatch::changed)
+                {
+                    tracing::trace!("mtimedb changed");
+                    self.client.refresh_merge_state();
+                }
+                0
+            }
+            Wakeup::Other => 0,
+        };
+        maybe_await!(self.update(len))
+    }
 
-        self.merge_len = Some(new_count);
+    maybe_async! {
+        /// Everything after the wait: connect, handle what came in (`len` bytes from the transport)
+        /// and update the presence.
+        fn update(&mut self, len: usize) -> Result<()> {
+            let Self {
+                client,
+                transport,
+                internal,
+                notifiers,
+                heartbeat,
+                auth,
+                buffered,
+                config,
+                ..
+            } = self;
+            let summary_delay = Duration::from_secs(config.summary_display_secs);
+            // Keep the connection warm even when no emerge is running, so that discord starting
+            // doesn't make the first command wait for a reconnection.
+            if !client.is_connected() && client.should_retry() {
+                match maybe_await!(client.connect()).and_then(|()| client.refresh_presence()) {
+                    Ok(()) => tracing::info!("Client connected"),
+                    Err(err) => tracing::debug!("Connection failed ({err})"),
+                }
+            }
+            // However it got connected again, before the commands received since
+            if client.is_connected() && !buffered.is_empty() {
+                tracing::info!(
+                    "Replaying {} commands received while disconnected",
+                    buffered.len()
+                );
+                for command in buffered.take() {
+                    if let Err(err) = handle_command(client, command) {
+                        tracing::warn!("Failed to handle buffered command ({err})");
+                    }
+                }
+            }
 
-        let PackagePayload {
-            category, package, ..
-        } = payload;
+            if client.is_connected() {
+                match maybe_await!(client.poll_events()) {
+                    Ok(()) => {}
+                    // Logged along with when we reconnect, which the loop does once the time comes
+                    Err(PresenceError::Closed { .. }) => {}
+                    Err(err) => tracing::debug!("Couldn't read from discord ({err})"),
+                }
+            }
+            maybe_await!(heartbeat.tick(client));
 
-        let mut value = json!({
-            "details": format!("{category}/{package}"),
-            "timestamps": {
-                "start": SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64,
-            },
-            "assets": {
-                "large_image": "gentoodrpgt"
-            },
-        });
+            if len > 0 {
+                tracing::info!("Received data");
+            }
+            transport.drain_commands(auth.as_ref(), |command| {
+                let res = command.and_then(|command| {
+                    if !client.is_connected() && BackpressureBuffer::accepts(&command) {
+                        tracing::debug!("Not connected, buffering the command");
+                        buffered.push(command);
+                        return Ok(None);
+                    }
+                    handle_command(client, command)
+                });
+                match res {
+                    Ok(reply) => reply,
+                    Err(err) => {
+                        tracing::warn!("Failed to handle command ({err})");
+                        None
+                    }
+                }
+            });
+            if len > 0 {
+                tracing::debug!("{client}");
+            }
+            for event in internal.try_iter() {
+                match event {
+                    InternalEvent::Failure { reason } => {
+                        if !client.fail_latest_session(reason)? {
+                            tracing::debug!("Failure reported with no session to fail, ignoring");
+                        }
+                    }
+                    InternalEvent::Command(command) => {
+                        if let Err(err) = handle_command(client, *command) {
+                            tracing::warn!("Failed to handle simulated command ({err})");
+                        }
+                    }
+                }
+            }
 
-        if let Some(state) = payload.state {
-            value
-                .as_object_mut()
-                .unwrap()
-                .insert("state".to_owned(), json!(state));
-        }
-        if let Some(party) = party {
-            value
-                .as_object_mut()
-                .unwrap()
-                .insert("party".to_owned(), party);
+            let delay = Duration::from_secs(config.unset_delay_secs);
+            let failure_delay = Duration::from_secs(config.failure_display_secs);
+            let ended = client.expire_sessions(delay, failure_delay);
+            for session in &ended {
+                session.summary().log();
+                if session.completed() {
+                    for notifier in notifiers.iter() {
+                        if let Err(err) = notifier.notify(session) {
+                            tracing::warn!("Couldn't send completion notification ({err:?})");
+                        }
+                    }
+                }
+            }
+            if !ended.is_empty() {
+                client.save_state();
+                let summary = ended
+                    .iter()
+                    .rev()
+                    .find(|session| session.completed())
+                    .map(MergeSession::summary)
+                    .filter(|_| !summary_delay.is_zero());
+                if client.has_sessions() {
+                    tracing::info!("A session ended, showing the next one");
+                    client.show_latest_session()?;
+                } else if let Some(summary) = summary {
+                    tracing::info!("No sessions left, showing the summary");
+                    client.show_summary(&summary)?;
+                } else {
+                    tracing::info!("No sessions left, clearing presence");
+                    maybe_await!(clear_presence(client))?;
+                }
+            }
+            if client.summary_left(summary_delay) == Some(Duration::ZERO) {
+                tracing::info!("Done showing the summary, clearing presence");
+                maybe_await!(clear_presence(client))?;
+            }
+            maybe_await!(client.flush_activities())?;
+            metrics::set_active_sessions(client.session_count());
+
+            Ok(())
         }
+    }
 
-        self.send(
-            1,
-            &json!({
-                "cmd": "SET_ACTIVITY",
-                "nonce": self.nonce(),
-                "args": {
-                    "activity": value,
-                    "pid": 0u32
+    maybe_async! {
+        /// Wait for a command and handle it, for --once. Discord has to be there: the command is
+        /// done once discord answered it.
+        fn run_once(&mut self) -> Result<()> {
+            let Self {
+                client,
+                transport,
+                #[cfg(not(feature = "tokio"))]
+                poll,
+                #[cfg(feature = "tokio")]
+                signals,
+                auth,
+                ..
+            } = self;
+            // Startup already tried to connect
+            if !client.is_connected() {
+                anyhow::bail!("Couldn't connect to discord");
+            }
+            #[cfg(not(feature = "tokio"))]
+            let mut events = Events::with_capacity(64);
+            loop {
+                #[cfg(not(feature = "tokio"))]
+                {
+                    match poll.poll(&mut events, None) {
+                        Err(err) if err.kind() == ErrorKind::Interrupted => {
+                            anyhow::bail!("Interrupted before getting a command");
+                        }
+                        res => res?,
+                    }
+                    transport.receive(&events, poll.registry())?;
                 }
-            }),
-        )
+                #[cfg(feature = "tokio")]
+                tokio::select! {
+                    res = transport.receive() => drop(res?),
+                    signal = signals.recv() => {
+                        if matches!(signal, SIGTERM | SIGINT) {
+                            anyhow::bail!("Interrupted before getting a command");
+                        }
+                        continue;
+                    }
+                }
+                let mut handled = None;
+                transport.drain_commands(auth.as_ref(), |command| {
+                    if handled.is_some() {
+                        tracing::warn!("Ignoring the commands after the first one");
+                        return None;
+                    }
+                    let res = command.and_then(|command| handle_command(client, command));
+                    let reply = res.as_ref().ok().cloned().flatten();
+                    handled = Some(res.map(drop));
+                    reply
+                });
+                if let Some(res) = handled {
+                    res?;
+                    // Queued with tokio
+                    maybe_await!(client.flush_activities())?;
+                    return Ok(());
+                }
+            }
+        }
     }
 }
 
-#[derive(Deserialize, Serialize)]
-#[serde(rename_all = "lowercase")]
-enum PackageState {
-    Preparing,
-    Compiling,
-    Installing,
+maybe_async! {
+    /// Clear the presence, reconnecting if that fails.
+    fn clear_presence(client: &mut Client) -> Result<()> {
+        if let Err(err) = client.clear_presence() {
+            tracing::info!("Couldn't clear presence ({err}), reconnecting");
+            maybe_await!(client.reconnect())?;
+        }
+        Ok(())
+    }
 }
 
-impl Display for PackageState {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Preparing => write!(f, "preparing"),
-            Self::Compiling => write!(f, "compiling"),
-            Self::Installing => write!(f, "installing"),
+/// Poll tokens of the signals, the waker, the mtimedb watch and the discord socket, transports
+/// ignore tokens they don't know.
+#[cfg(not(feature = "tokio"))]
+const SIGNALS: Token = Token(usize::MAX);
+#[cfg(not(feature = "tokio"))]
+const WAKER: Token = Token(usize::MAX - 1);
+#[cfg(not(feature = "tokio"))]
+const MTIMEDB: Token = Token(usize::MAX - 2);
+#[cfg(not(feature = "tokio"))]
+const DISCORD: Token = Token(usize::MAX - 3);
+const LOG_FILE: &str = "/tmp/rpcdiscordlogs";
+
+/// Log to the journal when running under systemd (and built with the journald feature), to stderr
+/// otherwise.
+fn init_logging(filter: EnvFilter, foreground: bool) {
+    #[cfg(feature = "journald")]
+    if env::var_os("JOURNAL_STREAM").is_some() {
+        use tracing_subscriber::layer::SubscriberExt;
+        use tracing_subscriber::util::SubscriberInitExt;
+
+        // No prefix, so that the span fields are PACKAGE, CATEGORY, STATE, ... in the journal
+        match tracing_journald::layer() {
+            Ok(layer) => {
+                tracing_subscriber::registry()
+                    .with(filter)
+                    .with(layer.with_field_prefix(None))
+                    .init();
+                return;
+            }
+            Err(err) => eprintln!("Couldn't connect to the journal, logging to stderr ({err})"),
         }
     }
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        // Once daemonized stderr is the log file
+        .with_ansi(foreground && std::io::stderr().is_terminal())
+        .init();
 }
 
-#[derive(Deserialize)]
-pub struct PackagePayload {
-    category: String,
-    package: String,
-    state: Option<PackageState>,
+/// Detach from the controlling terminal with the classic double fork, the first fork lets the
+/// shell get its prompt back and the second one (after `setsid`) makes sure we can never acquire
+/// a controlling terminal again. stdin is pointed at /dev/null and stdout/stderr at `log`.
+fn daemonize(log: &Path) -> Result<()> {
+    // Safety: we're still single threaded at this point
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        std::process::exit(0);
+    }
+    setsid()?;
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        std::process::exit(0);
+    }
+    chdir("/")?;
+
+    let null = File::open("/dev/null")?;
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .with_context(|| format!("Couldn't open log file {}", log.display()))?;
+    dup2(null.as_raw_fd(), 0)?;
+    dup2(log.as_raw_fd(), 1)?;
+    dup2(log.as_raw_fd(), 2)?;
+    Ok(())
 }
 
-pub fn get_number(stream: &mut UnixStream) -> Result<u32> {
-    let mut buf = [0u8; 4];
-    let len = stream.read(&mut buf)?;
-    if len < 4 {
-        Err(anyhow::anyhow!("Not enough bytes"))
-    } else {
-        Ok(u32::from_le_bytes(buf))
-    }
+#[derive(Parser)]
+#[command(version = build_info::VERSION, about)]
+struct Args {
+    /// Don't fork into the background
+    #[arg(long)]
+    foreground: bool,
+    /// Path to the config file (defaults to $XDG_CONFIG_HOME/emerge-presence/config.toml)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Path of the command socket (defaults to $XDG_RUNTIME_DIR/emerge-presence.sock)
+    #[arg(long, global = true)]
+    socket_path: Option<PathBuf>,
+    /// Read commands from the fifo instead of the socket
+    #[arg(long, global = true)]
+    legacy_fifo: bool,
+    /// Read commands from this already open socket or fifo instead of creating one (systemd
+    /// socket activation is picked up without it)
+    #[arg(long)]
+    socket_fd: Option<RawFd>,
+    /// Print what would be sent to discord instead of connecting to it
+    #[arg(long)]
+    dry_run: bool,
+    /// Replay the commands of a file (- for stdin), one per line like `set {...}` or `unset`,
+    /// to see what they look like without running emerge
+    #[arg(long, value_name = "FILE")]
+    simulate: Option<PathBuf>,
+    /// Seconds between simulated commands
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 2.0,
+        requires = "simulate"
+    )]
+    simulate_interval: f64,
+    /// Start the simulation over once it's done
+    #[arg(long = "loop", requires = "simulate")]
+    repeat: bool,
+    /// Seconds to wait after an unset before clearing the presence, instead of unset_delay_secs
+    /// (0 clears it right away)
+    #[arg(long, value_name = "SECS")]
+    clear_delay: Option<u64>,
+    /// Check the config file and exit, with a non-zero status if something is wrong
+    #[arg(long)]
+    check: bool,
+    /// Handle a single command (connecting to discord first) and exit, in the foreground. The
+    /// presence goes away with the connection, this is mostly for scripts and testing
+    #[arg(long)]
+    once: bool,
+    /// Sample what the daemon spends its time on, SIGUSR2 writes a flamegraph to
+    /// /tmp/emerge-presence-flamegraph.svg (needs the profile feature)
+    #[arg(long)]
+    profile: bool,
+    #[command(subcommand)]
+    command: Option<CliCommand>,
 }
 
-fn run(
-    client: &mut Client,
-    file: &mut File,
-    buf: &mut Vec<u8>,
-    poll: &mut Poll,
-    last_unset: &mut Option<Instant>,
-) -> Result<()> {
-    let mut events = Events::with_capacity(1);
-    poll.poll(&mut events, Some(Duration::from_secs(5)))?;
-    let len = file.read_to_end(buf)?;
-
-    if !client.is_connected() {
-        return client.connect();
+/// What `--version` prints, `-V` leaves out the portage version. This runs before logging is
+/// set up, so it doesn't go through the cache (which warns when portage isn't found).
+fn long_version() -> String {
+    let portage = match portage::detect_portage_version() {
+        Some(version) => version.to_string(),
+        None => "not found".to_owned(),
+    };
+    format!("{}\nportage {portage}", build_info::VERSION)
+}
+
+/// Print what `--check` found for each section of the config, returning the exit status.
+fn check_config(config: Result<Config>) -> i32 {
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            println!("ERROR parse: {err:#}");
+            return 1;
+        }
+    };
+    println!("OK    parse");
+    let errors = config.validate();
+    for section in config::CONFIG_SECTIONS {
+        let mut errors = errors
+            .iter()
+            .filter(|err| err.section == *section)
+            .peekable();
+        if errors.peek().is_none() {
+            println!("OK    {section}");
+        }
+        for err in errors {
+            println!("ERROR {section}: {}", err.message);
+        }
     }
+    i32::from(!errors.is_empty())
+}
 
-    if len > 0 {
-        log::info!("Received command");
-        let str = std::str::from_utf8(buf)?;
-        let commands = str.split_terminator('\u{0}').collect::<Vec<_>>();
-        log::trace!(
-            "Got {} commands, evaluating last ({commands:?})",
-            commands.len()
-        );
-        if let Some(command) = commands.last() {
-            if command.starts_with("set ") {
-                log::info!("Got set");
-                let json = command
-                    .strip_prefix("set ")
-                    .context("Command is missing arguments")?;
-                let val: PackagePayload = serde_json::from_str(json)?;
-                client.set_package(val)?;
-                log::info!("Response: {:?}", client.recv());
-                *last_unset = None;
-            } else if command.starts_with("unset") {
-                log::info!("Got unset, queueing");
-                *last_unset = Some(Instant::now());
-            }
+fn main() {
+    let mut command = Args::command();
+    // Asking portage for its version takes a while, only do it when it's going to be printed
+    if env::args_os().any(|arg| arg == "--version") {
+        command = command.long_version(long_version());
+    }
+    let matches = command.get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let config = Config::load(args.config.as_deref());
+    if args.check {
+        std::process::exit(check_config(config));
+    }
+    let mut config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err:?}");
+            std::process::exit(1);
         }
+    };
 
-        buf.clear();
+    if let Some(delay) = args.clear_delay {
+        config.unset_delay_secs = delay;
     }
 
-    if let Some(ts) = last_unset {
-        if ts.elapsed() > Duration::from_secs(30) {
-            log::info!("30 seconds has passed since the last unset with  no further commands, reconnecting.");
-            client.reconnect()?;
-            client.merge_len = None;
-            *last_unset = None;
+    if let Some(command) = &args.command {
+        let target = if args.legacy_fifo {
+            cli::Target::Fifo(config.fifo_path.clone())
+        } else {
+            cli::Target::Socket(
+                args.socket_path
+                    .clone()
+                    .or_else(|| config.socket_path.clone())
+                    .unwrap_or_else(transport::default_socket_path),
+            )
+        };
+        let auth = config.secret.as_ref().map(CommandAuth::new);
+        if let Err(err) = command.run(&target, auth.as_ref()) {
+            eprintln!("{err:?}");
+            std::process::exit(1);
         }
+        return;
     }
 
-    Ok(())
-}
-
-const PIPE: Token = Token(0);
+    let templates = if config.details_template.is_some() || config.state_template.is_some() {
+        let templates = Templates::new(
+            config.details_template.as_deref(),
+            config.state_template.as_deref(),
+        );
+        match templates {
+            Ok(templates) => Some(templates),
+            Err(err) => {
+                eprintln!("{err:?}");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
 
-fn main() {
-    //TODO: Daemonize
-    env_logger::init();
-    log::info!("Starting");
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.log_level))
+        .unwrap_or_else(|err| {
+            eprintln!("Invalid log level {:?} ({err})", config.log_level);
+            EnvFilter::new("error")
+        });
+    // Waiting for a single command in the background would only make it harder to tell how it went
+    let foreground = args.foreground || args.once;
+    init_logging(filter, foreground);
+    tracing::info!("Starting emerge-presence {}", build_info::VERSION);
+    if let Some(version) = portage::portage_version() {
+        tracing::info!("Found portage {version}");
+    }
+    tracing::debug!("Using config {config:?}");
 
-    // Create the file if needed
-    let mut pid_file = std::fs::OpenOptions::new()
+    // Create the file if needed, don't truncate before we hold the lock or we could wipe the pid
+    // of an already running instance.
+    let mut pid_file = OpenOptions::new()
         .create(true)
+        .truncate(false)
         .write(true)
-        .open("/tmp/rpcdiscordpid")
+        .open(&config.pid_file)
         .expect("Couldn't open pid file");
     flock(pid_file.as_raw_fd(), FlockArg::LockExclusiveNonblock)
         .expect("Couldn't lock pid file, another process may be using it");
+
+    // Before daemonizing, LISTEN_PID is the pid of this process.
+    let listen_fd = args.socket_fd.or_else(systemd::listen_fd);
+
+    // The lock is tied to the open file description, so it survives the forks as long as the
+    // daemon keeps pid_file open.
+    if !foreground {
+        tracing::info!("Daemonizing, logs will be written to {LOG_FILE}");
+        daemonize(Path::new(LOG_FILE)).expect("Failed to daemonize");
+    }
+
+    pid_file.set_len(0).expect("Failed to truncate pid file");
     pid_file
         .write_all(std::process::id().to_string().as_bytes())
         .expect("Failed to write pid");
 
-    if !Path::new("/tmp/_discordfifo").exists() {
-        log::info!("No fifo found, creating it");
-        // Otherwise pipe is created as prw-r--r--
-        let prev = umask(Mode::empty());
-        mkfifo(
-            "/tmp/_discordfifo",
-            Mode::S_IRUSR
-                | Mode::S_IWUSR
-                | Mode::S_IRGRP
-                | Mode::S_IWGRP
-                | Mode::S_IROTH
-                | Mode::S_IWOTH,
-        )
-        .unwrap();
-        umask(prev);
+    let state = StateFile::new(
+        config
+            .state_path
+            .clone()
+            .unwrap_or_else(state::default_state_path),
+    );
+    let client_id = env::var("DISCORD_CLIENT_ID")
+        .ok()
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| config.client_id.clone());
+    tracing::debug!("Using client id {client_id}");
+    let mut client = ClientBuilder::default()
+        .client_id(&client_id)
+        .build()
+        .expect("Invalid client id");
+    client.use_state_file(state);
+    let history = config
+        .history_path
+        .clone()
+        .or_else(history::default_history_path);
+    if let Some(path) = history {
+        match BuildHistoryDb::load(path) {
+            Ok(history) => client.set_history(history),
+            Err(err) => tracing::warn!("Couldn't read build history ({err:?})"),
+        }
     }
-    let mut client = Client::new("1007427345801556039");
-    match client.connect() {
-        Ok(()) => log::info!("Client connected"),
-        Err(err) => log::warn!("Connection failed ({err:?})"),
+    client.set_dry_run(args.dry_run);
+    client.apply_config(&config);
+    if let Some(templates) = templates {
+        client.set_templates(templates);
     }
-    let mut buf = Vec::new();
-    let mut poll = Poll::new().unwrap();
-    let mut file = File::options()
-        .read(true)
-        .write(false)
-        .open("/tmp/_discordfifo")
-        .unwrap();
-    poll.registry()
-        .register(&mut SourceFd(&file.as_raw_fd()), PIPE, Interest::READABLE)
-        .unwrap();
-    let mut last_unset = None;
-    loop {
-        log::info!("Waiting for command");
-        match run(&mut client, &mut file, &mut buf, &mut poll, &mut last_unset) {
-            Ok(()) => {}
-            Err(e) => log::warn!("{e:?}"),
+    // Started before connecting, which can get stuck too
+    let hang_watchdog = (config.watchdog_secs > 0).then(|| {
+        let watchdog = Watchdog::new(
+            Duration::from_secs(config.watchdog_secs),
+            config.watchdog_restart,
+        );
+        watchdog.spawn();
+        watchdog
+    });
+    #[cfg(not(feature = "tokio"))]
+    serve(args, config, client, listen_fd, hang_watchdog);
+    #[cfg(feature = "tokio")]
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Couldn't start the tokio runtime");
+        runtime.block_on(serve(args, config, client, listen_fd, hang_watchdog));
+        // Without waiting for the fifo read still going on the blocking pool
+        runtime.shutdown_background();
+    }
+}
+
+/// Open the fifo or socket the commands come from, exiting if that fails.
+fn open_transport(
+    args: &Args,
+    config: &Config,
+    listen_fd: Option<RawFd>,
+    #[cfg(not(feature = "tokio"))] registry: &Registry,
+) -> Result<Transport> {
+    let private = config.secret.is_some();
+    let mut transport = if let Some(fd) = listen_fd {
+        tracing::info!("Using the socket or fifo at fd {fd}");
+        Transport::from_fd(fd).context("Couldn't use the passed socket")?
+    } else if args.legacy_fifo {
+        Transport::fifo(&config.fifo_path, private).context("Couldn't open fifo")?
+    } else {
+        let path = args
+            .socket_path
+            .clone()
+            .or_else(|| config.socket_path.clone())
+            .unwrap_or_else(transport::default_socket_path);
+        tracing::info!("Listening on {}", path.display());
+        Transport::socket(&path, private).context("Couldn't open socket")?
+    };
+    #[cfg(not(feature = "tokio"))]
+    transport
+        .register(registry)
+        .context("Couldn't watch the transport")?;
+    transport.set_max_payload_bytes(config.max_payload_bytes);
+    Ok(transport)
+}
+
+maybe_async! {
+    /// Connect, then handle commands until terminated. The rest of [`main`], run on the runtime
+    /// with tokio.
+    fn serve(
+        args: Args,
+        config: Config,
+        mut client: Client,
+        listen_fd: Option<RawFd>,
+        hang_watchdog: Option<Watchdog>,
+    ) {
+        match maybe_await!(client.connect()).and_then(|()| client.refresh_presence()) {
+            Ok(()) => tracing::info!("Client connected"),
+            Err(err) => tracing::warn!("Connection failed ({err:?})"),
+        }
+        #[cfg(not(feature = "tokio"))]
+        let poll = Poll::new().unwrap();
+        // Wake up for the events and close frames of discord, read along with the commands
+        #[cfg(not(feature = "tokio"))]
+        if let Err(err) = client.register(poll.registry(), DISCORD) {
+            tracing::warn!("Couldn't watch the discord socket ({err:?})");
+        }
+        let transport = open_transport(
+            &args,
+            &config,
+            listen_fd,
+            #[cfg(not(feature = "tokio"))]
+            poll.registry(),
+        );
+        let transport = match transport {
+            Ok(transport) => transport,
+            Err(err) => {
+                tracing::error!("{err:?}");
+                // The presence set when connecting (restored sessions) would otherwise stay up
+                maybe_await!(client.shutdown());
+                std::process::exit(1);
+            }
+        };
+        let terminate = Arc::new(AtomicBool::new(false));
+        for signal in [SIGTERM, SIGINT] {
+            signal_hook::flag::register(signal, Arc::clone(&terminate))
+                .expect("Couldn't register signal handler");
+        }
+        // Started after daemonizing, the sampling timer doesn't survive the forks
+        let profiler = args.profile.then(Profiler::start).flatten();
+        // Left to its default (terminating) when there is no flamegraph to write
+        let dump_signals = match profiler {
+            Some(_) => &[SIGUSR1, SIGUSR2][..],
+            None => &[SIGUSR1],
+        };
+        #[cfg(not(feature = "tokio"))]
+        let mut signals = Signals::new(dump_signals).expect("Couldn't register signal handler");
+        #[cfg(not(feature = "tokio"))]
+        poll.registry()
+            .register(&mut signals, SIGNALS, Interest::READABLE)
+            .unwrap();
+        // The terminating ones too, without a poll to interrupt they have to wake the loop up
+        #[cfg(feature = "tokio")]
+        let signals = Signals::new(&[dump_signals, &[SIGTERM, SIGINT]].concat())
+            .expect("Couldn't register signal handler");
+        let mtimedb_path = Path::new(mtimedb::MTIMEDB_PATH);
+        #[cfg(not(feature = "tokio"))]
+        let mtimedb_watch = MtimeDbWatch::new(mtimedb_path, poll.registry(), MTIMEDB);
+        #[cfg(feature = "tokio")]
+        let mtimedb_watch = MtimeDbWatch::new(mtimedb_path);
+        let mtimedb_watch = match mtimedb_watch {
+            Ok(watch) => Some(watch),
+            Err(err) => {
+                tracing::warn!(
+                    "Couldn't watch the mtimedb, it will be checked on each set ({err:?})"
+                );
+                None
+            }
+        };
+        let (sender, internal) = mpsc::channel();
+        // A poll can only have one waker, which the threads share
+        #[cfg(not(feature = "tokio"))]
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER).expect("Couldn't create waker"));
+        #[cfg(feature = "tokio")]
+        let waker = Arc::new(Waker::new());
+        if let Some(path) = &config.emerge_log {
+            tracing::info!("Watching {} for failures", path.display());
+            emerge_log::watch(path.clone(), sender.clone(), Arc::clone(&waker));
+        }
+        if let Some(path) = &args.simulate {
+            tracing::info!("Simulating the commands of {}", path.display());
+            let interval = Duration::try_from_secs_f64(args.simulate_interval).unwrap_or_default();
+            simulate::replay(path.clone(), interval, args.repeat, sender, Arc::clone(&waker));
+        }
+
+        // The fifo/socket is open and we tried to connect once, which is as ready as we get
+        // (discord may very well not be running).
+        systemd::notify_ready();
+        let watchdog = systemd::watchdog_enabled();
+
+        if let Some(port) = config.metrics_port {
+            metrics::serve_on(port);
+        }
+        let notifiers = notify::from_config(&config);
+        let mut daemon = Daemon {
+            client,
+            transport,
+            #[cfg(not(feature = "tokio"))]
+            poll,
+            #[cfg(feature = "tokio")]
+            waker,
+            signals,
+            internal,
+            mtimedb_watch,
+            notifiers,
+            watchdog: hang_watchdog,
+            heartbeat: Heartbeat::new(),
+            auth: config.secret.as_ref().map(CommandAuth::new),
+            buffered: BackpressureBuffer::default(),
+            profiler,
+            config,
+        };
+        if args.once {
+            let res = maybe_await!(daemon.run_once());
+            maybe_await!(daemon.client.disconnect()).ok();
+            if let Err(err) = res {
+                tracing::error!("{err:?}");
+                eprintln!("{err:?}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        let mut status = 0;
+        while !terminate.load(Ordering::Relaxed) {
+            tracing::info!("Waiting for command");
+            match maybe_await!(daemon.run()) {
+                Ok(()) if watchdog => systemd::notify_watchdog(),
+                Ok(()) => {}
+                Err(err) => match err.downcast_ref::<PresenceError>() {
+                    Some(presence) if presence.is_transient() => {
+                        tracing::info!("{presence}, will reconnect");
+                    }
+                    // Retrying would only fail the same way, better let the service manager see it
+                    Some(presence) => {
+                        tracing::error!("{presence}, exiting");
+                        status = 1;
+                        break;
+                    }
+                    // Failed polls, accepts and reads of the transport: the next iteration may well
+                    // go through, the startup failures never make it here
+                    None => tracing::warn!("{err:?}"),
+                },
+            }
+        }
+
+        tracing::info!("Terminating, clearing presence");
+        maybe_await!(daemon.client.shutdown());
+        if status != 0 {
+            std::process::exit(status);
         }
     }
 }
@@ -0,0 +1,190 @@
+//! Prometheus metrics, served over http on `metrics_port` when built with the `metrics` feature.
+//! Without it recording is a no-op.
+
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+// AtomicU64 doesn't exist on some of the 32-bit targets gentoo runs on (powerpc, mips), hence the
+// usizes and the mutex for the one value that could wrap around there.
+#[cfg(feature = "metrics")]
+struct Metrics {
+    set_commands: AtomicUsize,
+    packages_merged: AtomicUsize,
+    discord_reconnects: AtomicUsize,
+    /// Time spent reading the mtimedb.
+    portage_query_time: Mutex<Duration>,
+    portage_queries: AtomicUsize,
+    active_sessions: AtomicUsize,
+}
+
+#[cfg(feature = "metrics")]
+static METRICS: Metrics = Metrics {
+    set_commands: AtomicUsize::new(0),
+    packages_merged: AtomicUsize::new(0),
+    discord_reconnects: AtomicUsize::new(0),
+    portage_query_time: Mutex::new(Duration::ZERO),
+    portage_queries: AtomicUsize::new(0),
+    active_sessions: AtomicUsize::new(0),
+};
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    /// The metrics in the prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, String)]| {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} {kind}").unwrap();
+            for (suffix, value) in values {
+                writeln!(out, "{name}{suffix} {value}").unwrap();
+            }
+        };
+        let get = |counter: &AtomicUsize| counter.load(Ordering::Relaxed).to_string();
+        metric(
+            "emerge_presence_set_commands_total",
+            "counter",
+            "Set commands received from the hooks.",
+            &[("", get(&self.set_commands))],
+        );
+        metric(
+            "emerge_presence_packages_merged_total",
+            "counter",
+            "Packages that finished merging.",
+            &[("", get(&self.packages_merged))],
+        );
+        metric(
+            "emerge_presence_discord_reconnects_total",
+            "counter",
+            "Connections to discord after the first one.",
+            &[("", get(&self.discord_reconnects))],
+        );
+        let query_time = *self.portage_query_time.lock().unwrap();
+        metric(
+            "emerge_presence_portage_query_duration_seconds",
+            "summary",
+            "Time spent reading the mtimedb.",
+            &[
+                ("_sum", query_time.as_secs_f64().to_string()),
+                ("_count", get(&self.portage_queries)),
+            ],
+        );
+        metric(
+            "emerge_presence_active_sessions",
+            "gauge",
+            "Emerges currently tracked.",
+            &[("", get(&self.active_sessions))],
+        );
+        out
+    }
+}
+
+pub fn record_set_command() {
+    #[cfg(feature = "metrics")]
+    METRICS.set_commands.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_package_merged() {
+    #[cfg(feature = "metrics")]
+    METRICS.packages_merged.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_reconnect() {
+    #[cfg(feature = "metrics")]
+    METRICS.discord_reconnects.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub fn record_portage_query(duration: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        *METRICS.portage_query_time.lock().unwrap() += duration;
+        METRICS.portage_queries.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub fn set_active_sessions(count: usize) {
+    #[cfg(feature = "metrics")]
+    METRICS.active_sessions.store(count, Ordering::Relaxed);
+}
+
+/// Serves `/metrics` on localhost from a background thread.
+#[cfg(feature = "metrics")]
+pub struct MetricsServer {
+    listener: TcpListener,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsServer {
+    pub fn bind(port: u16) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        Ok(Self { listener })
+    }
+
+    pub fn spawn(self) {
+        thread::Builder::new()
+            .name("metrics".to_owned())
+            .spawn(move || {
+                for stream in self.listener.incoming() {
+                    let res = stream.map_err(anyhow::Error::from).and_then(serve);
+                    if let Err(err) = res {
+                        tracing::debug!("Metrics request failed ({err:?})");
+                    }
+                }
+            })
+            .expect("Couldn't spawn metrics server");
+    }
+}
+
+/// Answer a single http request, closing the connection afterwards.
+#[cfg(feature = "metrics")]
+fn serve(mut stream: TcpStream) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", "Not found\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// Start serving the metrics on `port`.
+#[cfg(feature = "metrics")]
+pub fn serve_on(port: u16) {
+    match MetricsServer::bind(port) {
+        Ok(server) => {
+            tracing::info!("Serving metrics on 127.0.0.1:{port}");
+            server.spawn();
+        }
+        Err(err) => tracing::warn!("Couldn't serve metrics on port {port} ({err:?})"),
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn serve_on(_port: u16) {
+    tracing::warn!("emerge-presence was built without the metrics feature, not serving metrics");
+}
@@ -0,0 +1,295 @@
+#[cfg(not(feature = "tokio"))]
+use std::os::unix::prelude::AsRawFd;
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime},
+};
+
+#[cfg(not(feature = "tokio"))]
+use mio::{unix::SourceFd, Interest, Registry, Token};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use serde::{de::IgnoredAny, Deserialize};
+#[cfg(feature = "tokio")]
+use tokio::io::unix::AsyncFd;
+
+#[cfg(feature = "portage-native")]
+use crate::portage_native as pickle;
+use crate::{error::PresenceError, metrics, portage};
+
+pub const MTIMEDB_PATH: &str = "/var/cache/edb/mtimedb";
+
+/// The subset of portage's mtimedb we care about.
+#[derive(Deserialize)]
+struct MtimeDb {
+    resume: Option<ResumeList>,
+    /// The previous resume list, kept when a new merge starts over an interrupted one.
+    resume_backup: Option<ResumeList>,
+}
+
+#[derive(Deserialize)]
+struct ResumeList {
+    mergelist: Option<Vec<IgnoredAny>>,
+    myopts: Option<MergeOptions>,
+}
+
+/// The options of the merge, a dict since portage 2.1.6 and a list before.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MergeOptions {
+    Dict(HashMap<String, IgnoredAny>),
+    List(Vec<String>),
+}
+
+impl MergeOptions {
+    fn contains(&self, option: &str) -> bool {
+        match self {
+            Self::Dict(options) => options.contains_key(option),
+            Self::List(options) => options.iter().any(|o| o == option),
+        }
+    }
+}
+
+/// What the mtimedb tells about the merge in progress.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeState {
+    /// The merge was started with `--resume`.
+    pub is_resume: bool,
+    /// Length of the resume list, the number of packages left.
+    pub list_length: u32,
+    /// Length of the backup resume list, if an earlier merge was interrupted.
+    pub backup_list_length: Option<u32>,
+}
+
+/// Read the state of the merge from the mtimedb at `path`. Portage has been writing the mtimedb
+/// as json for years, so this is usually just a matter of deserializing the keys we need. Very
+/// old databases are pickled, which portage still reads, so we do too (see
+/// [`pickled_merge_state`]). With `legacy` (portage older than 2.3, which wrote pickles by
+/// default) the pickle is tried first.
+fn read_merge_state(path: &Path, legacy: bool) -> Result<MergeState, PresenceError> {
+    let content = std::fs::read(path).map_err(|err| {
+        PresenceError::PortageQuery(format!("Couldn't read {} ({err})", path.display()))
+    })?;
+    let parse_error = |json_err: String, pickle_err: String| {
+        PresenceError::PortageQuery(format!(
+            "Couldn't parse mtimedb as json ({json_err}) or pickle ({pickle_err})"
+        ))
+    };
+    if legacy {
+        return pickled_merge_state(path, &content).or_else(|pickle_err| {
+            json_merge_state(&content).map_err(|json_err| parse_error(json_err, pickle_err))
+        });
+    }
+    json_merge_state(&content).or_else(|json_err| {
+        pickled_merge_state(path, &content).map_err(|pickle_err| parse_error(json_err, pickle_err))
+    })
+}
+
+fn json_merge_state(content: &[u8]) -> Result<MergeState, String> {
+    let db = serde_json::from_slice::<MtimeDb>(content).map_err(|err| err.to_string())?;
+    let list_length = |list: &Option<ResumeList>| {
+        let list = list.as_ref()?.mergelist.as_ref()?;
+        Some(list.len() as u32)
+    };
+    Ok(MergeState {
+        is_resume: db
+            .resume
+            .as_ref()
+            .and_then(|resume| resume.myopts.as_ref())
+            .is_some_and(|opts| opts.contains("--resume")),
+        list_length: list_length(&db.resume).unwrap_or(0),
+        backup_list_length: list_length(&db.resume_backup),
+    })
+}
+
+/// Read a pickled mtimedb with our own parser.
+#[cfg(feature = "portage-native")]
+fn pickled_merge_state(_path: &Path, content: &[u8]) -> Result<MergeState, String> {
+    let db = pickle::parse(content)?;
+    let root = db.root();
+    if !matches!(root.value(), pickle::Value::Dict(_)) {
+        return Err("mtimedb isn't a dict".to_owned());
+    }
+    let list_length = |key: &str| {
+        let len = root.get(key)?.get("mergelist")?.len()?;
+        Some(len as u32)
+    };
+    Ok(MergeState {
+        is_resume: root
+            .get("resume")
+            .and_then(|resume| resume.get("myopts"))
+            .is_some_and(|opts| opts.contains("--resume")),
+        list_length: list_length("resume").unwrap_or(0),
+        backup_list_length: list_length("resume_backup"),
+    })
+}
+
+/// What portage itself makes of the mtimedb, for the python snippet below.
+#[cfg(all(feature = "portage-python", not(feature = "portage-native")))]
+const PYTHON_MERGE_STATE: &str = r#"
+import json, sys
+from portage.util.mtimedb import MtimeDB
+db = MtimeDB(sys.argv[1])
+def length(key):
+    mergelist = (db.get(key) or {}).get("mergelist")
+    return None if mergelist is None else len(mergelist)
+print(json.dumps({
+    "is_resume": "--resume" in ((db.get("resume") or {}).get("myopts") or {}),
+    "list_length": length("resume") or 0,
+    "backup_list_length": length("resume_backup"),
+}))
+"#;
+
+/// Ask portage to read a pickled mtimedb, through python.
+#[cfg(all(feature = "portage-python", not(feature = "portage-native")))]
+fn pickled_merge_state(path: &Path, _content: &[u8]) -> Result<MergeState, String> {
+    let output = std::process::Command::new("python3")
+        .args(["-c", PYTHON_MERGE_STATE])
+        .arg(path)
+        .output()
+        .map_err(|err| format!("Couldn't run python ({err})"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Python failed ({})", stderr.trim()));
+    }
+    let state: serde_json::Value =
+        serde_json::from_slice(&output.stdout).map_err(|err| err.to_string())?;
+    let number = |key: &str| state[key].as_u64().map(|n| n as u32);
+    Ok(MergeState {
+        is_resume: state["is_resume"].as_bool().unwrap_or(false),
+        list_length: number("list_length").unwrap_or(0),
+        backup_list_length: number("backup_list_length"),
+    })
+}
+
+#[cfg(not(any(feature = "portage-python", feature = "portage-native")))]
+fn pickled_merge_state(_path: &Path, _content: &[u8]) -> Result<MergeState, String> {
+    Err("emerge-presence was built without portage-python or portage-native".to_owned())
+}
+
+/// Merge state, only read again when the mtimedb changed since the last read.
+pub struct MergeStateCache {
+    path: PathBuf,
+    state: MergeState,
+    /// Modification time of the mtimedb when `state` was read, `None` if it couldn't be read.
+    mtime: Option<SystemTime>,
+    /// Whether portage predates 2.3, see [`read_merge_state`].
+    legacy: bool,
+}
+
+impl MergeStateCache {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            state: MergeState::default(),
+            mtime: None,
+            legacy: portage::is_legacy_portage(portage::portage_version()),
+        }
+    }
+
+    /// The merge state, assuming an empty merge list if the mtimedb can't be read.
+    pub fn get(&mut self) -> MergeState {
+        let mtime = std::fs::metadata(&self.path).and_then(|meta| meta.modified());
+        match mtime {
+            Ok(mtime) if self.mtime == Some(mtime) => self.state,
+            _ => self.refresh(),
+        }
+    }
+
+    /// Read the mtimedb again regardless of its modification time.
+    pub fn refresh(&mut self) -> MergeState {
+        // Take the mtime first, a write racing with the read then triggers another one next time.
+        self.mtime = std::fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .ok();
+        let started = Instant::now();
+        let state = read_merge_state(&self.path, self.legacy);
+        metrics::record_portage_query(started.elapsed());
+        self.state = match state {
+            Ok(state) => state,
+            Err(err) => {
+                tracing::warn!("Failed to get merge state, assuming an empty list ({err:?})");
+                self.mtime = None;
+                MergeState::default()
+            }
+        };
+        tracing::trace!(state = ?self.state, "Read merge state");
+        self.state
+    }
+}
+
+/// inotify watch on the directory of the mtimedb: portage replaces the file on each write, so
+/// watching the file itself would only catch the first change.
+pub struct MtimeDbWatch {
+    #[cfg(not(feature = "tokio"))]
+    inotify: Inotify,
+    #[cfg(feature = "tokio")]
+    inotify: AsyncFd<Inotify>,
+    name: PathBuf,
+}
+
+fn watch_dir(path: &Path) -> nix::Result<Inotify> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+    inotify.add_watch(
+        dir,
+        AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+    )?;
+    Ok(inotify)
+}
+
+impl MtimeDbWatch {
+    #[cfg(not(feature = "tokio"))]
+    pub fn new(path: &Path, registry: &Registry, token: Token) -> anyhow::Result<Self> {
+        let inotify = watch_dir(path)?;
+        registry.register(
+            &mut SourceFd(&inotify.as_raw_fd()),
+            token,
+            Interest::READABLE,
+        )?;
+        Ok(Self {
+            inotify,
+            name: path.file_name().map(PathBuf::from).unwrap_or_default(),
+        })
+    }
+
+    /// Watch the mtimedb at `path`, [`Self::readable`] says when to look at [`Self::changed`].
+    /// Must be called from within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            inotify: AsyncFd::new(watch_dir(path)?)?,
+            name: path.file_name().map(PathBuf::from).unwrap_or_default(),
+        })
+    }
+
+    /// Wait for something to happen in the directory of the mtimedb.
+    #[cfg(feature = "tokio")]
+    pub async fn readable(&self) -> std::io::Result<()> {
+        // Cleared right away, changed reads everything that's there
+        self.inotify.readable().await?.clear_ready();
+        Ok(())
+    }
+
+    /// Consume the pending events, returns true if the mtimedb was written.
+    pub fn changed(&self) -> bool {
+        #[cfg(not(feature = "tokio"))]
+        let inotify = &self.inotify;
+        #[cfg(feature = "tokio")]
+        let inotify = self.inotify.get_ref();
+        let mut changed = false;
+        while let Ok(events) = inotify.read_events() {
+            if events.is_empty() {
+                break;
+            }
+            changed |= events
+                .iter()
+                .any(|event| event.name.as_deref() == Some(OsStr::new(&self.name)));
+        }
+        changed
+    }
+}
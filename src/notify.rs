@@ -0,0 +1,90 @@
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::{config::Config, session::MergeSession};
+
+/// Something to tell when an emerge is done.
+pub trait CompletionNotifier {
+    fn notify(&self, session: &MergeSession) -> Result<()>;
+}
+
+/// The notifiers that can be enabled in the config.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierKind {
+    /// Accepted for the configs that have it, the summary is always logged.
+    Log,
+    Desktop,
+    Webhook,
+}
+
+/// libnotify notification, through D-Bus.
+#[cfg(feature = "desktop-notifications")]
+pub struct DesktopNotifier;
+
+#[cfg(feature = "desktop-notifications")]
+impl CompletionNotifier for DesktopNotifier {
+    fn notify(&self, session: &MergeSession) -> Result<()> {
+        notify_rust::Notification::new()
+            .summary("emerge finished")
+            .body(&session.summary().to_string())
+            .appname("emerge-presence")
+            .show()?;
+        Ok(())
+    }
+}
+
+/// POSTs a json summary of the session to `url`.
+#[cfg(feature = "webhook")]
+pub struct WebhookNotifier {
+    url: String,
+}
+
+#[cfg(feature = "webhook")]
+impl CompletionNotifier for WebhookNotifier {
+    fn notify(&self, session: &MergeSession) -> Result<()> {
+        let last = session
+            .current_package
+            .as_ref()
+            .map(|last| format!("{}/{}", last.category, last.package));
+        let summary = session.summary();
+        ureq::post(&self.url)
+            .timeout(std::time::Duration::from_secs(5))
+            .send_json(serde_json::json!({
+                "event": "completed",
+                "summary": summary.to_string(),
+                "packages": summary.merged,
+                "failed": summary.failed,
+                "duration_secs": summary.elapsed.as_secs(),
+                "last_package": last,
+            }))?;
+        Ok(())
+    }
+}
+
+/// Build the notifiers enabled in the config, leaving out (with a warning) the ones that can't
+/// be used.
+pub fn from_config(config: &Config) -> Vec<Box<dyn CompletionNotifier>> {
+    let notifier = |kind: &NotifierKind| -> Option<Box<dyn CompletionNotifier>> {
+        match kind {
+            // The summary of every session is logged anyway
+            NotifierKind::Log => None,
+            #[cfg(feature = "desktop-notifications")]
+            NotifierKind::Desktop => Some(Box::new(DesktopNotifier)),
+            #[cfg(feature = "webhook")]
+            NotifierKind::Webhook => match &config.webhook_url {
+                Some(url) => Some(Box::new(WebhookNotifier { url: url.clone() })),
+                None => {
+                    tracing::warn!("The webhook notifier needs webhook_url to be set");
+                    None
+                }
+            },
+            #[allow(unreachable_patterns)]
+            kind => {
+                tracing::warn!("emerge-presence was built without the {kind:?} notifier");
+                None
+            }
+        }
+    };
+    config.notifiers.iter().filter_map(notifier).collect()
+}
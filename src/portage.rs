@@ -0,0 +1,932 @@
+//! What the hooks tell us about portage (the package being merged and the options of emerge), and
+//! what we ask portage itself, through [`Portage`].
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::mtimedb::{self, MergeState, MergeStateCache};
+
+/// Installed packages database, the directory of portage there has its version.
+const VDB_PATH: &str = "/var/db/pkg";
+
+/// Parse a portage version (`3.0.49`, `2.3.100-r1`, `Portage 3.0.49 (python ...)`), leaving out
+/// the revision and padding missing components with zeros.
+fn parse_portage_version(text: &str) -> Option<Version> {
+    text.split_whitespace().find_map(|word| {
+        let word = word.split_once("-r").map_or(word, |(version, _)| version);
+        let mut parts = word.split('.').map(|part| part.parse::<u64>().ok());
+        let major = parts.next()??;
+        let minor = parts.next().unwrap_or(Some(0))?;
+        let patch = parts.next().unwrap_or(Some(0))?;
+        Some(Version::new(major, minor, patch))
+    })
+}
+
+/// The version of portage in the installed packages database.
+fn vdb_portage_version() -> Option<Version> {
+    std::fs::read_dir(Path::new(VDB_PATH).join("sys-apps"))
+        .ok()?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name();
+            parse_portage_version(name.to_str()?.strip_prefix("portage-")?)
+        })
+        .max()
+}
+
+/// How long the answers of [`Portage`] are reused for, they only change with merges (after which
+/// [`Portage::forget_installed`] is called) or when someone else runs emerge.
+const QUERY_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// An answer of portage, along with when it was asked.
+struct Cached<T> {
+    value: T,
+    queried_at: Instant,
+}
+
+impl<T: Clone> Cached<T> {
+    /// The answer in `cached` if it's recent enough, or else the one of `query`, kept in `cached`
+    /// for next time.
+    fn get_or_query(cached: &mut Option<Self>, query: impl FnOnce() -> T) -> T {
+        match cached {
+            Some(cached) if cached.queried_at.elapsed() <= QUERY_TTL => cached.value.clone(),
+            _ => {
+                let value = query();
+                *cached = Some(Self {
+                    value: value.clone(),
+                    queried_at: Instant::now(),
+                });
+                value
+            }
+        }
+    }
+}
+
+/// The queries to portage, made when first needed and kept for [`QUERY_TTL`].
+pub struct Portage {
+    vdb_path: PathBuf,
+    installed_count: Option<Cached<Option<u32>>>,
+    /// The mtimedb, which is only read again when it changes.
+    merge_state: MergeStateCache,
+}
+
+impl Portage {
+    pub fn new() -> Self {
+        Self::with_paths(VDB_PATH, mtimedb::MTIMEDB_PATH)
+    }
+
+    /// Query the installed packages database and mtimedb at other paths.
+    pub fn with_paths(vdb: impl Into<PathBuf>, mtimedb: impl Into<PathBuf>) -> Self {
+        Self {
+            vdb_path: vdb.into(),
+            installed_count: None,
+            merge_state: MergeStateCache::new(mtimedb),
+        }
+    }
+
+    /// Forget what was queried from the installed packages database, for after a merge.
+    pub fn forget_installed(&mut self) {
+        self.installed_count = None;
+    }
+
+    /// The state of the merge in progress, assuming an empty merge list if the mtimedb can't be
+    /// read.
+    pub fn merge_state(&mut self) -> MergeState {
+        self.merge_state.get()
+    }
+
+    /// Read the mtimedb again, for when it was written.
+    pub fn refresh_merge_state(&mut self) {
+        self.merge_state.refresh();
+    }
+
+    /// Number of installed packages.
+    pub fn installed_count(&mut self) -> Option<u32> {
+        Cached::get_or_query(&mut self.installed_count, || {
+            installed_count(&self.vdb_path)
+        })
+    }
+}
+
+impl Default for Portage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Number of installed packages, from the entries of the installed packages database (like
+/// `qlist -I | wc -l`, without needing portage-utils).
+fn installed_count(vdb: &Path) -> Option<u32> {
+    let count = std::fs::read_dir(vdb)
+        .ok()?
+        .filter_map(|category| std::fs::read_dir(category.ok()?.path()).ok())
+        .flatten()
+        .filter_map(Result::ok)
+        // Skip the -MERGING- entries of packages being merged, and hidden ones
+        .filter(|entry| {
+            let name = entry.file_name();
+            !name.to_string_lossy().starts_with(['-', '.'])
+                && entry.file_type().is_ok_and(|kind| kind.is_dir())
+        })
+        .count();
+    Some(count as u32)
+}
+
+/// The version in `/usr/lib/portage/*/VERSION`.
+fn lib_portage_version() -> Option<Version> {
+    std::fs::read_dir("/usr/lib/portage")
+        .ok()?
+        .filter_map(|entry| {
+            let content = std::fs::read_to_string(entry.ok()?.path().join("VERSION")).ok()?;
+            parse_portage_version(&content)
+        })
+        .max()
+}
+
+/// What `portageq --version` says, which is slow (it's python) so it's tried last.
+fn portageq_version() -> Option<Version> {
+    let output = Command::new("portageq").arg("--version").output().ok()?;
+    parse_portage_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Look for the version of the installed portage.
+pub fn detect_portage_version() -> Option<Version> {
+    vdb_portage_version()
+        .or_else(lib_portage_version)
+        .or_else(portageq_version)
+}
+
+/// The version of portage, detected on the first call.
+pub fn portage_version() -> Option<&'static Version> {
+    static VERSION: OnceLock<Option<Version>> = OnceLock::new();
+    VERSION
+        .get_or_init(|| {
+            let version = detect_portage_version();
+            match &version {
+                Some(version) => tracing::debug!("Detected portage {version}"),
+                None => tracing::warn!("Couldn't find out the version of portage"),
+            }
+            version
+        })
+        .as_ref()
+}
+
+/// Whether the portage version predates 2.3, whose mtimedb needs the compatibility path.
+pub fn is_legacy_portage(version: Option<&Version>) -> bool {
+    version.is_some_and(|version| *version < Version::new(2, 3, 0))
+}
+
+/// Whether a process with this pid is still running, pid 0 is used for hooks that don't send
+/// their pid and is always considered alive.
+pub fn pid_alive(pid: u32) -> bool {
+    if pid == 0 {
+        return true;
+    }
+    match std::fs::read_to_string(format!("/proc/{pid}/status")) {
+        // A zombie is as dead as it gets, its parent just didn't reap it yet
+        Ok(status) => !status
+            .lines()
+            .filter_map(|line| line.strip_prefix("State:"))
+            .any(|state| state.trim_start().starts_with('Z')),
+        Err(_) => false,
+    }
+}
+
+/// The emerge options we care about.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct EmergeFlags {
+    /// `--jobs`/`-j` with a count, `None` if absent or unlimited.
+    pub jobs: Option<u32>,
+    /// `--usepkg`/`--usepkgonly`
+    pub use_binary: bool,
+    /// `--buildpkg`/`--buildpkgonly`
+    pub build_binary: bool,
+    /// `--ask`/`-a`
+    pub ask: bool,
+    /// `--pretend`/`-p`
+    pub pretend: bool,
+    /// `--resume`/`-r`
+    pub resume: bool,
+}
+
+impl EmergeFlags {
+    /// Parse the options out of the arguments of emerge, ignoring the ones we don't care about.
+    pub fn from_args<S: AsRef<str>>(args: impl IntoIterator<Item = S>) -> Self {
+        let mut flags = Self::default();
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next() {
+            let arg = arg.as_ref();
+            if let Some(long) = arg.strip_prefix("--") {
+                // Boolean options can be given as --opt=n / --opt n to disable them.
+                let (name, value) = match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_owned())),
+                    None => (long, None),
+                };
+                let enabled = value.as_deref() != Some("n");
+                match name {
+                    "jobs" => {
+                        let value = value.or_else(|| {
+                            let next = args.peek()?.as_ref();
+                            next.parse::<u32>().ok()?;
+                            Some(args.next()?.as_ref().to_owned())
+                        });
+                        flags.jobs = value.and_then(|v| v.parse().ok());
+                    }
+                    "usepkg" | "usepkgonly" => flags.use_binary = enabled,
+                    "buildpkg" | "buildpkgonly" => flags.build_binary = enabled,
+                    "ask" => flags.ask = enabled,
+                    "pretend" => flags.pretend = enabled,
+                    "resume" => flags.resume = enabled,
+                    _ => {}
+                }
+            } else if let Some(short) = arg.strip_prefix('-') {
+                let mut chars = short.char_indices();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        'j' => {
+                            let count: String = short[i + 1..]
+                                .chars()
+                                .take_while(char::is_ascii_digit)
+                                .collect();
+                            flags.jobs = count.parse().ok();
+                            chars.by_ref().take(count.len()).for_each(drop);
+                        }
+                        'k' | 'K' => flags.use_binary = true,
+                        'b' | 'B' => flags.build_binary = true,
+                        'a' => flags.ask = true,
+                        'p' => flags.pretend = true,
+                        'r' => flags.resume = true,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        flags
+    }
+
+    /// Short description for the presence, e.g. "4 jobs, using binary packages".
+    pub fn summary(&self) -> Option<String> {
+        let parts: Vec<String> = [
+            self.jobs.map(|jobs| format!("{jobs} jobs")),
+            self.use_binary.then(|| "using binary packages".to_owned()),
+            self.build_binary
+                .then(|| "building binary packages".to_owned()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+}
+
+/// Read the options of the emerge process `pid` from its command line, this is best effort: the
+/// process can be gone by the time we look.
+#[cfg(target_os = "linux")]
+pub fn parse_emerge_cmdline(pid: u32) -> EmergeFlags {
+    match std::fs::read(format!("/proc/{pid}/cmdline")) {
+        Ok(cmdline) => EmergeFlags::from_args(
+            cmdline
+                .split(|&b| b == 0)
+                .map(|arg| String::from_utf8_lossy(arg).into_owned()),
+        ),
+        Err(err) => {
+            tracing::debug!("Couldn't read command line of {pid} ({err})");
+            EmergeFlags::default()
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn parse_emerge_cmdline(_pid: u32) -> EmergeFlags {
+    EmergeFlags::default()
+}
+
+/// Phase of the package, as sent by the hooks.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(from = "String", into = "String")]
+pub enum PackageState {
+    Preparing,
+    Compiling,
+    Installing,
+    /// Set by the daemon for hooks that don't send a state during a resumed merge.
+    Resuming,
+    /// pkg_pretend, checking that the package can be merged.
+    Pretend,
+    /// pkg_nofetch, the sources have to be downloaded by hand.
+    Fetch,
+    /// The sources are being downloaded.
+    Fetching,
+    /// pkg_config, from `emerge --config`.
+    Configure,
+    /// pkg_info, from `emerge --info`.
+    Info,
+    /// src_test, with FEATURES=test.
+    Testing,
+    /// A phase we don't know about, shown as is.
+    Unknown(String),
+}
+
+impl PackageState {
+    /// The name the hooks send.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Preparing => "preparing",
+            Self::Compiling => "compiling",
+            Self::Installing => "installing",
+            Self::Resuming => "resuming",
+            Self::Pretend => "pretend",
+            Self::Fetch => "fetch",
+            Self::Fetching => "fetching",
+            Self::Configure => "configure",
+            Self::Info => "info",
+            Self::Testing => "test",
+            Self::Unknown(name) => name,
+        }
+    }
+
+    /// Key of the small image shown for this phase, before `assets_map` is applied.
+    pub fn asset_key(&self) -> Option<&'static str> {
+        Some(match self {
+            Self::Preparing => "phase_prepare",
+            Self::Compiling => "phase_compile",
+            Self::Installing => "phase_install",
+            Self::Resuming => "phase_resume",
+            Self::Pretend => "phase_pretend",
+            Self::Fetch => "phase_fetch",
+            Self::Fetching => "phase_fetching",
+            Self::Configure => "phase_config",
+            Self::Info => "phase_info",
+            Self::Testing => "phase_test",
+            Self::Unknown(_) => return None,
+        })
+    }
+}
+
+impl From<String> for PackageState {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "preparing" => Self::Preparing,
+            "compiling" => Self::Compiling,
+            "installing" => Self::Installing,
+            "resuming" => Self::Resuming,
+            "pretend" => Self::Pretend,
+            "fetch" => Self::Fetch,
+            "fetching" => Self::Fetching,
+            "configure" => Self::Configure,
+            "info" => Self::Info,
+            "test" | "testing" => Self::Testing,
+            _ => Self::Unknown(name),
+        }
+    }
+}
+
+impl From<PackageState> for String {
+    fn from(state: PackageState) -> Self {
+        match state {
+            PackageState::Unknown(name) => name,
+            state => state.name().to_owned(),
+        }
+    }
+}
+
+impl Display for PackageState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pretend => write!(f, "checking requirements"),
+            Self::Fetch => write!(f, "waiting for sources"),
+            Self::Configure => write!(f, "configuring"),
+            Self::Info => write!(f, "showing info"),
+            Self::Testing => write!(f, "running tests"),
+            state => write!(f, "{}", state.name()),
+        }
+    }
+}
+
+/// Build system of a package, guessed from the eclasses it inherits.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildSystem {
+    Autotools,
+    CMake,
+    Meson,
+    Cargo,
+    /// distutils-r1, setuptools and the other PEP 517 backends.
+    Python,
+    /// None of the above.
+    Other,
+}
+
+impl BuildSystem {
+    /// Name for humans.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Autotools => "Autotools",
+            Self::CMake => "CMake",
+            Self::Meson => "Meson",
+            Self::Cargo => "Cargo",
+            Self::Python => "Python",
+            Self::Other => "other",
+        }
+    }
+
+    /// Key of the small image shown instead of the phase one, before `assets_map` is applied.
+    pub fn asset_key(self) -> Option<&'static str> {
+        Some(match self {
+            Self::Autotools => "build_autotools",
+            Self::CMake => "build_cmake",
+            Self::Meson => "build_meson",
+            Self::Cargo => "build_cargo",
+            Self::Python => "build_python",
+            Self::Other => return None,
+        })
+    }
+}
+
+/// Guess the build system from a list of eclasses ($INHERITED), `None` if there are none. When
+/// several are inherited the one driving the build wins: meson or cmake over the cargo of the
+/// rust parts, and anything over autotools, which is often only there for eautoreconf.
+pub fn detect_build_system(eclasses: &str) -> Option<BuildSystem> {
+    let eclasses: Vec<&str> = eclasses.split_whitespace().collect();
+    if eclasses.is_empty() {
+        return None;
+    }
+    let inherits = |names: &[&str]| eclasses.iter().any(|eclass| names.contains(eclass));
+    let build_system = if inherits(&["meson", "meson-multilib"]) {
+        BuildSystem::Meson
+    } else if inherits(&["cmake", "cmake-multilib", "cmake-utils"]) {
+        BuildSystem::CMake
+    } else if inherits(&["distutils-r1"]) {
+        BuildSystem::Python
+    } else if inherits(&["cargo"]) {
+        BuildSystem::Cargo
+    } else if inherits(&["autotools"]) {
+        BuildSystem::Autotools
+    } else {
+        BuildSystem::Other
+    };
+    Some(build_system)
+}
+
+/// What an emerge is doing as a whole, sent with the set-operation command.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OperationType {
+    /// Merging packages, nothing special about it.
+    NormalMerge,
+    /// `emerge @preserved-rebuild`, relinking packages against upgraded libraries.
+    PreservedRebuild,
+    /// `emerge --update @world`
+    WorldUpdate,
+    /// `emerge --depclean`
+    #[serde(rename = "depclean")]
+    DepClean,
+    /// `emerge --sync`
+    Sync,
+}
+
+impl OperationType {
+    /// The details line while no package is shown.
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::NormalMerge => "Merging packages",
+            Self::PreservedRebuild => "Rebuilding preserved libraries",
+            Self::WorldUpdate => "Updating @world",
+            Self::DepClean => "Removing unneeded packages",
+            Self::Sync => "Syncing repositories",
+        }
+    }
+
+    /// Key of the large image to show instead of the default one, before `assets_map` is applied.
+    pub fn large_image(self) -> Option<&'static str> {
+        match self {
+            Self::PreservedRebuild => Some("gentoodrpgt_preserved_rebuild"),
+            Self::Sync => Some("gentoodrpgt_sync"),
+            Self::NormalMerge | Self::WorldUpdate | Self::DepClean => None,
+        }
+    }
+}
+
+/// A size in bytes for humans: `850 KB`, `12 MB`, `1.2 GB`.
+pub fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in ["KB", "MB", "GB", "TB"] {
+        if size < 1000.0 {
+            break;
+        }
+        size /= 1000.0;
+        unit = next;
+    }
+    if unit != "B" && size < 10.0 {
+        format!("{size:.1} {unit}")
+    } else {
+        format!("{size:.0} {unit}")
+    }
+}
+
+/// A duration for humans: `42s`, `3m 42s`, or `1h 23m` past an hour.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s}s"),
+        (h, m, _) => format!("{h}h {m}m"),
+    }
+}
+
+/// `name` (of a package or category) without the characters portage doesn't allow in them,
+/// keeping `[A-Za-z0-9+_.-]`: anything else could only come from a broken overlay or hook, and
+/// could be taken as markdown by discord.
+pub fn sanitize_package_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '_' | '.' | '-'))
+        .collect()
+}
+
+/// Whether `version` looks like a portage version (`1.2.3_p4`, with the revision for older
+/// hooks): `[0-9][0-9a-z._-]*`.
+fn is_valid_version(version: &str) -> bool {
+    let mut chars = version.chars();
+    chars.next().is_some_and(|c| c.is_ascii_digit())
+        && chars
+            .all(|c| c.is_ascii_digit() || c.is_ascii_lowercase() || matches!(c, '.' | '_' | '-'))
+}
+
+/// What the phases of binary packages are shown as.
+pub const BINARY_STATE: &str = "installing from binary";
+
+/// The package being merged, as sent by the hooks with the set command.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PackagePayload {
+    /// $CATEGORY
+    pub category: String,
+    /// $PN
+    pub package: String,
+    /// Version, without the revision ($PV). Older hooks send it with the revision ($PVR).
+    pub version: Option<String>,
+    /// Revision ($PR).
+    pub revision: Option<String>,
+    /// Enabled use flags ($USE).
+    pub use_flags: Option<Vec<String>>,
+    /// Phase the package is in.
+    pub state: Option<PackageState>,
+    /// Pid of the emerge process, used to tell parallel emerges apart.
+    pub pid: Option<u32>,
+    /// Upstream homepage(s) ($HOMEPAGE).
+    pub homepage: Option<String>,
+    /// Repository the ebuild comes from ($PORTAGE_REPO_NAME).
+    pub repo: Option<String>,
+    /// Size in bytes of the sources being fetched ($DISTFILE_SIZE, when portage sets it).
+    pub distfile_size: Option<u64>,
+    /// Number of packages in the merge, when the hook knows it. The mtimedb isn't read then.
+    pub total: Option<u32>,
+    /// Position of the package in the merge (from 1), when the hook knows it. The one derived from
+    /// the merge list is used otherwise.
+    pub position: Option<u32>,
+    /// The eclasses of the package ($INHERITED), see [`Self::build_system`]. Older hooks send
+    /// them as `build_system`.
+    #[serde(alias = "build_system")]
+    pub inherited: Option<String>,
+    /// $SLOT, possibly with the sub-slot (`3.11`, `0/3`).
+    pub slot: Option<String>,
+    /// $CFLAGS of the build, only logged and queried: they don't fit in the presence.
+    pub cflags: Option<String>,
+    /// $CXXFLAGS of the build, like `cflags`.
+    pub cxxflags: Option<String>,
+    /// How long the phase in `state` took, when the hook sends the set at the end of it.
+    pub elapsed_secs: Option<f64>,
+    /// Tests run so far by the test suite, when the hook can tell from its output.
+    pub test_count: Option<u32>,
+    /// Merged from a binary package rather than built ($MERGE_TYPE is `binary`).
+    #[serde(default)]
+    pub binary_package: bool,
+}
+
+impl PackagePayload {
+    /// The version followed by the revision, which is left out when it's r0 like portage does.
+    pub fn full_version(&self) -> Option<String> {
+        let version = self.version.as_deref().filter(|v| !v.is_empty())?;
+        match self.revision.as_deref() {
+            None | Some("" | "r0") => Some(version.to_owned()),
+            Some(revision) => Some(format!("{version}-{revision}")),
+        }
+    }
+
+    /// The build system, guessed from the eclasses.
+    pub fn build_system(&self) -> Option<BuildSystem> {
+        detect_build_system(self.inherited.as_deref()?)
+    }
+
+    /// The slot without the sub-slot, unless it's the default one (`0`).
+    pub fn slot(&self) -> Option<&str> {
+        let slot = self.slot.as_deref()?.split('/').next()?;
+        (!slot.is_empty() && slot != "0").then_some(slot)
+    }
+
+    /// Whether the package is being installed from a binary package: the phases of binary
+    /// packages are all about that, except for fetching it.
+    pub fn installing_binary(&self) -> bool {
+        self.binary_package
+            && matches!(
+                self.state,
+                Some(PackageState::Preparing | PackageState::Compiling | PackageState::Installing)
+            )
+    }
+
+    /// The phase as shown in the presence.
+    pub fn state_text(&self) -> Option<String> {
+        match &self.state {
+            Some(_) if self.installing_binary() => Some(BINARY_STATE.to_owned()),
+            state => state.as_ref().map(ToString::to_string),
+        }
+    }
+
+    /// Remove what doesn't belong in the names of the package, and drop a version or revision
+    /// that isn't one, so that only what portage could have sent ends up in the presence.
+    pub fn sanitize(&mut self) {
+        for name in [&mut self.category, &mut self.package] {
+            let sanitized = sanitize_package_name(name);
+            if sanitized != *name {
+                tracing::warn!("Invalid characters in {name:?}, shown as {sanitized:?}");
+                *name = sanitized;
+            }
+        }
+        if let Some(version) = self
+            .version
+            .take_if(|v| !v.is_empty() && !is_valid_version(v))
+        {
+            tracing::warn!("Invalid version {version:?}, leaving it out");
+        }
+        let is_revision = |r: &str| {
+            r.strip_prefix('r')
+                .is_some_and(|n| n.chars().all(|c| c.is_ascii_digit()))
+        };
+        if let Some(revision) = self.revision.take_if(|r| !r.is_empty() && !is_revision(r)) {
+            tracing::warn!("Invalid revision {revision:?}, leaving it out");
+        }
+    }
+
+    /// The repository of the package, unless it's the main tree.
+    pub fn overlay(&self) -> Option<&str> {
+        self.repo
+            .as_deref()
+            .filter(|repo| !repo.is_empty() && *repo != "gentoo")
+    }
+
+    /// Identifies the package across the phases of its merge: `category/package` and the version.
+    pub fn package_key(&self) -> (String, String) {
+        (
+            format!("{}/{}", self.category, self.package),
+            self.full_version().unwrap_or_default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn install(vdb: &Path, package: &str) {
+        std::fs::create_dir_all(vdb.join(package)).unwrap();
+    }
+
+    #[test]
+    fn sanitize() {
+        let mut payload = parse(
+            r#"{"category": "dev-*lang*", "package": "`rust`-bin", "version": "1.77.0_beta1", "revision": "r2"}"#,
+        )
+        .unwrap();
+        payload.sanitize();
+        assert_eq!(payload.category, "dev-lang");
+        assert_eq!(payload.package, "rust-bin");
+        assert_eq!(payload.full_version().as_deref(), Some("1.77.0_beta1-r2"));
+
+        for (version, revision) in [("**1.0**", "r1"), ("1.0", "r1`"), ("v1.0", "1")] {
+            payload.version = Some(version.to_owned());
+            payload.revision = Some(revision.to_owned());
+            payload.sanitize();
+            assert!(payload.version.is_none() || payload.revision.is_none());
+        }
+        // With the revision, from older hooks
+        payload.version = Some("9999-r1".to_owned());
+        payload.revision = None;
+        payload.sanitize();
+        assert_eq!(payload.version.as_deref(), Some("9999-r1"));
+    }
+
+    #[test]
+    fn cached_queries() {
+        let vdb = tempfile::tempdir().unwrap();
+        install(vdb.path(), "sys-devel/gcc-13.2.1_p20240113-r1");
+        install(vdb.path(), "sys-devel/gcc-config-2.11");
+        install(vdb.path(), "dev-lang/python-3.11.8_p1");
+        install(vdb.path(), "dev-lang/python-3.12.2_p1");
+        let mut portage = Portage::with_paths(vdb.path(), vdb.path().join("mtimedb"));
+
+        assert_eq!(portage.installed_count(), Some(4));
+        install(vdb.path(), "sys-devel/gcc-14.1.1_p20240622");
+        assert_eq!(portage.installed_count(), Some(4));
+        portage.forget_installed();
+        assert_eq!(portage.installed_count(), Some(5));
+    }
+
+    fn parse(json: &str) -> serde_json::Result<PackagePayload> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn known_states() {
+        for (name, expected) in [
+            ("preparing", PackageState::Preparing),
+            ("compiling", PackageState::Compiling),
+            ("installing", PackageState::Installing),
+            ("resuming", PackageState::Resuming),
+            ("pretend", PackageState::Pretend),
+            ("fetch", PackageState::Fetch),
+            ("fetching", PackageState::Fetching),
+            ("configure", PackageState::Configure),
+            ("info", PackageState::Info),
+            ("test", PackageState::Testing),
+            ("testing", PackageState::Testing),
+        ] {
+            let payload = parse(&format!(
+                r#"{{"category":"dev-libs","package":"openssl","state":"{name}"}}"#
+            ))
+            .unwrap();
+            let state = payload.state.unwrap();
+            assert_eq!(state.name(), expected.name());
+            assert!(!matches!(state, PackageState::Unknown(_)));
+        }
+    }
+
+    #[test]
+    fn unknown_state_is_kept() {
+        let payload = parse(r#"{"category":"a","package":"b","state":"postinst"}"#).unwrap();
+        assert!(matches!(payload.state, Some(PackageState::Unknown(name)) if name == "postinst"));
+    }
+
+    #[test]
+    fn missing_state() {
+        let payload = parse(r#"{"category":"dev-libs","package":"openssl","state":null}"#).unwrap();
+        assert!(payload.state.is_none());
+        let payload = parse(r#"{"category":"dev-libs","package":"openssl"}"#).unwrap();
+        assert!(payload.state.is_none());
+        assert!(payload.version.is_none());
+        assert!(payload.pid.is_none());
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let payload = parse(
+            r#"{"category":"dev-libs","package":"openssl","slot":"0/3","extra":{"nested":[1,2]}}"#,
+        )
+        .unwrap();
+        assert_eq!(payload.category, "dev-libs");
+        assert_eq!(payload.package, "openssl");
+    }
+
+    #[test]
+    fn missing_required_fields() {
+        let err = parse(r#"{"package":"openssl"}"#).err().unwrap();
+        assert!(
+            err.to_string().contains("missing field `category`"),
+            "{err}"
+        );
+        let err = parse(r#"{"category":"dev-libs"}"#).err().unwrap();
+        assert!(err.to_string().contains("missing field `package`"), "{err}");
+    }
+
+    #[test]
+    fn unicode_names() {
+        let payload =
+            parse(r#"{"category":"app-i18n","package":"日本語-ïnput","version":"1.0ß"}"#).unwrap();
+        assert_eq!(payload.package, "日本語-ïnput");
+        assert_eq!(payload.full_version().as_deref(), Some("1.0ß"));
+    }
+
+    #[test]
+    fn dead_and_zombie_processes() {
+        assert!(pid_alive(0));
+        assert!(pid_alive(std::process::id()));
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        // Not reaped yet, so it stays around as a zombie (with its /proc entry) once it exited,
+        // dead only if zombies count as such
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pid_alive(child.id()) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!pid_alive(child.id()));
+        child.wait().unwrap();
+        assert!(!pid_alive(child.id()));
+    }
+
+    #[test]
+    fn portage_versions() {
+        assert_eq!(
+            parse_portage_version("3.0.49"),
+            Some(Version::new(3, 0, 49))
+        );
+        assert_eq!(
+            parse_portage_version("2.3.100-r1"),
+            Some(Version::new(2, 3, 100))
+        );
+        assert_eq!(
+            parse_portage_version(
+                "Portage 3.0.49 (python 3.11.4-final-0, default/linux/amd64/17.1)"
+            ),
+            Some(Version::new(3, 0, 49))
+        );
+        assert_eq!(parse_portage_version("2.2\n"), Some(Version::new(2, 2, 0)));
+        assert_eq!(parse_portage_version("portage"), None);
+        assert!(is_legacy_portage(Some(&Version::new(2, 2, 28))));
+        assert!(!is_legacy_portage(Some(&Version::new(3, 0, 49))));
+        assert!(!is_legacy_portage(None));
+    }
+
+    #[test]
+    fn operations() {
+        let parse = |name: &str| serde_json::from_value::<OperationType>(serde_json::json!(name));
+        assert_eq!(
+            parse("preserved-rebuild").unwrap(),
+            OperationType::PreservedRebuild
+        );
+        assert_eq!(parse("depclean").unwrap(), OperationType::DepClean);
+        assert_eq!(parse("world-update").unwrap(), OperationType::WorldUpdate);
+        assert!(parse("preserved_rebuild").is_err());
+    }
+
+    #[test]
+    fn build_systems() {
+        let detect = detect_build_system;
+        assert_eq!(
+            detect("toolchain-funcs meson xdg"),
+            Some(BuildSystem::Meson)
+        );
+        assert_eq!(detect("cargo meson"), Some(BuildSystem::Meson));
+        assert_eq!(detect("python-r1 cmake"), Some(BuildSystem::CMake));
+        assert_eq!(detect("distutils-r1 cargo"), Some(BuildSystem::Python));
+        assert_eq!(detect("autotools libtool"), Some(BuildSystem::Autotools));
+        assert_eq!(detect("flag-o-matic"), Some(BuildSystem::Other));
+        assert_eq!(detect("  "), None);
+
+        let payload =
+            parse(r#"{"category": "a", "package": "b", "inherited": "cargo git-r3"}"#).unwrap();
+        assert_eq!(payload.build_system(), Some(BuildSystem::Cargo));
+        // What we serialize reads back the same
+        let json = serde_json::to_string(&payload).unwrap();
+        assert_eq!(
+            parse(&json).unwrap().build_system(),
+            Some(BuildSystem::Cargo)
+        );
+        let old = r#"{"category": "a", "package": "b", "build_system": "cmake"}"#;
+        assert_eq!(parse(old).unwrap().build_system(), Some(BuildSystem::CMake));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(850_000), "850 KB");
+        assert_eq!(format_size(12_345_678), "12 MB");
+        assert_eq!(format_size(1_234_567_890), "1.2 GB");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(222)), "3m 42s");
+        assert_eq!(format_duration(Duration::from_secs(4980)), "1h 23m");
+    }
+
+    #[test]
+    fn slots() {
+        let slot = |slot: &str| {
+            let payload = parse(&format!(
+                r#"{{"category":"dev-lang","package":"python","slot":"{slot}"}}"#
+            ))
+            .unwrap();
+            payload.slot().map(str::to_owned)
+        };
+        assert_eq!(slot("3.11").as_deref(), Some("3.11"));
+        assert_eq!(slot("17/17"), Some("17".to_owned()));
+        assert_eq!(slot("0"), None);
+        assert_eq!(slot("0/3"), None);
+        assert_eq!(slot(""), None);
+    }
+
+    #[test]
+    fn full_version() {
+        let payload =
+            parse(r#"{"category":"a","package":"b","version":"3.0.7","revision":"r1"}"#).unwrap();
+        assert_eq!(payload.full_version().as_deref(), Some("3.0.7-r1"));
+        let payload =
+            parse(r#"{"category":"a","package":"b","version":"3.0.7","revision":"r0"}"#).unwrap();
+        assert_eq!(payload.full_version().as_deref(), Some("3.0.7"));
+    }
+}
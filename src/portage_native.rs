@@ -0,0 +1,600 @@
+//! Just enough of python's pickle format to read old mtimedbs without python, with the
+//! `portage-native` feature: the opcodes of protocols 0 to 5
+//! that build plain data (dicts, lists, tuples, strings, numbers). Anything that would need to
+//! call into python (globals, reduce, ...) is an error.
+
+use std::collections::HashMap;
+
+/// A decoded object, containers hold indices into the arena of [`Pickle`] so that memoized
+/// objects can be mutated after being shared, like python does.
+#[derive(Debug, Clone)]
+pub enum Value {
+    /// None, booleans, numbers and bytes, which nothing looks at.
+    Scalar,
+    Str(String),
+    List(Vec<usize>),
+    Tuple(Vec<usize>),
+    Dict(Vec<(usize, usize)>),
+}
+
+/// The result of unpickling: every object created, and the one that was returned.
+pub struct Pickle {
+    arena: Vec<Value>,
+    root: usize,
+}
+
+/// A value of a [`Pickle`], to walk the object graph.
+#[derive(Clone, Copy)]
+pub struct Object<'a> {
+    pickle: &'a Pickle,
+    index: usize,
+}
+
+impl Pickle {
+    pub fn root(&self) -> Object<'_> {
+        Object {
+            pickle: self,
+            index: self.root,
+        }
+    }
+}
+
+impl<'a> Object<'a> {
+    pub fn value(&self) -> &'a Value {
+        &self.pickle.arena[self.index]
+    }
+
+    fn at(&self, index: usize) -> Object<'a> {
+        Object {
+            pickle: self.pickle,
+            index,
+        }
+    }
+
+    /// Look up a string key, if this is a dict.
+    pub fn get(&self, key: &str) -> Option<Object<'a>> {
+        let Value::Dict(items) = self.value() else {
+            return None;
+        };
+        items
+            .iter()
+            .find(|&&(k, _)| matches!(self.at(k).value(), Value::Str(s) if s == key))
+            .map(|&(_, v)| self.at(v))
+    }
+
+    /// Whether this is a dict with the string key, or a list or tuple with the string element.
+    pub fn contains(&self, key: &str) -> bool {
+        let is_key = |index: usize| matches!(self.at(index).value(), Value::Str(s) if s == key);
+        match self.value() {
+            Value::List(items) | Value::Tuple(items) => items.iter().any(|&i| is_key(i)),
+            Value::Dict(items) => items.iter().any(|&(k, _)| is_key(k)),
+            _ => false,
+        }
+    }
+
+    /// Number of elements of a list, tuple or dict.
+    pub fn len(&self) -> Option<usize> {
+        match self.value() {
+            Value::List(items) | Value::Tuple(items) => Some(items.len()),
+            Value::Dict(items) => Some(items.len()),
+            _ => None,
+        }
+    }
+}
+
+enum Slot {
+    Mark,
+    Object(usize),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or("Unexpected end of pickle")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uint(&mut self, len: usize) -> Result<u64, String> {
+        let mut buf = [0u8; 8];
+        buf[..len].copy_from_slice(self.take(len)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn size(&mut self, len: usize) -> Result<usize, String> {
+        usize::try_from(self.uint(len)?).map_err(|_| "Length too big".to_owned())
+    }
+
+    /// The argument of the protocol 0 opcodes, up to the newline.
+    fn raw_line(&mut self) -> Result<&'a [u8], String> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or("Unterminated line in pickle")?;
+        self.pos += len + 1;
+        Ok(&rest[..len])
+    }
+
+    fn line(&mut self) -> Result<&'a str, String> {
+        std::str::from_utf8(self.raw_line()?).map_err(|err| err.to_string())
+    }
+}
+
+struct Machine {
+    arena: Vec<Value>,
+    stack: Vec<Slot>,
+    memo: HashMap<u64, usize>,
+}
+
+impl Machine {
+    fn push(&mut self, value: Value) {
+        self.arena.push(value);
+        self.stack.push(Slot::Object(self.arena.len() - 1));
+    }
+
+    fn pop(&mut self) -> Result<usize, String> {
+        match self.stack.pop() {
+            Some(Slot::Object(index)) => Ok(index),
+            Some(Slot::Mark) => Err("Unexpected mark".to_owned()),
+            None => Err("Stack underflow".to_owned()),
+        }
+    }
+
+    fn top(&self) -> Result<usize, String> {
+        match self.stack.last() {
+            Some(Slot::Object(index)) => Ok(*index),
+            _ => Err("Expected an object on the stack".to_owned()),
+        }
+    }
+
+    /// Pop everything down to the last mark (included), returns the objects in order.
+    fn pop_mark(&mut self) -> Result<Vec<usize>, String> {
+        let mark = self
+            .stack
+            .iter()
+            .rposition(|slot| matches!(slot, Slot::Mark))
+            .ok_or("No mark on the stack")?;
+        let items = self
+            .stack
+            .drain(mark + 1..)
+            .map(|slot| match slot {
+                Slot::Object(index) => index,
+                Slot::Mark => unreachable!(),
+            })
+            .collect();
+        self.stack.pop();
+        Ok(items)
+    }
+
+    fn extend(&mut self, target: usize, items: Vec<usize>) -> Result<(), String> {
+        match &mut self.arena[target] {
+            Value::List(list) => {
+                list.extend(items);
+                Ok(())
+            }
+            Value::Dict(dict) => {
+                if !items.len().is_multiple_of(2) {
+                    return Err("Odd number of items for a dict".to_owned());
+                }
+                dict.extend(items.chunks(2).map(|pair| (pair[0], pair[1])));
+                Ok(())
+            }
+            _ => Err("Adding items to something that isn't a list or dict".to_owned()),
+        }
+    }
+
+    fn memoize(&mut self, id: u64) -> Result<(), String> {
+        let top = self.top()?;
+        self.memo.insert(id, top);
+        Ok(())
+    }
+
+    fn get(&mut self, id: u64) -> Result<(), String> {
+        let index = *self.memo.get(&id).ok_or("Unknown memo id")?;
+        self.stack.push(Slot::Object(index));
+        Ok(())
+    }
+}
+
+/// Decode python's raw-unicode-escape: latin-1, except for `\uXXXX` and `\UXXXXXXXX`.
+fn raw_unicode_escape(bytes: &[u8]) -> Result<String, String> {
+    let mut out = String::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let len = match bytes[i..] {
+            [b'\\', b'u', ..] => 4,
+            [b'\\', b'U', ..] => 8,
+            _ => {
+                out.push(bytes[i] as char);
+                i += 1;
+                continue;
+            }
+        };
+        let hex = bytes
+            .get(i + 2..i + 2 + len)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .ok_or("Truncated unicode escape")?;
+        let c = u32::from_str_radix(hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or("Invalid unicode escape")?;
+        out.push(c);
+        i += 2 + len;
+    }
+    Ok(out)
+}
+
+/// Protocol 0 strings are python literals, only the common escapes are handled.
+fn unquote(literal: &str) -> Result<String, String> {
+    let inner = literal
+        .strip_prefix('\'')
+        .and_then(|s| s.strip_suffix('\''))
+        .or_else(|| literal.strip_prefix('"').and_then(|s| s.strip_suffix('"')))
+        .ok_or("Badly quoted string")?;
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16).map_err(|err| err.to_string())?;
+                out.push(byte as char);
+            }
+            Some(c) => out.push(c),
+            None => return Err("Trailing backslash".to_owned()),
+        }
+    }
+    Ok(out)
+}
+
+fn utf8(bytes: &[u8]) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|err| err.to_string())
+}
+
+pub fn parse(data: &[u8]) -> Result<Pickle, String> {
+    let mut reader = Reader { data, pos: 0 };
+    let mut vm = Machine {
+        arena: Vec::new(),
+        stack: Vec::new(),
+        memo: HashMap::new(),
+    };
+    loop {
+        let opcode = reader.byte()?;
+        match opcode {
+            // PROTO
+            0x80 => {
+                reader.byte()?;
+            }
+            // FRAME
+            0x95 => {
+                reader.take(8)?;
+            }
+            // STOP
+            b'.' => {
+                let root = vm.pop()?;
+                return Ok(Pickle {
+                    arena: vm.arena,
+                    root,
+                });
+            }
+            b'(' => vm.stack.push(Slot::Mark),
+            b'0' => {
+                vm.stack.pop().ok_or("Stack underflow")?;
+            }
+            b'1' => {
+                vm.pop_mark()?;
+            }
+            b'2' => {
+                let top = vm.top()?;
+                vm.stack.push(Slot::Object(top));
+            }
+            b'N' => vm.push(Value::Scalar),
+            0x88 => vm.push(Value::Scalar),
+            0x89 => vm.push(Value::Scalar),
+            // BININT, BININT1, BININT2
+            b'J' | b'K' | b'M' => {
+                let len = match opcode {
+                    b'J' => 4,
+                    b'K' => 1,
+                    _ => 2,
+                };
+                reader.take(len)?;
+                vm.push(Value::Scalar);
+            }
+            // LONG1, LONG4
+            0x8a | 0x8b => {
+                let len = reader.size(if opcode == 0x8a { 1 } else { 4 })?;
+                reader.take(len)?;
+                vm.push(Value::Scalar);
+            }
+            // INT, LONG, FLOAT
+            b'I' | b'L' | b'F' => {
+                reader.line()?;
+                vm.push(Value::Scalar);
+            }
+            // BINFLOAT
+            b'G' => {
+                reader.take(8)?;
+                vm.push(Value::Scalar);
+            }
+            // STRING, BINSTRING, SHORT_BINSTRING: python 2 str, treated as text
+            b'S' => {
+                let line = reader.line()?;
+                vm.push(Value::Str(unquote(line)?));
+            }
+            b'T' => {
+                let len = reader.size(4)?;
+                vm.push(Value::Str(utf8(reader.take(len)?)?));
+            }
+            b'U' => {
+                let len = reader.size(1)?;
+                vm.push(Value::Str(utf8(reader.take(len)?)?));
+            }
+            // UNICODE
+            b'V' => {
+                let line = reader.raw_line()?;
+                vm.push(Value::Str(raw_unicode_escape(line)?));
+            }
+            // BINUNICODE, SHORT_BINUNICODE, BINUNICODE8
+            b'X' | 0x8c | 0x8d => {
+                let len = match opcode {
+                    b'X' => reader.size(4)?,
+                    0x8c => reader.size(1)?,
+                    _ => reader.size(8)?,
+                };
+                vm.push(Value::Str(utf8(reader.take(len)?)?));
+            }
+            // BINBYTES, SHORT_BINBYTES, BINBYTES8
+            b'B' | b'C' | 0x8e => {
+                let len = match opcode {
+                    b'B' => reader.size(4)?,
+                    b'C' => reader.size(1)?,
+                    _ => reader.size(8)?,
+                };
+                reader.take(len)?;
+                vm.push(Value::Scalar);
+            }
+            b']' => vm.push(Value::List(Vec::new())),
+            b'}' => vm.push(Value::Dict(Vec::new())),
+            b')' => vm.push(Value::Tuple(Vec::new())),
+            b'l' => {
+                let items = vm.pop_mark()?;
+                vm.push(Value::List(items));
+            }
+            b't' => {
+                let items = vm.pop_mark()?;
+                vm.push(Value::Tuple(items));
+            }
+            b'd' => {
+                let items = vm.pop_mark()?;
+                vm.push(Value::Dict(Vec::new()));
+                let dict = vm.top()?;
+                vm.extend(dict, items)?;
+            }
+            // TUPLE1, TUPLE2, TUPLE3
+            0x85..=0x87 => {
+                let len = (opcode - 0x84) as usize;
+                let mut items = (0..len).map(|_| vm.pop()).collect::<Result<Vec<_>, _>>()?;
+                items.reverse();
+                vm.push(Value::Tuple(items));
+            }
+            // APPEND, SETITEM
+            b'a' | b's' => {
+                let count = if opcode == b'a' { 1 } else { 2 };
+                let mut items = (0..count)
+                    .map(|_| vm.pop())
+                    .collect::<Result<Vec<_>, _>>()?;
+                items.reverse();
+                let target = vm.top()?;
+                vm.extend(target, items)?;
+            }
+            // APPENDS, SETITEMS
+            b'e' | b'u' => {
+                let items = vm.pop_mark()?;
+                let target = vm.top()?;
+                vm.extend(target, items)?;
+            }
+            // PUT, BINPUT, LONG_BINPUT, MEMOIZE
+            b'p' => {
+                let id = reader.line()?.parse().map_err(|_| "Invalid memo id")?;
+                vm.memoize(id)?;
+            }
+            b'q' => {
+                let id = reader.uint(1)?;
+                vm.memoize(id)?;
+            }
+            b'r' => {
+                let id = reader.uint(4)?;
+                vm.memoize(id)?;
+            }
+            0x94 => {
+                let id = vm.memo.len() as u64;
+                vm.memoize(id)?;
+            }
+            // GET, BINGET, LONG_BINGET
+            b'g' => {
+                let id = reader.line()?.parse().map_err(|_| "Invalid memo id")?;
+                vm.get(id)?;
+            }
+            b'h' => {
+                let id = reader.uint(1)?;
+                vm.get(id)?;
+            }
+            b'j' => {
+                let id = reader.uint(4)?;
+                vm.get(id)?;
+            }
+            _ => return Err(format!("Unsupported pickle opcode {opcode:#04x}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `{"resume": {"mergelist": [entry, entry]}, "x": (1, 2.5, None, True, 2**70, -3, 300,
+    /// 70000), "u": "é€😀", "t1": (1,), "e": []}` with `entry = ["ebuild", "/", "dev-libs/a-1",
+    /// "merge"]`, pickled by python 3 with protocols 0 to 5.
+    const PICKLES: [&[u8]; 6] = [
+        b"(dp0\nVresume\np1\n(dp2\nVmergelist\np3\n(lp4\n(lp5\nVebuild\np6\naV/\np7\naVdev-libs/a\
+        -1\np8\naVmerge\np9\naag5\nassVx\np10\n(I1\nF2.5\nNI01\nL1180591620717411303424L\nI-3\nI3\
+        00\nI70000\ntp11\nsVu\np12\nV\xe9\\u20ac\\U0001f600\np13\nsVt1\np14\n(I1\ntp15\nsVe\np16\
+        \n(lp17\ns.",
+        b"}q\x00(X\x06\x00\x00\x00resumeq\x01}q\x02X\x09\x00\x00\x00mergelistq\x03]q\x04(]q\x05(X\
+        \x06\x00\x00\x00ebuildq\x06X\x01\x00\x00\x00/q\x07X\x0c\x00\x00\x00dev-libs/a-1q\x08X\x05\
+        \x00\x00\x00mergeq\x09eh\x05esX\x01\x00\x00\x00xq\n(K\x01G@\x04\x00\x00\x00\x00\x00\x00NI\
+        01\nL1180591620717411303424L\nJ\xfd\xff\xff\xffM,\x01Jp\x11\x01\x00tq\x0bX\x01\x00\x00\
+        \x00uq\x0cX\x09\x00\x00\x00\xc3\xa9\xe2\x82\xac\xf0\x9f\x98\x80q\x0dX\x02\x00\x00\x00t1q\
+        \x0e(K\x01tq\x0fX\x01\x00\x00\x00eq\x10]q\x11u.",
+        b"\x80\x02}q\x00(X\x06\x00\x00\x00resumeq\x01}q\x02X\x09\x00\x00\x00mergelistq\x03]q\x04(\
+        ]q\x05(X\x06\x00\x00\x00ebuildq\x06X\x01\x00\x00\x00/q\x07X\x0c\x00\x00\x00dev-libs/a-1q\
+        \x08X\x05\x00\x00\x00mergeq\x09eh\x05esX\x01\x00\x00\x00xq\n(K\x01G@\x04\x00\x00\x00\x00\
+        \x00\x00N\x88\x8a\x09\x00\x00\x00\x00\x00\x00\x00\x00@J\xfd\xff\xff\xffM,\x01Jp\x11\x01\
+        \x00tq\x0bX\x01\x00\x00\x00uq\x0cX\x09\x00\x00\x00\xc3\xa9\xe2\x82\xac\xf0\x9f\x98\x80q\
+        \x0dX\x02\x00\x00\x00t1q\x0eK\x01\x85q\x0fX\x01\x00\x00\x00eq\x10]q\x11u.",
+        b"\x80\x03}q\x00(X\x06\x00\x00\x00resumeq\x01}q\x02X\x09\x00\x00\x00mergelistq\x03]q\x04(\
+        ]q\x05(X\x06\x00\x00\x00ebuildq\x06X\x01\x00\x00\x00/q\x07X\x0c\x00\x00\x00dev-libs/a-1q\
+        \x08X\x05\x00\x00\x00mergeq\x09eh\x05esX\x01\x00\x00\x00xq\n(K\x01G@\x04\x00\x00\x00\x00\
+        \x00\x00N\x88\x8a\x09\x00\x00\x00\x00\x00\x00\x00\x00@J\xfd\xff\xff\xffM,\x01Jp\x11\x01\
+        \x00tq\x0bX\x01\x00\x00\x00uq\x0cX\x09\x00\x00\x00\xc3\xa9\xe2\x82\xac\xf0\x9f\x98\x80q\
+        \x0dX\x02\x00\x00\x00t1q\x0eK\x01\x85q\x0fX\x01\x00\x00\x00eq\x10]q\x11u.",
+        b"\x80\x04\x95\x96\x00\x00\x00\x00\x00\x00\x00}\x94(\x8c\x06resume\x94}\x94\x8c\x09mergel\
+        ist\x94]\x94(]\x94(\x8c\x06ebuild\x94\x8c\x01/\x94\x8c\x0cdev-libs/a-1\x94\x8c\x05merge\
+        \x94eh\x05es\x8c\x01x\x94(K\x01G@\x04\x00\x00\x00\x00\x00\x00N\x88\x8a\x09\x00\x00\x00\
+        \x00\x00\x00\x00\x00@J\xfd\xff\xff\xffM,\x01Jp\x11\x01\x00t\x94\x8c\x01u\x94\x8c\x09\xc3\
+        \xa9\xe2\x82\xac\xf0\x9f\x98\x80\x94\x8c\x02t1\x94K\x01\x85\x94\x8c\x01e\x94]\x94u.",
+        b"\x80\x05\x95\x96\x00\x00\x00\x00\x00\x00\x00}\x94(\x8c\x06resume\x94}\x94\x8c\x09mergel\
+        ist\x94]\x94(]\x94(\x8c\x06ebuild\x94\x8c\x01/\x94\x8c\x0cdev-libs/a-1\x94\x8c\x05merge\
+        \x94eh\x05es\x8c\x01x\x94(K\x01G@\x04\x00\x00\x00\x00\x00\x00N\x88\x8a\x09\x00\x00\x00\
+        \x00\x00\x00\x00\x00@J\xfd\xff\xff\xffM,\x01Jp\x11\x01\x00t\x94\x8c\x01u\x94\x8c\x09\xc3\
+        \xa9\xe2\x82\xac\xf0\x9f\x98\x80\x94\x8c\x02t1\x94K\x01\x85\x94\x8c\x01e\x94]\x94u.",
+    ];
+
+    /// `{"k": entry, "f": entry}` with `entry = ["it's\n", 1, 2, 1.5]`, as pickled by python 2
+    /// (protocol 0 str, one memo per object).
+    const PYTHON2: &[u8] =
+        b"(dp0\nS'k'\np1\n(lp2\nS'it\\'s\\n'\np3\naI1\naL2L\naF1.5\nasS'f'\np4\ng2\ns.";
+    /// `{"k": "abc", "l": [1, 256, 16777216]}` with protocol 2 python 2 strs.
+    const PYTHON2_BINARY: &[u8] =
+        b"\x80\x02}q\x00(U\x01kq\x01T\x03\x00\x00\x00abcq\x02U\x01lq\x03]q\x04(K\x01M\x00\x01J\x00\x00\x00\x01eu.";
+
+    fn is_str(object: Option<Object<'_>>, expected: &str) -> bool {
+        matches!(object.map(|object| object.value()), Some(Value::Str(s)) if s == expected)
+    }
+
+    #[test]
+    fn protocols() {
+        for (protocol, data) in PICKLES.iter().enumerate() {
+            let pickle = parse(data).unwrap_or_else(|err| panic!("protocol {protocol}: {err}"));
+            let root = pickle.root();
+            assert_eq!(root.len(), Some(5), "protocol {protocol}");
+            assert!(root.contains("resume"));
+            assert!(root.get("missing").is_none());
+
+            let mergelist = root
+                .get("resume")
+                .and_then(|resume| resume.get("mergelist"));
+            let mergelist = mergelist.unwrap();
+            let Value::List(entries) = mergelist.value() else {
+                panic!("protocol {protocol}: the merge list isn't a list");
+            };
+            // The second entry is the first one, from the memo
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0], entries[1]);
+            let entry = mergelist.at(entries[0]);
+            assert_eq!(entry.len(), Some(4));
+            assert!(entry.contains("dev-libs/a-1"));
+
+            assert!(
+                matches!(root.get("x").unwrap().value(), Value::Tuple(items) if items.len() == 8)
+            );
+            assert!(is_str(root.get("u"), "é€😀"), "protocol {protocol}");
+            assert!(
+                matches!(root.get("t1").unwrap().value(), Value::Tuple(items) if items.len() == 1)
+            );
+            assert_eq!(root.get("e").unwrap().len(), Some(0));
+        }
+    }
+
+    #[test]
+    fn python2_strings() {
+        let pickle = parse(PYTHON2).unwrap();
+        let root = pickle.root();
+        let entry = root.get("k").unwrap();
+        assert_eq!(entry.len(), Some(4));
+        assert!(entry.contains("it's\n"));
+        assert_eq!(root.get("f").unwrap().index, entry.index);
+
+        let pickle = parse(PYTHON2_BINARY).unwrap();
+        assert!(is_str(pickle.root().get("k"), "abc"));
+        assert_eq!(pickle.root().get("l").unwrap().len(), Some(3));
+    }
+
+    #[test]
+    fn scalars_and_stack() {
+        let pickle = parse(b"\x80\x03}q\x00X\x01\x00\x00\x00bq\x01C\x03rawq\x02s.").unwrap();
+        assert!(matches!(
+            pickle.root().get("b").unwrap().value(),
+            Value::Scalar
+        ));
+        // LONG4
+        assert!(matches!(
+            parse(b"\x8b\x01\x00\x00\x00\x05.").unwrap().root().value(),
+            Value::Scalar
+        ));
+        // MARK POP_MARK, then DUP and POP
+        assert!(matches!(
+            parse(b"(K\x011K\x0220.").unwrap().root().value(),
+            Value::Scalar
+        ));
+        assert!(parse(b"0.").is_err());
+        assert!(parse(b"1.").is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        for data in PICKLES.iter().chain([&PYTHON2, &PYTHON2_BINARY]) {
+            for len in 0..data.len() {
+                assert!(parse(&data[..len]).is_err(), "{:?}", &data[..len]);
+            }
+        }
+        // Lengths past the end
+        assert!(parse(b"\x8c\xff").is_err());
+        assert!(parse(b"X\xff\xff\xff\xffabc.").is_err());
+    }
+
+    #[test]
+    fn unsupported_opcodes() {
+        // set([]) through GLOBAL and REDUCE, then set([1]) through protocol 4 EMPTY_SET
+        let err = parse(b"c__builtin__\nset\np0\n((lp1\ntp2\nRp3\n.")
+            .err()
+            .unwrap();
+        assert!(err.contains("0x63"), "{err}");
+        let err = parse(b"\x80\x04\x95\x07\x00\x00\x00\x00\x00\x00\x00\x8f\x94(K\x01\x90.")
+            .err()
+            .unwrap();
+        assert!(err.contains("0x8f"), "{err}");
+    }
+}
@@ -0,0 +1,66 @@
+//! Cpu profiling for `--profile`, sampling the whole run with pprof when built with the `profile`
+//! feature. A flamegraph of everything sampled so far is written on SIGUSR2.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+/// Where SIGUSR2 writes the flamegraph.
+pub const FLAMEGRAPH_PATH: &str = "/tmp/emerge-presence-flamegraph.svg";
+
+/// Samples the stacks of the daemon until dropped.
+pub struct Profiler {
+    #[cfg(feature = "profile")]
+    guard: pprof::ProfilerGuard<'static>,
+}
+
+impl Profiler {
+    /// Start sampling, `None` if that isn't possible (or without the profile feature).
+    pub fn start() -> Option<Self> {
+        #[cfg(feature = "profile")]
+        {
+            let builder = pprof::ProfilerGuardBuilder::default().frequency(100);
+            // Unwinding through these can crash the profiler, pprof only supports skipping them
+            // on 64-bit arches.
+            #[cfg(any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64",
+                target_arch = "loongarch64"
+            ))]
+            let builder = builder.blocklist(&["libc", "libgcc", "pthread", "vdso"]);
+            let guard = builder.build();
+            match guard {
+                Ok(guard) => Some(Self { guard }),
+                Err(err) => {
+                    tracing::warn!("Couldn't start the profiler ({err})");
+                    None
+                }
+            }
+        }
+        #[cfg(not(feature = "profile"))]
+        {
+            tracing::warn!("emerge-presence was built without the profile feature, not profiling");
+            None
+        }
+    }
+
+    /// Write the flamegraph of what was sampled since the start to `path`.
+    #[cfg(feature = "profile")]
+    pub fn dump(&self, path: &Path) -> Result<()> {
+        let report = self.guard.report().build()?;
+        // An idle daemon doesn't get sampled, which would make an empty file
+        if report.data.is_empty() {
+            anyhow::bail!("Nothing was sampled yet");
+        }
+        let mut svg = Vec::new();
+        report.flamegraph(&mut svg)?;
+        crate::write_atomic(path, &svg)?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "profile"))]
+    pub fn dump(&self, _path: &Path) -> Result<()> {
+        anyhow::bail!("emerge-presence was built without the profile feature")
+    }
+}
@@ -0,0 +1,253 @@
+//! The emerges the daemon keeps track of.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::{
+    portage::{self, EmergeFlags, OperationType, PackagePayload, PackageState},
+    unix_secs,
+};
+
+/// State of a single emerge invocation, kept apart from the connection state of the client.
+pub struct MergeSession {
+    /// Merge list length at the last set, the number of packages left.
+    pub(crate) merge_len: u32,
+    /// Biggest merge list length seen during this session, which is the total number of packages.
+    pub(crate) total_packages: u32,
+    pub(crate) started_at: Instant,
+    /// `None` for the legacy session of hooks that don't send a pid.
+    pub(crate) emerge_pid: Option<u32>,
+    pub(crate) current_package: Option<PackagePayload>,
+    pub(crate) flags: EmergeFlags,
+    pub(crate) last_update: Instant,
+    /// When the last unset was received, cleared by any following set.
+    pub(crate) unset_at: Option<Instant>,
+    /// Set when the current package failed to merge, cleared by any following set.
+    pub(crate) failure: Option<Failure>,
+    /// When the first set of each package was received, keyed by `package_key`, so that phase
+    /// changes don't reset the elapsed time shown by discord.
+    pub(crate) package_start_times: HashMap<(String, String), SystemTime>,
+    /// The emerge is resuming an interrupted merge.
+    pub(crate) is_resume: bool,
+    /// What the emerge is doing, from set-operation. Kept across the packages of the session.
+    pub(crate) operation: Option<OperationType>,
+    /// Packages that finished merging.
+    pub(crate) merged: u32,
+    /// Packages that failed to merge (more than one with --keep-going).
+    pub(crate) failed: u32,
+    /// The package that took the longest to merge, as `category/package`.
+    pub(crate) longest_build: Option<(String, Duration)>,
+    /// How long the phases of the current package took, as timed by the hooks.
+    pub(crate) phase_durations: HashMap<PackageState, Duration>,
+}
+
+pub(crate) struct Failure {
+    pub(crate) reason: Option<String>,
+    pub(crate) at: Instant,
+}
+
+impl MergeSession {
+    pub(crate) fn new(emerge_pid: Option<u32>) -> Self {
+        Self {
+            merge_len: 0,
+            total_packages: 0,
+            started_at: Instant::now(),
+            emerge_pid,
+            current_package: None,
+            flags: EmergeFlags::default(),
+            last_update: Instant::now(),
+            unset_at: None,
+            failure: None,
+            package_start_times: HashMap::new(),
+            is_resume: false,
+            operation: None,
+            merged: 0,
+            failed: 0,
+            longest_build: None,
+            phase_durations: HashMap::new(),
+        }
+    }
+
+    /// Count a package that finished merging after `duration`.
+    pub(crate) fn record_merged(&mut self, package: String, duration: Duration) {
+        self.merged += 1;
+        if self
+            .longest_build
+            .as_ref()
+            .is_none_or(|(_, longest)| duration > *longest)
+        {
+            self.longest_build = Some((package, duration));
+        }
+    }
+
+    /// What the emerge did, for once it's done.
+    pub fn summary(&self) -> CompletionSummary {
+        CompletionSummary {
+            merged: self.merged,
+            failed: self.failed,
+            elapsed: self.started_at.elapsed(),
+            longest_build: self.longest_build.clone(),
+        }
+    }
+
+    /// When the package currently shown started, now if we never saw a set for it.
+    pub(crate) fn package_started_at(&self) -> SystemTime {
+        self.current_package
+            .as_ref()
+            .and_then(|payload| self.package_start_times.get(&payload.package_key()))
+            .copied()
+            .unwrap_or_else(SystemTime::now)
+    }
+
+    /// How long the package currently shown has been merging.
+    pub fn elapsed(&self) -> Duration {
+        self.package_started_at().elapsed().unwrap_or_default()
+    }
+
+    /// How long the current package took to build: the phases timed by the hooks if they sent
+    /// any, which are closer to the truth than our timing of the commands, [`Self::elapsed`]
+    /// otherwise.
+    pub(crate) fn build_duration(&self) -> Duration {
+        if self.phase_durations.is_empty() {
+            self.elapsed()
+        } else {
+            self.phase_durations.values().sum()
+        }
+    }
+
+    pub(crate) fn status(&self) -> Option<SessionStatus<'_>> {
+        let payload = self.current_package.as_ref()?;
+        let queue = self.queue_position();
+        Some(SessionStatus {
+            package: &payload.package,
+            category: &payload.category,
+            version: payload.full_version(),
+            state: payload.state.as_ref(),
+            started_at: unix_secs(SystemTime::now() - self.started_at.elapsed()),
+            queue_position: queue.map(|(pos, _)| pos),
+            queue_total: queue.map(|(_, total)| total),
+            operation: self.operation,
+            cflags: payload.cflags.as_deref(),
+            cxxflags: payload.cxxflags.as_deref(),
+        })
+    }
+
+    /// Whether the emerge went through its whole merge list: it unset without failing, after
+    /// getting to the merge list.
+    pub fn completed(&self) -> bool {
+        self.unset_at.is_some() && self.failure.is_none() && self.merge_len > 0
+    }
+
+    /// Position of the current package in the merge list and its length, if known. A position
+    /// sent by the hook wins over the one derived from the packages left.
+    pub(crate) fn queue_position(&self) -> Option<(u32, u32)> {
+        let total = match self.total_packages {
+            0 => return None,
+            total => total,
+        };
+        match self
+            .current_package
+            .as_ref()
+            .and_then(|payload| payload.position)
+        {
+            Some(position) => Some((position, total.max(position))),
+            None => Some((total - self.merge_len + 1, total)),
+        }
+    }
+}
+
+/// Statistics of a whole emerge run, shown (and logged) once it's done.
+#[derive(Debug, Clone)]
+pub struct CompletionSummary {
+    pub merged: u32,
+    pub failed: u32,
+    pub elapsed: Duration,
+    /// `category/package` and how long it took.
+    pub longest_build: Option<(String, Duration)>,
+}
+
+impl CompletionSummary {
+    /// The longest build and the failures, the second line of the presence.
+    pub fn details(&self) -> Option<String> {
+        let longest = self.longest_build.as_ref().map(|(package, duration)| {
+            format!(
+                "longest: {package} ({})",
+                portage::format_duration(*duration)
+            )
+        });
+        let failed = (self.failed > 0).then(|| format!("{} failed", self.failed));
+        match (longest, failed) {
+            (Some(longest), Some(failed)) => Some(format!("{longest}, {failed}")),
+            (longest, failed) => longest.or(failed),
+        }
+    }
+
+    /// Write the summary to the daemon log.
+    pub fn log(&self) {
+        match self.details() {
+            Some(details) => tracing::info!("{self} ({details})"),
+            None => tracing::info!("{self}"),
+        }
+    }
+}
+
+/// "Merged 17 packages in 2h 34m".
+impl Display for CompletionSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let elapsed = portage::format_duration(self.elapsed);
+        match self.merged {
+            1 => write!(f, "Merged 1 package in {elapsed}"),
+            n => write!(f, "Merged {n} packages in {elapsed}"),
+        }
+    }
+}
+
+/// A session in the state dump.
+#[derive(Serialize)]
+pub struct SessionDump<'a> {
+    pub(crate) emerge_pid: Option<u32>,
+    pub(crate) merge_len: u32,
+    pub(crate) total_packages: u32,
+    pub(crate) unset_pending: bool,
+    pub(crate) failed: bool,
+    pub(crate) resumed: bool,
+    pub(crate) status: Option<SessionStatus<'a>>,
+}
+
+/// What the daemon is currently showing, as returned by the query command.
+#[derive(Serialize)]
+pub struct SessionStatus<'a> {
+    pub(crate) package: &'a str,
+    pub(crate) category: &'a str,
+    /// Version with the revision, if any.
+    pub(crate) version: Option<String>,
+    pub(crate) state: Option<&'a PackageState>,
+    /// Unix timestamp (in seconds) of the start of the session.
+    pub(crate) started_at: u64,
+    pub(crate) queue_position: Option<u32>,
+    pub(crate) queue_total: Option<u32>,
+    pub(crate) operation: Option<OperationType>,
+    pub(crate) cflags: Option<&'a str>,
+    pub(crate) cxxflags: Option<&'a str>,
+}
+
+/// One of the sessions returned by the list command.
+#[derive(Serialize)]
+pub struct SessionListEntry<'a> {
+    /// `None` for the session of hooks that don't send a pid.
+    pub(crate) pid: Option<u32>,
+    pub(crate) category: &'a str,
+    pub(crate) package: &'a str,
+    pub(crate) version: Option<String>,
+    pub(crate) state: Option<&'a PackageState>,
+    pub(crate) queue_position: Option<u32>,
+    pub(crate) queue_total: Option<u32>,
+    pub(crate) operation: Option<OperationType>,
+    /// Unix timestamp (in seconds) of the start of the session.
+    pub(crate) started_at_secs: u64,
+}
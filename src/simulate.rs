@@ -0,0 +1,74 @@
+//! `--simulate`: replay commands from a file, to see what the presence looks like without
+//! running emerge.
+
+use std::{
+    io::Read,
+    path::PathBuf,
+    sync::{mpsc::Sender, Arc},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::{command::Command, wake, InternalEvent, Waker};
+
+/// Replay the commands of `path` (stdin for `-`) in a background thread, one every `interval`.
+/// Lines are legacy commands (`set {...}`, `unset`), empty lines and lines starting with `#` are
+/// skipped. With `repeat` the file starts over once done.
+pub fn replay(
+    path: PathBuf,
+    interval: Duration,
+    repeat: bool,
+    events: Sender<InternalEvent>,
+    waker: Arc<Waker>,
+) {
+    thread::Builder::new()
+        .name("simulate".to_owned())
+        .spawn(move || {
+            if let Err(err) = run(&path, interval, repeat, &events, &waker) {
+                tracing::warn!("Stopped the simulation ({err:?})");
+            }
+        })
+        .expect("Couldn't spawn simulation");
+}
+
+fn run(
+    path: &PathBuf,
+    interval: Duration,
+    repeat: bool,
+    events: &Sender<InternalEvent>,
+    waker: &Waker,
+) -> anyhow::Result<()> {
+    // Read everything first, stdin can't be read again for --loop
+    let content = if path.as_os_str() == "-" {
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content)?;
+        content
+    } else {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read {}", path.display()))?
+    };
+    let lines: Vec<&str> = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    loop {
+        for line in &lines {
+            tracing::info!("Simulating {line}");
+            match Command::from_legacy(line.as_bytes()) {
+                Ok(command) => {
+                    events.send(InternalEvent::Command(Box::new(command)))?;
+                    wake(waker)?;
+                }
+                Err(err) => tracing::warn!("Invalid simulated command {line:?} ({err})"),
+            }
+            thread::sleep(interval);
+        }
+        if !repeat || lines.is_empty() {
+            tracing::info!("Simulation done");
+            return Ok(());
+        }
+    }
+}
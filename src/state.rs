@@ -0,0 +1,116 @@
+use std::{
+    collections::HashMap,
+    env,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    portage::{parse_emerge_cmdline, pid_alive, OperationType, PackagePayload},
+    session::MergeSession,
+    unix_secs, write_atomic,
+};
+
+/// `$XDG_RUNTIME_DIR/emerge-presence-state.json`, or in /tmp if XDG_RUNTIME_DIR isn't set.
+pub fn default_state_path() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .filter(|v| !v.is_empty())
+        .map_or_else(|| PathBuf::from("/tmp"), PathBuf::from)
+        .join("emerge-presence-state.json")
+}
+
+/// What survives a restart of a session. Failures are short lived and aren't kept.
+#[derive(Serialize, Deserialize)]
+struct SavedSession {
+    emerge_pid: Option<u32>,
+    merge_len: u32,
+    total_packages: u32,
+    /// Unix timestamp (in seconds).
+    started_at: u64,
+    current_package: Option<PackagePayload>,
+    /// Unix timestamp (in seconds) of the first set of the current package.
+    #[serde(default)]
+    package_started_at: Option<u64>,
+    unset_pending: bool,
+    #[serde(default)]
+    operation: Option<OperationType>,
+}
+
+/// The sessions, saved as json so that a restarted daemon picks up where it left.
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn save(&self, sessions: &HashMap<u32, MergeSession>) -> Result<()> {
+        let saved: Vec<SavedSession> = sessions
+            .values()
+            .map(|session| SavedSession {
+                emerge_pid: session.emerge_pid,
+                merge_len: session.merge_len,
+                total_packages: session.total_packages,
+                started_at: unix_secs(SystemTime::now() - session.started_at.elapsed()),
+                current_package: session.current_package.clone(),
+                package_started_at: session
+                    .current_package
+                    .as_ref()
+                    .map(|_| unix_secs(session.package_started_at())),
+                unset_pending: session.unset_at.is_some(),
+                operation: session.operation,
+            })
+            .collect();
+        write_atomic(&self.path, &serde_json::to_vec(&saved)?)?;
+        Ok(())
+    }
+
+    /// Read the saved sessions, leaving out those whose emerge is gone. A missing file is no
+    /// sessions.
+    pub fn load(&self) -> Result<HashMap<u32, MergeSession>> {
+        let content = match std::fs::read(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let saved: Vec<SavedSession> = serde_json::from_slice(&content)?;
+        let now = unix_secs(SystemTime::now());
+        let sessions = saved
+            .into_iter()
+            .filter_map(|saved| {
+                let pid = saved.emerge_pid.unwrap_or(0);
+                if !pid_alive(pid) {
+                    tracing::debug!("Dropping saved session of dead process {pid}");
+                    return None;
+                }
+                let age = Duration::from_secs(now.saturating_sub(saved.started_at));
+                let mut session = MergeSession::new(saved.emerge_pid);
+                session.merge_len = saved.merge_len;
+                session.total_packages = saved.total_packages;
+                session.started_at = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+                if let (Some(payload), Some(started_at)) =
+                    (&saved.current_package, saved.package_started_at)
+                {
+                    session.package_start_times.insert(
+                        payload.package_key(),
+                        SystemTime::UNIX_EPOCH + Duration::from_secs(started_at),
+                    );
+                }
+                session.current_package = saved.current_package;
+                session.flags = saved
+                    .emerge_pid
+                    .map(parse_emerge_cmdline)
+                    .unwrap_or_default();
+                session.unset_at = saved.unset_pending.then(Instant::now);
+                session.operation = saved.operation;
+                Some((pid, session))
+            })
+            .collect();
+        Ok(sessions)
+    }
+}
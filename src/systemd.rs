@@ -0,0 +1,69 @@
+//! Service manager notifications, these are no-ops when not running under systemd (or when built
+//! without the `systemd` feature).
+
+use std::os::unix::io::RawFd;
+
+#[cfg(feature = "systemd")]
+use sd_notify::NotifyState;
+
+/// Tell systemd we're done starting up (for `Type=notify` services).
+#[cfg(feature = "systemd")]
+pub fn notify_ready() {
+    let status = format!("emerge-presence {}", crate::build_info::VERSION);
+    if let Err(err) = sd_notify::notify(false, &[NotifyState::Ready, NotifyState::Status(&status)])
+    {
+        tracing::warn!("Couldn't notify systemd ({err:?})");
+    }
+}
+
+/// Whether systemd expects watchdog pings (`WatchdogSec=` is set).
+#[cfg(feature = "systemd")]
+pub fn watchdog_enabled() -> bool {
+    let mut usec = 0;
+    let enabled = sd_notify::watchdog_enabled(false, &mut usec);
+    if enabled {
+        tracing::info!("Systemd watchdog enabled ({usec}us)");
+    }
+    enabled
+}
+
+#[cfg(feature = "systemd")]
+pub fn notify_watchdog() {
+    if let Err(err) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+        tracing::warn!("Couldn't ping systemd watchdog ({err:?})");
+    }
+}
+
+/// The socket (or fifo) systemd passed us if we were socket activated.
+#[cfg(feature = "systemd")]
+pub fn listen_fd() -> Option<RawFd> {
+    match sd_notify::listen_fds() {
+        Ok(mut fds) => {
+            let fd = fds.next();
+            if fds.next().is_some() {
+                tracing::warn!("Got more than one socket from systemd, only using the first one");
+            }
+            fd
+        }
+        Err(err) => {
+            tracing::warn!("Couldn't get the sockets passed by systemd ({err:?})");
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "systemd"))]
+pub fn notify_ready() {}
+
+#[cfg(not(feature = "systemd"))]
+pub fn watchdog_enabled() -> bool {
+    false
+}
+
+#[cfg(not(feature = "systemd"))]
+pub fn notify_watchdog() {}
+
+#[cfg(not(feature = "systemd"))]
+pub fn listen_fd() -> Option<RawFd> {
+    None
+}
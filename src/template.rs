@@ -0,0 +1,70 @@
+//! User supplied formats for the text of the presence, as minijinja templates.
+
+use anyhow::{Context, Result};
+use minijinja::Environment;
+use serde::Serialize;
+
+const DETAILS: &str = "details";
+const STATE: &str = "state";
+
+/// What the templates can use. Missing values are left out, so that they render as nothing and
+/// are falsy in conditions.
+#[derive(Serialize)]
+pub struct TemplateContext<'a> {
+    pub category: &'a str,
+    pub package: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Unless it's the default slot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    /// The flags that are shown by default (`+flag -other`), space separated.
+    pub use_flags: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repo: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_pos: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_total: Option<u32>,
+}
+
+/// The `details_template` and `state_template` of the config, compiled.
+pub struct Templates {
+    env: Environment<'static>,
+}
+
+impl Templates {
+    pub fn new(details: Option<&str>, state: Option<&str>) -> Result<Self> {
+        let mut env = Environment::new();
+        for (name, source) in [(DETAILS, details), (STATE, state)] {
+            if let Some(source) = source {
+                env.add_template_owned(name, source.to_owned())
+                    .with_context(|| format!("Invalid {name}_template"))?;
+            }
+        }
+        Ok(Self { env })
+    }
+
+    fn render(&self, name: &str, context: &TemplateContext) -> Option<String> {
+        let template = self.env.get_template(name).ok()?;
+        match template.render(context) {
+            Ok(text) => Some(text.trim().to_owned()),
+            Err(err) => {
+                tracing::warn!("Couldn't render {name}_template ({err})");
+                None
+            }
+        }
+    }
+
+    /// The details line, `None` if there is no template for it (or it failed to render).
+    pub fn details(&self, context: &TemplateContext) -> Option<String> {
+        self.render(DETAILS, context)
+    }
+
+    /// The state line, `None` if there is no template for it (or it failed to render).
+    pub fn state(&self, context: &TemplateContext) -> Option<String> {
+        self.render(STATE, context)
+    }
+}
@@ -0,0 +1,929 @@
+#[cfg(not(feature = "tokio"))]
+use std::io::{Read, Write};
+#[cfg(feature = "tokio")]
+use std::task::Poll;
+use std::{
+    collections::HashMap,
+    env,
+    fs::{File, Permissions},
+    io::ErrorKind,
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        io::{FromRawFd, RawFd},
+        prelude::AsRawFd,
+    },
+    path::{Path, PathBuf},
+};
+
+use mio::Token;
+#[cfg(not(feature = "tokio"))]
+use mio::{
+    net::{UnixListener, UnixStream},
+    unix::SourceFd,
+    Events, Interest, Registry,
+};
+#[cfg(not(feature = "tokio"))]
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    sys::{
+        inotify::{AddWatchFlags, InitFlags, Inotify},
+        stat::{fstat, umask, Mode, SFlag},
+    },
+    unistd::mkfifo,
+};
+#[cfg(feature = "tokio")]
+use tokio::{
+    io::{unix::AsyncFd, AsyncReadExt},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{
+    auth::CommandAuth,
+    command::{read_frame, Command},
+    error::{CommandError, TransportError},
+};
+
+type Result<T, E = TransportError> = std::result::Result<T, E>;
+
+pub const PIPE: Token = Token(0);
+pub const LISTENER: Token = Token(1);
+/// inotify watch of the directory of the fifo.
+#[cfg(not(feature = "tokio"))]
+const FIFO_WATCH: Token = Token(2);
+/// Tokens of accepted connections start here.
+const FIRST_CONNECTION: usize = 3;
+
+/// Most we buffer from the fifo or a connection at once, unless
+/// [`Transport::set_max_payload_bytes`] says otherwise. Commands are a few hundred bytes, more than
+/// this is someone writing garbage (or worse).
+pub const MAX_PAYLOAD_BYTES: usize = 65536;
+/// Smallest limit that lets a set through, with the least it can carry in a frame (which takes a
+/// few more bytes than the legacy `set ...` and its terminating NUL).
+pub const MIN_PAYLOAD_BYTES: usize = 8 + r#"{"category":"a","package":"b"}"#.len();
+
+/// rw for everyone, the hooks don't necessarily run as the same user as the daemon.
+const MODE: Mode = Mode::S_IRUSR
+    .union(Mode::S_IWUSR)
+    .union(Mode::S_IRGRP)
+    .union(Mode::S_IWGRP)
+    .union(Mode::S_IROTH)
+    .union(Mode::S_IWOTH);
+/// Only for us (and root), once a secret says who may control the presence.
+const PRIVATE_MODE: Mode = Mode::S_IRUSR.union(Mode::S_IWUSR);
+
+/// The mode of the fifo or socket, private when commands are authenticated.
+fn mode(private: bool) -> Mode {
+    match private {
+        true => PRIVATE_MODE,
+        false => MODE,
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/emerge-presence.sock`, or in /tmp if XDG_RUNTIME_DIR isn't set.
+pub fn default_socket_path() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .filter(|v| !v.is_empty())
+        .map_or_else(|| PathBuf::from("/tmp"), PathBuf::from)
+        .join("emerge-presence.sock")
+}
+
+/// Where commands come from. With the tokio feature it has to be created from within a tokio
+/// runtime.
+pub enum Transport {
+    /// Legacy named pipe, all writers share a single byte stream.
+    Fifo(FifoReader),
+    /// Unix socket, each writer gets its own connection (and buffer).
+    Socket(SocketServer),
+}
+
+pub struct FifoReader {
+    #[cfg(not(feature = "tokio"))]
+    file: File,
+    /// Read on the blocking pool, tokio can't poll a fifo.
+    #[cfg(feature = "tokio")]
+    file: tokio::fs::File,
+    /// Write end of the fifo, without which reads would return EOF right away (over and over)
+    /// rather than wait whenever no hook has the fifo open. Replaced along with the fifo, which
+    /// ends the read that may still be waiting on the old one.
+    #[cfg(feature = "tokio")]
+    writer: File,
+    buf: Vec<u8>,
+    path: PathBuf,
+    /// Watch on the directory of the fifo, to notice when it gets deleted or recreated (by
+    /// tmpfiles cleaners for example), which would otherwise leave us reading a dead inode.
+    #[cfg(not(feature = "tokio"))]
+    watch: Option<Inotify>,
+    #[cfg(feature = "tokio")]
+    watch: Option<AsyncFd<Inotify>>,
+    /// Inode of the fifo when it was opened, when it differs from the one at `path` the fifo was
+    /// replaced.
+    inode: u64,
+    /// Whether the fifo is ours to create again, a fifo passed by the service manager isn't.
+    recreate: bool,
+    /// What the fifo is created with.
+    mode: Mode,
+    /// Most bytes buffered at once, writes past it are dropped.
+    max_payload_bytes: usize,
+    /// The last read was cut short, so whatever is left in the buffer once the complete commands
+    /// have been handled is the start of a truncated one.
+    truncated: bool,
+}
+
+pub struct SocketServer {
+    listener: UnixListener,
+    /// `None` for a socket passed by the service manager, which isn't ours to remove.
+    path: Option<PathBuf>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    /// Most bytes buffered for a connection, which is closed past it.
+    max_payload_bytes: usize,
+}
+
+struct Connection {
+    stream: UnixStream,
+    buf: Vec<u8>,
+    /// The other end hung up, the connection is dropped once its buffer has been handled.
+    closed: bool,
+}
+
+impl Transport {
+    /// Read the fifo at `path`, creating it if needed. A `private` fifo (for when there is a
+    /// secret) is only writable by us, otherwise by everyone as the hooks of the userpriv phases
+    /// run as portage.
+    pub fn fifo(path: &Path, private: bool) -> Result<Self> {
+        let mode = mode(private);
+        if !path.exists() {
+            tracing::info!("No fifo found, creating it");
+            create_fifo(path, mode)?;
+        }
+        let file = open_fifo(path)?;
+        let watch = match watch_fifo_dir(path) {
+            Ok(watch) => Some(watch),
+            Err(err) => {
+                tracing::warn!("Couldn't watch the fifo directory, a deleted fifo will only be recreated once its writers hang up ({err})");
+                None
+            }
+        };
+        FifoReader::new(file, path.to_owned(), watch, true, mode).map(Self::Fifo)
+    }
+
+    /// Listen on a socket at `path`, with the same mode as [`Self::fifo`] for `private`.
+    pub fn socket(path: &Path, private: bool) -> Result<Self> {
+        // A socket left over by a previous instance would make bind fail, we hold the pid lock so
+        // it can't be in use.
+        if let Ok(meta) = path.symlink_metadata() {
+            if meta.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        // Sockets are created with 0777 & !umask. A private one must never be reachable by others,
+        // even until the chmod, the umask keeping the rest of the process' files usable meanwhile.
+        let prev = private.then(|| umask(Mode::S_IRWXG | Mode::S_IRWXO));
+        let res = UnixListener::bind(path);
+        if let Some(prev) = prev {
+            umask(prev);
+        }
+        let listener = res.map_err(|err| TransportError::at("bind socket", path, err))?;
+        set_mode(path, mode(private))?;
+        Ok(Self::Socket(SocketServer::new(
+            listener,
+            Some(path.to_owned()),
+        )))
+    }
+
+    /// Use an already open socket or fifo, passed by the service manager (socket activation) or
+    /// with `--socket-fd`. Takes ownership of `fd`.
+    pub fn from_fd(fd: RawFd) -> Result<Self> {
+        let stat = fstat(fd).map_err(|err| TransportError::InvalidFd { fd, err })?;
+        match SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT {
+            SFlag::S_IFSOCK => {
+                // SAFETY: the fd is a socket handed over to us, nothing else uses it.
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                #[cfg(not(feature = "tokio"))]
+                let listener = UnixListener::from_std(listener);
+                #[cfg(feature = "tokio")]
+                let listener = UnixListener::from_std(listener)?;
+                Ok(Self::Socket(SocketServer::new(listener, None)))
+            }
+            SFlag::S_IFIFO => {
+                // SAFETY: the fd is a fifo handed over to us, nothing else uses it.
+                let file = unsafe { File::from_raw_fd(fd) };
+                fcntl(fd, FcntlArg::F_SETFL(OFlag::empty()))?;
+                let path = std::fs::read_link(format!("/proc/self/fd/{fd}")).unwrap_or_default();
+                // Whoever created the fifo is in charge of it, so no watch to recreate it.
+                FifoReader::new(file, path, None, false, MODE).map(Self::Fifo)
+            }
+            _ => Err(TransportError::NotSocketOrFifo(fd)),
+        }
+    }
+
+    /// Register with `registry`, to poll it for [`Self::receive`].
+    #[cfg(not(feature = "tokio"))]
+    pub fn register(&mut self, registry: &Registry) -> Result<()> {
+        match self {
+            Self::Fifo(fifo) => {
+                let fd = fifo.file.as_raw_fd();
+                registry.register(&mut SourceFd(&fd), PIPE, Interest::READABLE)?;
+                if let Some(watch) = &fifo.watch {
+                    let fd = watch.as_raw_fd();
+                    registry.register(&mut SourceFd(&fd), FIFO_WATCH, Interest::READABLE)?;
+                }
+            }
+            Self::Socket(server) => {
+                registry.register(&mut server.listener, LISTENER, Interest::READABLE)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read whatever is available after a poll.
+    #[cfg(not(feature = "tokio"))]
+    pub fn receive(&mut self, events: &Events, registry: &Registry) -> Result<usize> {
+        match self {
+            Self::Fifo(fifo) => {
+                if events.iter().any(|event| event.token() == FIFO_WATCH) {
+                    fifo.handle_watch(registry)?;
+                }
+                let len = fifo.read()?;
+                let readable = events.iter().any(|event| event.token() == PIPE);
+                if len == 0 && readable {
+                    // EOF usually means the last writer closed, but it's also what a deleted fifo
+                    // looks like. The watch should catch that, this covers it going missing.
+                    fifo.check_replaced(registry)?;
+                }
+                Ok(len)
+            }
+            Self::Socket(server) => {
+                let mut len = 0;
+                for event in events {
+                    match event.token() {
+                        LISTENER => server.accept(registry)?,
+                        token => len += server.read(token, registry),
+                    }
+                }
+                Ok(len)
+            }
+        }
+    }
+
+    /// Wait for something to read and read it. Cancel safe: what a cancelled call was reading is
+    /// returned by the next one.
+    #[cfg(feature = "tokio")]
+    pub async fn receive(&mut self) -> Result<usize> {
+        match self {
+            Self::Fifo(fifo) => fifo.receive().await,
+            Self::Socket(server) => server.receive().await,
+        }
+    }
+
+    /// Limit how much is buffered: the rest of a write to the fifo is dropped, and a connection
+    /// sending more without a whole command in it is closed.
+    pub fn set_max_payload_bytes(&mut self, max: usize) {
+        match self {
+            Self::Fifo(fifo) => fifo.max_payload_bytes = max,
+            Self::Socket(server) => server.max_payload_bytes = max,
+        }
+    }
+
+    /// Parse every complete frame out of the buffers and hand the commands to `handle`, which can
+    /// return a reply to write back to the sender. Commands `auth` rejects are handed as errors.
+    pub fn drain_commands(
+        &mut self,
+        auth: Option<&CommandAuth>,
+        mut handle: impl FnMut(Result<Command, CommandError>) -> Option<Vec<u8>>,
+    ) {
+        match self {
+            Self::Fifo(FifoReader { buf, truncated, .. }) => {
+                drain_buffer(buf, auth, |command| {
+                    if handle(command).is_some() {
+                        tracing::warn!("Can't reply to a command received through the fifo");
+                    }
+                });
+                if std::mem::take(truncated) && !buf.is_empty() {
+                    tracing::debug!("Dropping {} bytes of a truncated command", buf.len());
+                    buf.clear();
+                }
+            }
+            Self::Socket(server) => {
+                for (token, connection) in &mut server.connections {
+                    let Connection { stream, buf, .. } = connection;
+                    drain_buffer(buf, auth, |command| {
+                        if let Some(reply) = handle(command) {
+                            if let Err(err) = write_reply(stream, &reply) {
+                                tracing::warn!("Couldn't reply to connection {token:?} ({err:?})");
+                            }
+                        }
+                    });
+                }
+                server.connections.retain(|token, connection| {
+                    if connection.closed && !connection.buf.is_empty() {
+                        tracing::warn!(
+                            "Connection {token:?} closed with {} unparsed bytes",
+                            connection.buf.len()
+                        );
+                    }
+                    !connection.closed
+                });
+            }
+        }
+    }
+}
+
+fn create_fifo(path: &Path, mode: Mode) -> Result<()> {
+    mkfifo(path, mode).map_err(|err| TransportError::at("create fifo", path, err.into()))?;
+    // Whatever the umask is, which can only have made it stricter until now
+    set_mode(path, mode)
+}
+
+fn set_mode(path: &Path, mode: Mode) -> Result<()> {
+    std::fs::set_permissions(path, Permissions::from_mode(mode.bits()))
+        .map_err(|err| TransportError::at("set the mode of", path, err))
+}
+
+/// Open the fifo for reading without waiting for a writer: a plain open blocks until one shows up,
+/// which could be never when no emerge runs, and the daemon would hang before getting to its main
+/// loop. Reads are blocking again once it's open.
+fn open_fifo(path: &Path) -> Result<File> {
+    let file = File::options()
+        .read(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(path)
+        .map_err(|err| TransportError::at("open fifo", path, err))?;
+    fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty()))?;
+    Ok(file)
+}
+
+/// Open the fifo `reader` reads for writing, through /proc as its path may be gone (or another
+/// fifo) by now.
+#[cfg(feature = "tokio")]
+fn open_writer(reader: &File) -> Result<File> {
+    let path = PathBuf::from(format!("/proc/self/fd/{}", reader.as_raw_fd()));
+    // Doesn't wait, the fifo has a reader
+    File::options()
+        .write(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(&path)
+        .map_err(|err| TransportError::at("open fifo", &path, err))
+}
+
+fn watch_fifo_dir(path: &Path) -> Result<Inotify> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+    inotify.add_watch(dir, AddWatchFlags::IN_CREATE | AddWatchFlags::IN_DELETE)?;
+    Ok(inotify)
+}
+
+#[cfg(not(feature = "tokio"))]
+fn write_reply(stream: &mut UnixStream, reply: &[u8]) -> std::io::Result<()> {
+    stream.write_all(reply)
+}
+
+/// Like the `write_all` of a non blocking socket: a full socket is an error, not something to
+/// wait for.
+#[cfg(feature = "tokio")]
+fn write_reply(stream: &UnixStream, mut reply: &[u8]) -> std::io::Result<()> {
+    while !reply.is_empty() {
+        match stream.try_write(reply) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(len) => reply = &reply[len..],
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+impl FifoReader {
+    fn new(
+        file: File,
+        path: PathBuf,
+        watch: Option<Inotify>,
+        recreate: bool,
+        mode: Mode,
+    ) -> Result<Self> {
+        let inode = file.metadata()?.ino();
+        #[cfg(feature = "tokio")]
+        let (writer, file, watch) = (
+            open_writer(&file)?,
+            tokio::fs::File::from_std(file),
+            watch.map(AsyncFd::new).transpose()?,
+        );
+        Ok(Self {
+            file,
+            #[cfg(feature = "tokio")]
+            writer,
+            buf: Vec::new(),
+            path,
+            watch,
+            inode,
+            recreate,
+            mode,
+            max_payload_bytes: MAX_PAYLOAD_BYTES,
+            truncated: false,
+        })
+    }
+
+    /// Consume the events of the watch, recreating the fifo if it was deleted. Returns whether
+    /// one was created.
+    fn watch_events(&self) -> Result<bool> {
+        let Some(watch) = &self.watch else {
+            return Ok(false);
+        };
+        #[cfg(feature = "tokio")]
+        let watch = watch.get_ref();
+        let name = self.path.file_name();
+        let (mut deleted, mut created) = (false, false);
+        while let Ok(events) = watch.read_events() {
+            if events.is_empty() {
+                break;
+            }
+            for event in events.iter().filter(|event| event.name.as_deref() == name) {
+                deleted |= event.mask.contains(AddWatchFlags::IN_DELETE);
+                created |= event.mask.contains(AddWatchFlags::IN_CREATE);
+            }
+        }
+        if deleted && !self.path.exists() {
+            tracing::info!("The fifo was deleted, creating it again");
+            // Our own creation shows up as an IN_CREATE on the next poll
+            create_fifo(&self.path, self.mode)?;
+        }
+        Ok(created)
+    }
+
+    /// Whether the fifo at `path` is gone or a different one than the one we have open, in which
+    /// case it's recreated (if gone) and has to be reopened.
+    fn replaced(&self) -> Result<bool> {
+        if self.path.as_os_str().is_empty() {
+            return Ok(false);
+        }
+        match self.path.metadata() {
+            Ok(meta) => Ok(meta.ino() != self.inode),
+            Err(err) if err.kind() == ErrorKind::NotFound && self.recreate => {
+                tracing::info!("The fifo was deleted, creating it again");
+                create_fifo(&self.path, self.mode)?;
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+#[cfg(not(feature = "tokio"))]
+impl FifoReader {
+    /// Read what the writers wrote, up to `max_payload_bytes` buffered. The rest is read and
+    /// thrown away rather than buffered, they'd otherwise make us allocate as much as they write.
+    fn read(&mut self) -> Result<usize> {
+        let limit = self.max_payload_bytes.saturating_sub(self.buf.len());
+        self.buf.try_reserve(limit)?;
+        let len = (&mut self.file)
+            .take(limit as u64)
+            .read_to_end(&mut self.buf)?;
+        // With nothing left the rest could only be waited for, the writers may not be done
+        if len == limit && readable(&self.file) {
+            let dropped = std::io::copy(&mut self.file, &mut std::io::sink())?;
+            tracing::warn!(
+                "More than {} bytes written to the fifo, dropped the last {dropped}",
+                self.max_payload_bytes
+            );
+            self.truncated = true;
+        }
+        Ok(len)
+    }
+
+    /// Look at what happened to the fifo: recreate it if it was deleted, and switch to the new
+    /// one once it's created.
+    fn handle_watch(&mut self, registry: &Registry) -> Result<()> {
+        if self.watch_events()? {
+            // Might already be the one we reopened after an EOF
+            self.check_replaced(registry)?;
+        }
+        Ok(())
+    }
+
+    /// Compare the fifo at `path` to the one we have open, recreating and reopening it if it's
+    /// gone or a different one.
+    fn check_replaced(&mut self, registry: &Registry) -> Result<()> {
+        if self.replaced()? {
+            self.reopen(registry)?;
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self, registry: &Registry) -> Result<()> {
+        tracing::info!("The fifo was recreated, reopening it");
+        let file = open_fifo(&self.path)?;
+        registry
+            .deregister(&mut SourceFd(&self.file.as_raw_fd()))
+            .ok();
+        registry.register(&mut SourceFd(&file.as_raw_fd()), PIPE, Interest::READABLE)?;
+        self.inode = file.metadata()?.ino();
+        self.file = file;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl FifoReader {
+    /// Wait for the writers to write (or the watch to see the fifo replaced) and read it, up to
+    /// `max_payload_bytes` buffered. Past it the rest of what was read is thrown away, like the
+    /// blocking read does.
+    async fn receive(&mut self) -> Result<usize> {
+        let mut chunk = [0u8; 4096];
+        let (file, watch) = (&mut self.file, &self.watch);
+        let watched = async {
+            match watch {
+                Some(watch) => watch.readable().await.map(|mut ready| ready.clear_ready()),
+                None => std::future::pending().await,
+            }
+        };
+        // A read given up on keeps going on the blocking pool, its bytes are handed to the next
+        let read = tokio::select! {
+            read = file.read(&mut chunk) => Some(read?),
+            res = watched => {
+                res?;
+                None
+            }
+        };
+        let Some(read) = read else {
+            self.handle_watch()?;
+            return Ok(0);
+        };
+        if read == 0 {
+            // Only once we dropped the writer, which goes with a replaced fifo
+            self.check_replaced()?;
+            return Ok(0);
+        }
+        let room = self.max_payload_bytes.saturating_sub(self.buf.len());
+        if read > room {
+            tracing::warn!(
+                "More than {} bytes written to the fifo, dropped the last {}",
+                self.max_payload_bytes,
+                read - room
+            );
+            self.truncated = true;
+        }
+        let len = read.min(room);
+        self.buf.try_reserve(len)?;
+        self.buf.extend_from_slice(&chunk[..len]);
+        Ok(len)
+    }
+
+    /// Look at what happened to the fifo: recreate it if it was deleted, and switch to the new
+    /// one once it's created.
+    fn handle_watch(&mut self) -> Result<()> {
+        if self.watch_events()? {
+            self.check_replaced()?;
+        }
+        Ok(())
+    }
+
+    /// Compare the fifo at `path` to the one we have open, recreating and reopening it if it's
+    /// gone or a different one.
+    fn check_replaced(&mut self) -> Result<()> {
+        if self.replaced()? {
+            self.reopen()?;
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        tracing::info!("The fifo was recreated, reopening it");
+        let file = open_fifo(&self.path)?;
+        self.inode = file.metadata()?.ino();
+        // Dropping the old writer ends the read that may still wait on the old fifo
+        self.writer = open_writer(&file)?;
+        self.file = tokio::fs::File::from_std(file);
+        Ok(())
+    }
+}
+
+/// Whether there is something to read on `fd` right away.
+#[cfg(not(feature = "tokio"))]
+fn readable(fd: &impl AsRawFd) -> bool {
+    let mut fds = [PollFd::new(fd.as_raw_fd(), PollFlags::POLLIN)];
+    matches!(poll(&mut fds, 0), Ok(n) if n > 0)
+        && fds[0]
+            .revents()
+            .is_some_and(|events| events.contains(PollFlags::POLLIN))
+}
+
+fn drain_buffer(
+    buf: &mut Vec<u8>,
+    auth: Option<&CommandAuth>,
+    mut handle: impl FnMut(Result<Command, CommandError>),
+) {
+    let mut consumed = 0;
+    while let Some((used, command)) = read_frame(&buf[consumed..], auth) {
+        consumed += used;
+        handle(command);
+    }
+    tracing::trace!("{} bytes left in buffer", buf.len() - consumed);
+    buf.drain(..consumed);
+}
+
+impl SocketServer {
+    fn new(listener: UnixListener, path: Option<PathBuf>) -> Self {
+        Self {
+            listener,
+            path,
+            connections: HashMap::new(),
+            next_token: FIRST_CONNECTION,
+            max_payload_bytes: MAX_PAYLOAD_BYTES,
+        }
+    }
+
+    /// Read everything available on the connection, returns the number of bytes read.
+    fn read_connection(&mut self, token: Token) -> usize {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return 0;
+        };
+        let mut chunk = [0u8; 4096];
+        let mut len = 0;
+        loop {
+            #[cfg(not(feature = "tokio"))]
+            let read = connection.stream.read(&mut chunk);
+            #[cfg(feature = "tokio")]
+            let read = connection.stream.try_read(&mut chunk);
+            match read {
+                Ok(0) => {
+                    connection.closed = true;
+                    break;
+                }
+                Ok(n) if connection.buf.len() + n > self.max_payload_bytes => {
+                    // What fits is still handled, it may hold whole commands
+                    let fits = self.max_payload_bytes.saturating_sub(connection.buf.len());
+                    connection.buf.extend_from_slice(&chunk[..fits]);
+                    len += fits;
+                    tracing::warn!(
+                        "Connection {token:?} sent more than {} bytes at once, closing it",
+                        self.max_payload_bytes
+                    );
+                    connection.closed = true;
+                    break;
+                }
+                Ok(n) => {
+                    connection.buf.extend_from_slice(&chunk[..n]);
+                    len += n;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => {
+                    tracing::warn!("Error reading from connection {token:?} ({err:?})");
+                    connection.closed = true;
+                    break;
+                }
+            }
+        }
+        if connection.closed {
+            tracing::trace!("Connection {token:?} closed");
+        }
+        len
+    }
+}
+
+#[cfg(not(feature = "tokio"))]
+impl SocketServer {
+    fn accept(&mut self, registry: &Registry) -> Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((mut stream, _)) => {
+                    let token = Token(self.next_token);
+                    self.next_token += 1;
+                    registry.register(&mut stream, token, Interest::READABLE)?;
+                    tracing::trace!("Accepted connection {token:?}");
+                    self.connections.insert(
+                        token,
+                        Connection {
+                            stream,
+                            buf: Vec::new(),
+                            closed: false,
+                        },
+                    );
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// [`Self::read_connection`], deregistering the connection once it's closed.
+    fn read(&mut self, token: Token, registry: &Registry) -> usize {
+        let len = self.read_connection(token);
+        if let Some(connection) = self.connections.get_mut(&token) {
+            if connection.closed {
+                registry.deregister(&mut connection.stream).ok();
+            }
+        }
+        len
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl SocketServer {
+    /// Wait for connections, or for one to send something, and read what they sent.
+    async fn receive(&mut self) -> Result<usize> {
+        let (mut accepted, mut readable) = (Vec::new(), Vec::new());
+        // Nothing is taken from the listener or the connections until all of it is returned
+        std::future::poll_fn(|cx| {
+            while let Poll::Ready(res) = self.listener.poll_accept(cx) {
+                let failed = res.is_err();
+                accepted.push(res);
+                if failed {
+                    break;
+                }
+            }
+            readable.extend(
+                self.connections
+                    .iter()
+                    .filter(|(_, connection)| {
+                        // Until drained away, a closed one would always be ready
+                        !connection.closed && connection.stream.poll_read_ready(cx).is_ready()
+                    })
+                    .map(|(token, _)| *token),
+            );
+            match accepted.is_empty() && readable.is_empty() {
+                true => Poll::Pending,
+                false => Poll::Ready(()),
+            }
+        })
+        .await;
+        for res in accepted {
+            let (stream, _) = res?;
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            tracing::trace!("Accepted connection {token:?}");
+            self.connections.insert(
+                token,
+                Connection {
+                    stream,
+                    buf: Vec::new(),
+                    closed: false,
+                },
+            );
+        }
+        Ok(readable
+            .into_iter()
+            .map(|token| self.read_connection(token))
+            .sum())
+    }
+}
+
+impl Drop for SocketServer {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "tokio"))]
+    use mio::Poll;
+
+    use super::*;
+
+    fn mode_of(path: &Path) -> u32 {
+        path.metadata().unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn modes() {
+        let dir = tempfile::tempdir().unwrap();
+        #[cfg(feature = "tokio")]
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        #[cfg(feature = "tokio")]
+        let _runtime = runtime.enter();
+        for (private, expected) in [(false, 0o666), (true, 0o600)] {
+            let path = dir.path().join(format!("fifo-{private}"));
+            let _fifo = Transport::fifo(&path, private).unwrap();
+            assert_eq!(mode_of(&path), expected, "fifo, private: {private}");
+
+            let path = dir.path().join(format!("socket-{private}"));
+            let _socket = Transport::socket(&path, private).unwrap();
+            assert_eq!(mode_of(&path), expected, "socket, private: {private}");
+        }
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    #[test]
+    fn fifo_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fifo");
+        let mut transport = Transport::fifo(&path, false).unwrap();
+        transport.set_max_payload_bytes(64);
+        let Transport::Fifo(fifo) = &mut transport else {
+            unreachable!();
+        };
+
+        // Exactly the limit, nothing is left to drop
+        std::fs::write(&path, [b'a'; 64]).unwrap();
+        assert_eq!(fifo.read().unwrap(), 64);
+        assert!(!fifo.truncated);
+        // Full, with nothing more written
+        assert_eq!(fifo.read().unwrap(), 0);
+        assert!(!fifo.truncated);
+        // Full, with more written
+        std::fs::write(&path, b"b").unwrap();
+        assert_eq!(fifo.read().unwrap(), 0);
+        assert!(fifo.truncated);
+
+        transport.drain_commands(None, |_| None);
+        let Transport::Fifo(fifo) = &mut transport else {
+            unreachable!();
+        };
+        assert!(fifo.buf.is_empty());
+        std::fs::write(&path, [b'a'; 65]).unwrap();
+        assert_eq!(fifo.read().unwrap(), 64);
+        assert!(fifo.truncated);
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    #[test]
+    fn connection_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("socket");
+        let poll = Poll::new().unwrap();
+        let mut transport = Transport::socket(&path, false).unwrap();
+        transport.set_max_payload_bytes(64);
+        let Transport::Socket(server) = &mut transport else {
+            unreachable!();
+        };
+
+        for (written, closed) in [(64, false), (65, true)] {
+            let mut client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+            client.write_all(&vec![b'a'; written]).unwrap();
+            server.accept(poll.registry()).unwrap();
+            let token = Token(server.next_token - 1);
+            assert_eq!(server.read(token, poll.registry()), 64);
+            assert_eq!(server.connections[&token].closed, closed, "{written} bytes");
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_receive() {
+        use std::{
+            io::{Read, Write},
+            time::Duration,
+        };
+
+        use crate::command::{encode_frame, OP_CLEAR, OP_QUERY};
+
+        /// Receive until a command comes in, replying to queries.
+        async fn next_command(transport: &mut Transport) -> Command {
+            loop {
+                transport.receive().await.unwrap();
+                let mut received = None;
+                transport.drain_commands(None, |command| {
+                    let command = command.unwrap();
+                    let reply = matches!(command, Command::Query).then(|| b"reply".to_vec());
+                    received = Some(command);
+                    reply
+                });
+                if let Some(command) = received {
+                    return command;
+                }
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let test = async {
+                let path = dir.path().join("fifo");
+                let mut fifo = Transport::fifo(&path, false).unwrap();
+                std::fs::write(&path, encode_frame(OP_CLEAR, b"")).unwrap();
+                assert!(matches!(next_command(&mut fifo).await, Command::Clear));
+
+                let path = dir.path().join("socket");
+                let mut socket = Transport::socket(&path, false).unwrap();
+                let mut client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+                client.write_all(&encode_frame(OP_QUERY, b"")).unwrap();
+                assert!(matches!(next_command(&mut socket).await, Command::Query));
+                let mut reply = [0; 5];
+                client.read_exact(&mut reply).unwrap();
+                assert_eq!(&reply, b"reply");
+                client.write_all(&encode_frame(OP_CLEAR, b"")).unwrap();
+                assert!(matches!(next_command(&mut socket).await, Command::Clear));
+            };
+            tokio::time::timeout(Duration::from_secs(10), test)
+                .await
+                .expect("nothing received");
+        });
+        // Without waiting for the fifo read left on the blocking pool
+        runtime.shutdown_background();
+    }
+}
@@ -0,0 +1,92 @@
+//! Watches the main loop from another thread. A loop stuck on something (a blocking call that
+//! never returns, discord not answering past the timeouts) stops handling commands while still
+//! holding the pid lock, which is worse than not running at all: nothing else can take over.
+
+use std::{
+    os::unix::process::CommandExt,
+    process::Command,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+
+/// Kills (or restarts) the daemon when the main loop hasn't beaten for `timeout`.
+pub struct Watchdog {
+    /// Monotonic, which unlike the wall clock doesn't jump forward over a suspend and make the
+    /// loop look stuck on resume.
+    started: Instant,
+    /// Seconds between `started` and the last beat. Not an AtomicU64, which some 32-bit targets
+    /// lack.
+    last_beat: Arc<AtomicU32>,
+    timeout: Duration,
+    /// Start the daemon again instead of only killing it.
+    restart: bool,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration, restart: bool) -> Self {
+        Self {
+            started: Instant::now(),
+            last_beat: Arc::new(AtomicU32::new(0)),
+            timeout,
+            restart,
+        }
+    }
+
+    /// Tell the watchdog the main loop is alive, once per iteration.
+    pub fn beat(&self) {
+        self.last_beat
+            .store(secs_since(self.started), Ordering::Relaxed);
+    }
+
+    /// Start watching from a background thread.
+    pub fn spawn(&self) {
+        let last_beat = Arc::clone(&self.last_beat);
+        let (started, timeout, restart) = (self.started, self.timeout, self.restart);
+        let interval = (timeout / 4).max(Duration::from_secs(1));
+        thread::Builder::new()
+            .name("watchdog".to_owned())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let last = last_beat.load(Ordering::Relaxed);
+                let stuck = Duration::from_secs(secs_since(started).saturating_sub(last).into());
+                if stuck > timeout {
+                    hang(stuck, restart);
+                }
+            })
+            .expect("Couldn't spawn watchdog");
+    }
+}
+
+fn secs_since(started: Instant) -> u32 {
+    started.elapsed().as_secs().try_into().unwrap_or(u32::MAX)
+}
+
+/// The main loop is stuck: exec ourselves again if asked to, which also releases the pid lock
+/// (it isn't inherited), or die so that the service manager (or the user) can start us again.
+fn hang(stuck: Duration, restart: bool) {
+    tracing::error!(
+        "Main loop of process {} hasn't run for {stuck:?}, it's most likely stuck",
+        std::process::id()
+    );
+    if restart {
+        match std::env::current_exe() {
+            Ok(exe) => {
+                tracing::error!("Restarting {}", exe.display());
+                let err = Command::new(exe).args(std::env::args_os().skip(1)).exec();
+                tracing::error!("Couldn't restart ({err})");
+            }
+            Err(err) => tracing::error!("Couldn't find the executable to restart ({err})"),
+        }
+    }
+    tracing::error!("Killing the daemon");
+    kill(Pid::this(), Signal::SIGKILL).ok();
+}
@@ -0,0 +1,191 @@
+//! Fallback for when there is no discord to talk to over ipc (discord in a browser, or on another
+//! machine): the activity is posted to a channel through a discord webhook instead, as an embed
+//! that is edited as the activity changes. Needs the `webhook` feature.
+
+use std::{
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+
+/// Shortest time between two requests: discord allows 5 requests per 2 seconds to a webhook and
+/// 30 messages a minute to a channel, the activity changes faster than that during an emerge.
+const MIN_REQUEST_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Posts the activities to a discord webhook, keeping a single message up to date. The requests
+/// are made by a thread of their own, the main loop only hands it the activities.
+pub struct WebhookClient {
+    activities: Option<Sender<Value>>,
+    worker: Option<JoinHandle<()>>,
+    /// Whether the message shows an activity (or will once the thread gets to it).
+    shown: bool,
+}
+
+impl WebhookClient {
+    pub fn new(url: String, thumbnail: Option<String>) -> Self {
+        let (activities, receiver) = mpsc::channel();
+        let webhook = Webhook {
+            url: url.trim_end_matches('/').to_owned(),
+            thumbnail,
+            message_id: None,
+            last_embed: None,
+        };
+        let worker = thread::Builder::new()
+            .name("webhook".to_owned())
+            .spawn(move || webhook.run(receiver))
+            .expect("Couldn't spawn webhook thread");
+        Self {
+            activities: Some(activities),
+            worker: Some(worker),
+            shown: false,
+        }
+    }
+
+    /// The webhook of the config, if there is one and it can be used.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let url = config.presence_webhook_url.clone()?;
+        if cfg!(not(feature = "webhook")) {
+            tracing::warn!("emerge-presence was built without the webhook feature, ignoring presence_webhook_url");
+            return None;
+        }
+        Some(Self::new(url, config.presence_webhook_thumbnail.clone()))
+    }
+
+    /// Show `activity` (as sent to discord over ipc), `null` removes the message.
+    pub fn update(&mut self, activity: &Value) {
+        if activity.is_null() {
+            return self.clear();
+        }
+        self.shown = true;
+        self.send(activity.clone());
+    }
+
+    /// Delete the message, if there is one.
+    pub fn clear(&mut self) {
+        if std::mem::take(&mut self.shown) {
+            self.send(Value::Null);
+        }
+    }
+
+    fn send(&self, activity: Value) {
+        let sent = self
+            .activities
+            .as_ref()
+            .is_some_and(|activities| activities.send(activity).is_ok());
+        if !sent {
+            tracing::warn!("The webhook thread is gone, not posting the activity");
+        }
+    }
+}
+
+/// Waits for the thread to be done with the requests left, the last one usually being the delete
+/// of shutting down.
+impl Drop for WebhookClient {
+    fn drop(&mut self) {
+        drop(self.activities.take());
+        if let Some(worker) = self.worker.take() {
+            worker.join().ok();
+        }
+    }
+}
+
+/// The state of the webhook message, owned by the thread making the requests.
+struct Webhook {
+    url: String,
+    thumbnail: Option<String>,
+    /// The message showing the activity, edited by the next updates and deleted on clear.
+    message_id: Option<String>,
+    /// The last embed sent, to skip updates that wouldn't change anything.
+    last_embed: Option<Value>,
+}
+
+impl Webhook {
+    /// Show the activities until the client is dropped, only the latest of those that came in
+    /// while waiting between two requests is shown.
+    fn run(mut self, activities: Receiver<Value>) {
+        let mut next_request = Instant::now();
+        while let Ok(activity) = activities.recv() {
+            thread::sleep(next_request.saturating_duration_since(Instant::now()));
+            let activity = activities.try_iter().last().unwrap_or(activity);
+            let res = match activity.is_null() {
+                true => self.clear(),
+                false => self.update(&activity),
+            };
+            if let Err(err) = res {
+                tracing::warn!("Couldn't post to the webhook ({err:?})");
+            }
+            next_request = Instant::now() + MIN_REQUEST_INTERVAL;
+        }
+    }
+
+    fn update(&mut self, activity: &Value) -> Result<()> {
+        let embed = self.embed(activity);
+        if self.last_embed.as_ref() == Some(&embed) {
+            return Ok(());
+        }
+        let body = json!({ "embeds": [embed] });
+        match self.message_id.take() {
+            Some(id) => {
+                // Post a new message next time if this one can't be edited (was deleted, ...)
+                request("PATCH", &format!("{}/messages/{id}", self.url), Some(&body))?;
+                self.message_id = Some(id);
+            }
+            None => {
+                let message = request("POST", &format!("{}?wait=true", self.url), Some(&body))?;
+                self.message_id = message["id"].as_str().map(str::to_owned);
+            }
+        }
+        self.last_embed = Some(embed);
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.last_embed = None;
+        if let Some(id) = self.message_id.take() {
+            request("DELETE", &format!("{}/messages/{id}", self.url), None)?;
+        }
+        Ok(())
+    }
+
+    fn embed(&self, activity: &Value) -> Value {
+        let mut embed = json!({
+            "title": activity["details"].as_str().unwrap_or("emerge"),
+            "footer": { "text": "emerge-presence" },
+        });
+        if let Some(state) = activity["state"].as_str() {
+            embed["description"] = json!(state);
+        }
+        // The first button links to packages.gentoo.org (or the homepage)
+        if let Some(url) = activity["buttons"][0]["url"].as_str() {
+            embed["url"] = json!(url);
+        }
+        if let Some(thumbnail) = &self.thumbnail {
+            embed["thumbnail"] = json!({ "url": thumbnail });
+        }
+        embed
+    }
+}
+
+/// Send a request to the webhook, returning the json it answered (null without a body).
+#[cfg(feature = "webhook")]
+fn request(method: &str, url: &str, body: Option<&Value>) -> Result<Value> {
+    let request = ureq::request(method, url).timeout(std::time::Duration::from_secs(5));
+    let response = match body {
+        Some(body) => request.send_json(body)?,
+        None => request.call()?,
+    };
+    if response.status() == 204 {
+        return Ok(Value::Null);
+    }
+    Ok(response.into_json()?)
+}
+
+#[cfg(not(feature = "webhook"))]
+fn request(_method: &str, _url: &str, _body: Option<&Value>) -> Result<Value> {
+    anyhow::bail!("emerge-presence was built without the webhook feature")
+}
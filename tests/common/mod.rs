@@ -0,0 +1,150 @@
+//! A fake discord to run the client against: it answers the handshake, pings and every command
+//! like discord would, and hands the frames it got to the test.
+
+use std::{
+    io::{Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+/// Opcodes of the discord ipc.
+pub const IPC_HANDSHAKE: u32 = 0;
+pub const IPC_FRAME: u32 = 1;
+pub const IPC_CLOSE: u32 = 2;
+pub const IPC_PING: u32 = 3;
+pub const IPC_PONG: u32 = 4;
+
+/// Listens on `discord-ipc-0` in a temporary directory, for a single connection.
+///
+/// Nothing needs to wait for the frames of the server to arrive: those sent by the test are
+/// written (and readable by the client) once the method returns, and the answers to the frames
+/// of the client are written before [`Self::next_message`] hands those out.
+pub struct MockDiscordServer {
+    path: PathBuf,
+    messages: Receiver<(u32, Value)>,
+    /// The connection of the client, to send it events.
+    client: Arc<Mutex<Option<UnixStream>>>,
+    // Removes the socket once the test is done
+    _dir: TempDir,
+}
+
+impl MockDiscordServer {
+    pub fn start() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("discord-ipc-0");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (tx, messages) = mpsc::channel();
+        let client = Arc::new(Mutex::new(None));
+        let connection = Arc::clone(&client);
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            *connection.lock().unwrap() = Some(stream.try_clone().unwrap());
+            let (opcode, handshake) = read_frame(&mut stream).unwrap();
+            assert_eq!(opcode, IPC_HANDSHAKE, "expected a handshake first");
+            let ready = json!({
+                "cmd": "DISPATCH",
+                "evt": "READY",
+                "data": {
+                    "v": 1,
+                    "user": { "id": "1", "username": "tester" },
+                },
+            });
+            write_frame(&mut stream, IPC_FRAME, &ready);
+            if tx.send((opcode, handshake)).is_err() {
+                return;
+            }
+            // Until the client disconnects
+            while let Some((opcode, message)) = read_frame(&mut stream) {
+                if opcode == IPC_FRAME {
+                    let response = json!({
+                        "cmd": message["cmd"],
+                        "nonce": message["nonce"],
+                        "evt": null,
+                        "data": message["args"]["activity"],
+                    });
+                    write_frame(&mut stream, IPC_FRAME, &response);
+                } else if opcode == IPC_PING {
+                    write_frame(&mut stream, IPC_PONG, &message);
+                }
+                if tx.send((opcode, message)).is_err() {
+                    return;
+                }
+            }
+        });
+        Self {
+            path,
+            messages,
+            client,
+            _dir: dir,
+        }
+    }
+
+    /// The socket the client should connect to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Dispatch `event` to the client, like discord does for the events it subscribed to.
+    pub fn dispatch(&self, event: &str, data: Value) {
+        let mut client = self.client.lock().unwrap();
+        let stream = client.as_mut().expect("the client isn't connected");
+        let frame = json!({ "cmd": "DISPATCH", "evt": event, "nonce": null, "data": data });
+        write_frame(stream, IPC_FRAME, &frame);
+    }
+
+    /// Close the connection like discord does, with a close frame.
+    pub fn close(&self, code: u32, message: &str) {
+        let mut client = self.client.lock().unwrap();
+        let stream = client.as_mut().expect("the client isn't connected");
+        write_frame(
+            stream,
+            IPC_CLOSE,
+            &json!({ "code": code, "message": message }),
+        );
+        stream.shutdown(std::net::Shutdown::Both).ok();
+    }
+
+    /// Write `bytes` as they are, to send part of a frame.
+    #[cfg(feature = "tokio")]
+    pub fn write_raw(&self, bytes: &[u8]) {
+        let mut client = self.client.lock().unwrap();
+        let stream = client.as_mut().expect("the client isn't connected");
+        stream.write_all(bytes).unwrap();
+    }
+
+    /// The next frame sent by the client (the handshake first), as its opcode and json.
+    pub fn next_message(&self) -> (u32, Value) {
+        self.messages
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the client didn't send anything")
+    }
+}
+
+fn read_frame(stream: &mut UnixStream) -> Option<(u32, Value)> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).ok()?;
+    let opcode = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload).ok()?;
+    Some((opcode, serde_json::from_slice(&payload).unwrap()))
+}
+
+fn write_frame(stream: &mut UnixStream, opcode: u32, payload: &Value) {
+    let payload = serde_json::to_vec(payload).unwrap();
+    let mut frame = Vec::with_capacity(8 + payload.len());
+    frame.extend_from_slice(&opcode.to_le_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    // The client may be gone already
+    stream.write_all(&frame).ok();
+}
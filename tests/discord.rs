@@ -0,0 +1,277 @@
+// The async client has its own tests, in discord_async.rs
+#![cfg(not(feature = "tokio"))]
+
+mod common;
+
+use emerge_presence::{
+    config::{CategoryImageMap, Config},
+    discord::{Client, ClientBuilder, CLOSE_INVALID_CLIENT_ID, CLOSE_NORMAL},
+    error::PresenceError,
+    portage::{EmergeFlags, PackagePayload},
+};
+use mio::{Events, Poll, Token};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{MockDiscordServer, IPC_FRAME, IPC_HANDSHAKE, IPC_PING};
+
+fn connect(server: &MockDiscordServer) -> Client {
+    connect_with(server, ClientBuilder::default())
+}
+
+fn connect_with(server: &MockDiscordServer, builder: ClientBuilder) -> Client {
+    let mut client = builder.client_id("1234").build().unwrap();
+    client.apply_config(&Config {
+        ipc_socket_path: Some(server.path().to_owned()),
+        ..Config::default()
+    });
+    client.connect().unwrap();
+    client
+}
+
+#[test]
+fn handshake() {
+    let server = MockDiscordServer::start();
+    let client = connect(&server);
+    assert!(client.is_connected());
+
+    let (opcode, handshake) = server.next_message();
+    assert_eq!(opcode, IPC_HANDSHAKE);
+    assert_eq!(handshake["v"], 1);
+    assert_eq!(handshake["client_id"], "1234");
+}
+
+#[test]
+fn set_package() {
+    let server = MockDiscordServer::start();
+    let mut client = connect(&server);
+    server.next_message();
+
+    let payload: PackagePayload = serde_json::from_value(json!({
+        "category": "sys-devel",
+        "package": "gcc",
+        "version": "13.2.1",
+        "revision": "r3",
+        "state": "compiling",
+    }))
+    .unwrap();
+    client.set_package(payload, EmergeFlags::default()).unwrap();
+
+    let (opcode, message) = server.next_message();
+    assert_eq!(opcode, IPC_FRAME);
+    assert_eq!(message["cmd"], "SET_ACTIVITY");
+    assert!(message["nonce"].is_string());
+    let activity = &message["args"]["activity"];
+    assert_eq!(activity["details"], "sys-devel/gcc 13.2.1-r3");
+    assert_eq!(activity["state"], "compiling");
+    assert_eq!(activity["instance"], false);
+    let party = activity["party"]["id"].clone();
+
+    // The same activity again, with another party id for discord not to ignore it
+    let payload: PackagePayload = serde_json::from_value(json!({
+        "category": "sys-devel",
+        "package": "gcc",
+        "version": "13.2.1",
+        "revision": "r3",
+        "state": "compiling",
+    }))
+    .unwrap();
+    client.set_package(payload, EmergeFlags::default()).unwrap();
+    let (_, message) = server.next_message();
+    assert_ne!(message["args"]["activity"]["party"]["id"], party);
+    assert!(activity["timestamps"]["start"].is_u64());
+}
+
+#[test]
+fn clear_sends_null_activity() {
+    let server = MockDiscordServer::start();
+    let mut client = connect(&server);
+    server.next_message();
+
+    client.clear_sessions().unwrap();
+    let (opcode, message) = server.next_message();
+    assert_eq!(opcode, IPC_FRAME);
+    assert_eq!(message["cmd"], "SET_ACTIVITY");
+    assert!(message["args"]["activity"].is_null());
+}
+
+#[test]
+fn subscribe_and_dispatch() {
+    let server = MockDiscordServer::start();
+    let mut client = connect(&server);
+    server.next_message();
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let received = Arc::clone(&events);
+    client.on_dispatch(move |event, data| {
+        received
+            .lock()
+            .unwrap()
+            .push((event.to_owned(), data.clone()))
+    });
+    client.subscribe("ACTIVITY_JOIN").unwrap();
+    let (opcode, message) = server.next_message();
+    assert_eq!(opcode, IPC_FRAME);
+    assert_eq!(message["cmd"], "SUBSCRIBE");
+    assert_eq!(message["evt"], "ACTIVITY_JOIN");
+
+    // Readable as soon as dispatch returns, poll_events doesn't wait for it
+    server.dispatch("ACTIVITY_JOIN", json!({ "secret": "abc" }));
+    client.poll_events().unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        [("ACTIVITY_JOIN".to_owned(), json!({ "secret": "abc" }))]
+    );
+}
+
+#[test]
+fn registered_wakeup() {
+    let server = MockDiscordServer::start();
+    let mut client = connect(&server);
+    server.next_message();
+
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(4);
+    client.register(poll.registry(), Token(7)).unwrap();
+    let dispatched = Arc::new(Mutex::new(0));
+    let count = Arc::clone(&dispatched);
+    client.on_dispatch(move |_, _| *count.lock().unwrap() += 1);
+
+    // Each burst wakes the poll again once the previous one was read
+    for burst in 1..=2 {
+        server.dispatch("ACTIVITY_JOIN", json!({}));
+        server.dispatch("ACTIVITY_JOIN", json!({}));
+        poll.poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        assert!(events.iter().any(|event| event.token() == Token(7)));
+        client.poll_events().unwrap();
+        assert_eq!(*dispatched.lock().unwrap(), 2 * burst);
+    }
+}
+
+#[test]
+fn ping_pong() {
+    let server = MockDiscordServer::start();
+    let mut client = connect(&server);
+    server.next_message();
+
+    let sent = Instant::now();
+    client.ping().unwrap();
+    // The pong is written by the time the ping is handed to the test
+    let (opcode, _) = server.next_message();
+    assert_eq!(opcode, IPC_PING);
+    client.poll_events().unwrap();
+    assert!(client.last_pong().is_some_and(|pong| pong >= sent));
+    assert!(client.is_connected());
+}
+
+#[test]
+fn close_frame() {
+    let server = MockDiscordServer::start();
+    let mut client = connect(&server);
+    server.next_message();
+
+    server.close(CLOSE_NORMAL, "Discord is shutting down");
+    let err = client.poll_events().unwrap_err();
+    assert!(
+        matches!(&err, PresenceError::Closed { code: CLOSE_NORMAL, message } if message == "Discord is shutting down")
+    );
+    assert!(err.is_transient());
+    assert!(!client.is_connected());
+    // The reconnection waits for the backoff rather than hammering a discord that is going away
+    assert!(!client.should_retry());
+
+    let refused = PresenceError::Closed {
+        code: CLOSE_INVALID_CLIENT_ID,
+        message: "Invalid Client ID".to_owned(),
+    };
+    assert!(!refused.is_transient());
+}
+
+#[test]
+fn refused_then_failed_connect() {
+    let server = MockDiscordServer::start();
+    let builder = ClientBuilder::default().max_reconnect_delay(Duration::from_millis(20));
+    let mut client = connect_with(&server, builder);
+    server.next_message();
+
+    server.close(CLOSE_INVALID_CLIENT_ID, "Invalid Client ID");
+    client.poll_events().unwrap_err();
+    // Nothing to connect to once the next attempt is due
+    drop(server);
+    while !client.should_retry() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    client.connect().unwrap_err();
+    // Still counted as many failures rather than back to the first, fastest, retry
+    let dump = serde_json::to_value(client.dump()).unwrap();
+    assert!(dump["backoff"]["consecutive_failures"].as_u64().unwrap() > 1);
+}
+
+#[test]
+fn builder() {
+    assert!(matches!(
+        ClientBuilder::default().build(),
+        Err(PresenceError::NoClientId)
+    ));
+    let client = ClientBuilder::default()
+        .client_id("1234")
+        .heartbeat_interval(Duration::from_secs(10))
+        .build()
+        .unwrap();
+    assert_eq!(client.ping_interval(), Duration::from_secs(10));
+}
+
+#[test]
+fn builder_images_and_config() {
+    let server = MockDiscordServer::start();
+    let images = HashMap::from([("dev-libs".to_owned(), "builder_libs".to_owned())]);
+    let mut client = connect_with(&server, ClientBuilder::default().category_images(images));
+    server.next_message();
+    client.apply_config(&Config {
+        ipc_socket_path: Some(server.path().to_owned()),
+        category_images: CategoryImageMap::from(HashMap::from([
+            ("dev-libs".to_owned(), "config_libs".to_owned()),
+            ("sys".to_owned(), "config_sys".to_owned()),
+        ])),
+        ..Config::default()
+    });
+
+    for (category, image) in [("dev-libs", "builder_libs"), ("sys-devel", "config_sys")] {
+        let payload: PackagePayload = serde_json::from_value(json!({
+            "category": category,
+            "package": "a",
+            "version": "1",
+            "state": "compiling",
+        }))
+        .unwrap();
+        client.set_package(payload, EmergeFlags::default()).unwrap();
+        let (_, message) = server.next_message();
+        assert_eq!(message["args"]["activity"]["assets"]["large_image"], image);
+    }
+}
+
+#[test]
+fn without_rate_limit() {
+    let server = MockDiscordServer::start();
+    let mut client = connect_with(&server, ClientBuilder::default().rate_limit(false));
+    server.next_message();
+
+    // The default limit is 5 updates in a row
+    for version in 0..8 {
+        let payload: PackagePayload = serde_json::from_value(json!({
+            "category": "sys-devel",
+            "package": "gcc",
+            "version": version.to_string(),
+        }))
+        .unwrap();
+        client.set_package(payload, EmergeFlags::default()).unwrap();
+        let (_, message) = server.next_message();
+        assert_eq!(message["cmd"], "SET_ACTIVITY");
+    }
+}
@@ -0,0 +1,158 @@
+// The blocking client has its own tests, in discord.rs
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use emerge_presence::{
+    command::encode_frame,
+    config::Config,
+    discord::{Client, ClientBuilder, CLOSE_NORMAL},
+    error::PresenceError,
+    portage::{EmergeFlags, PackagePayload},
+};
+use serde_json::json;
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use common::{MockDiscordServer, IPC_FRAME, IPC_HANDSHAKE, IPC_PING};
+
+/// Run `test` on a runtime like the daemon's, failing it if it gets stuck.
+fn block_on(test: impl Future<Output = ()>) {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            tokio::time::timeout(Duration::from_secs(10), test)
+                .await
+                .expect("the test got stuck");
+        });
+}
+
+async fn connect(server: &MockDiscordServer) -> Client {
+    let mut client = ClientBuilder::default().client_id("1234").build().unwrap();
+    client.apply_config(&Config {
+        ipc_socket_path: Some(server.path().to_owned()),
+        ..Config::default()
+    });
+    client.connect().await.unwrap();
+    client
+}
+
+#[test]
+fn handshake() {
+    block_on(async {
+        let server = MockDiscordServer::start();
+        let client = connect(&server).await;
+        assert!(client.is_connected());
+
+        let (opcode, handshake) = server.next_message();
+        assert_eq!(opcode, IPC_HANDSHAKE);
+        assert_eq!(handshake["client_id"], "1234");
+    });
+}
+
+#[test]
+fn set_package_is_queued() {
+    block_on(async {
+        let server = MockDiscordServer::start();
+        let mut client = connect(&server).await;
+        server.next_message();
+
+        let payload: PackagePayload = serde_json::from_value(json!({
+            "category": "sys-devel",
+            "package": "gcc",
+            "version": "13.2.1",
+            "state": "compiling",
+        }))
+        .unwrap();
+        client.set_package(payload, EmergeFlags::default()).unwrap();
+        assert_eq!(client.next_flush(), Some(Duration::ZERO));
+        client.flush_activities().await.unwrap();
+        assert_eq!(client.next_flush(), None);
+
+        let (opcode, message) = server.next_message();
+        assert_eq!(opcode, IPC_FRAME);
+        assert_eq!(message["cmd"], "SET_ACTIVITY");
+        assert_eq!(
+            message["args"]["activity"]["details"],
+            "sys-devel/gcc 13.2.1"
+        );
+    });
+}
+
+#[test]
+fn readable_dispatch() {
+    block_on(async {
+        let server = MockDiscordServer::start();
+        let mut client = connect(&server).await;
+        server.next_message();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&events);
+        client.on_dispatch(move |event, _| received.lock().unwrap().push(event.to_owned()));
+        server.dispatch("ACTIVITY_JOIN", json!({}));
+        client.readable().await.unwrap();
+        client.poll_events().await.unwrap();
+        assert_eq!(*events.lock().unwrap(), ["ACTIVITY_JOIN"]);
+
+        // Half a frame is kept for when the rest arrives
+        let event = json!({ "cmd": "DISPATCH", "evt": "ACTIVITY_SPECTATE", "data": {} });
+        let frame = encode_frame(IPC_FRAME, event.to_string().as_bytes());
+        server.write_raw(&frame[..5]);
+        client.readable().await.unwrap();
+        client.poll_events().await.unwrap();
+        assert_eq!(events.lock().unwrap().len(), 1);
+        server.write_raw(&frame[5..]);
+        client.readable().await.unwrap();
+        client.poll_events().await.unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["ACTIVITY_JOIN", "ACTIVITY_SPECTATE"]
+        );
+    });
+}
+
+#[test]
+fn ping_pong() {
+    block_on(async {
+        let server = MockDiscordServer::start();
+        let mut client = connect(&server).await;
+        server.next_message();
+
+        let sent = Instant::now();
+        client.ping().await.unwrap();
+        let (opcode, _) = server.next_message();
+        assert_eq!(opcode, IPC_PING);
+        client.readable().await.unwrap();
+        client.poll_events().await.unwrap();
+        assert!(client.last_pong().is_some_and(|pong| pong >= sent));
+    });
+}
+
+#[test]
+fn close_frame() {
+    block_on(async {
+        let server = MockDiscordServer::start();
+        let mut client = connect(&server).await;
+        server.next_message();
+
+        server.close(CLOSE_NORMAL, "Discord is shutting down");
+        client.readable().await.unwrap();
+        let err = client.poll_events().await.unwrap_err();
+        assert!(matches!(
+            err,
+            PresenceError::Closed {
+                code: CLOSE_NORMAL,
+                ..
+            }
+        ));
+        assert!(!client.is_connected());
+        // Nothing left to wait on, it doesn't wake up the loop anymore
+        let readable = tokio::time::timeout(Duration::from_millis(10), client.readable());
+        assert!(readable.await.is_err());
+    });
+}
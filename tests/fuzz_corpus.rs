@@ -0,0 +1,58 @@
+//! The inputs of the fuzz corpus, through the same checks as the fuzz targets, so that what the
+//! fuzzer found stays fixed without needing cargo-fuzz (and nightly).
+
+use std::{fs, path::Path};
+
+use emerge_presence::{
+    auth::{CommandAuth, Secret},
+    command::read_frame,
+    portage::PackagePayload,
+};
+
+fn corpus(target: &str) -> Vec<Vec<u8>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fuzz/corpus")
+        .join(target);
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+        .collect()
+}
+
+fn drain(data: &[u8], auth: Option<&CommandAuth>) {
+    let mut consumed = 0;
+    while let Some((used, _command)) = read_frame(&data[consumed..], auth) {
+        assert!(used > 0, "read_frame made no progress");
+        consumed += used;
+    }
+}
+
+#[test]
+fn command() {
+    let secret: Secret = serde_json::from_str(r#""fuzz""#).unwrap();
+    let auth = CommandAuth::new(&secret);
+    for data in corpus("command") {
+        drain(&data, None);
+        drain(&data, Some(&auth));
+    }
+}
+
+#[test]
+fn unterminated_legacy_command() {
+    let data = vec![b'a'; 2 << 20];
+    let (used, command) = read_frame(&data, None).unwrap();
+    assert_eq!(used, data.len());
+    assert!(command.is_err());
+}
+
+#[test]
+fn package_payload() {
+    for data in corpus("package_payload") {
+        let Ok(payload) = serde_json::from_slice::<PackagePayload>(&data) else {
+            continue;
+        };
+        let json = serde_json::to_string(&payload).unwrap();
+        let again: PackagePayload = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&again).unwrap(), json);
+    }
+}